@@ -0,0 +1,700 @@
+//! Chat component parsing and rendering for server descriptions (MOTDs)
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::Write;
+
+/// Work allowed for one component: each nested component costs 1, text its length.
+/// `translate` arguments can be reused and nested, which would otherwise expand a short
+/// MOTD exponentially.
+const MAX_RENDER_COST: usize = 32 * 1024;
+
+/// Named colors with their `§` code and RGB value
+const NAMED_COLORS: &[(&str, char, u32)] = &[
+    ("black", '0', 0x000000),
+    ("dark_blue", '1', 0x0000AA),
+    ("dark_green", '2', 0x00AA00),
+    ("dark_aqua", '3', 0x00AAAA),
+    ("dark_red", '4', 0xAA0000),
+    ("dark_purple", '5', 0xAA00AA),
+    ("gold", '6', 0xFFAA00),
+    ("gray", '7', 0xAAAAAA),
+    ("dark_gray", '8', 0x555555),
+    ("blue", '9', 0x5555FF),
+    ("green", 'a', 0x55FF55),
+    ("aqua", 'b', 0x55FFFF),
+    ("red", 'c', 0xFF5555),
+    ("light_purple", 'd', 0xFF55FF),
+    ("yellow", 'e', 0xFFFF55),
+    ("white", 'f', 0xFFFFFF),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Style {
+    pub color: Option<u32>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underlined: bool,
+    pub strikethrough: bool,
+    pub obfuscated: bool,
+}
+
+impl Style {
+    /// Applies the style fields present on a JSON component
+    fn merged_with(mut self, component: &Value) -> Self {
+        if let Some(color) = component.get("color").and_then(Value::as_str) {
+            self.color = parse_color(color).or(self.color);
+        }
+        let flag = |key: &str, current: bool| {
+            component
+                .get(key)
+                .and_then(Value::as_bool)
+                .unwrap_or(current)
+        };
+        self.bold = flag("bold", self.bold);
+        self.italic = flag("italic", self.italic);
+        self.underlined = flag("underlined", self.underlined);
+        self.strikethrough = flag("strikethrough", self.strikethrough);
+        self.obfuscated = flag("obfuscated", self.obfuscated);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+/// Chat platforms accepting a restricted set of HTML tags instead of CSS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HtmlDialect {
+    /// Colors via `data-mx-color`, obfuscated text as `data-mx-spoiler`
+    Matrix,
+    /// No colors, obfuscated text as `<tg-spoiler>`
+    Telegram,
+}
+
+/// A chat component flattened into styled text runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatComponent {
+    spans: Vec<Span>,
+    /// Left of `MAX_RENDER_COST` while parsing
+    #[serde(skip)]
+    budget: usize,
+}
+
+impl ChatComponent {
+    /// Control characters in the text are replaced, so the spans are safe for terminals
+    pub fn from_json(value: &Value) -> Self {
+        let mut component = Self {
+            spans: Vec::new(),
+            budget: MAX_RENDER_COST,
+        };
+        component.push_value(value, Style::default(), 0);
+        component
+    }
+
+    pub fn to_plain(&self) -> String {
+        self.spans.iter().map(|s| s.text.as_str()).collect()
+    }
+
+    /// Renders with 24-bit ANSI colors; obfuscated text is masked
+    pub fn to_ansi(&self) -> String {
+        let mut out = String::new();
+        for span in &self.spans {
+            let mut codes = Vec::new();
+            if let Some(color) = span.style.color {
+                codes.push(format!(
+                    "38;2;{};{};{}",
+                    color >> 16,
+                    (color >> 8) & 0xFF,
+                    color & 0xFF
+                ));
+            }
+            for (enabled, code) in [
+                (span.style.bold, "1"),
+                (span.style.italic, "3"),
+                (span.style.underlined, "4"),
+                (span.style.strikethrough, "9"),
+            ] {
+                if enabled {
+                    codes.push(code.to_string());
+                }
+            }
+
+            let text = if span.style.obfuscated {
+                mask(&span.text)
+            } else {
+                span.text.clone()
+            };
+            if codes.is_empty() {
+                out.push_str(&text);
+            } else {
+                let _ = write!(out, "\x1b[{}m{}\x1b[0m", codes.join(";"), text);
+            }
+        }
+        out
+    }
+
+    /// Renders Discord markdown; colors are dropped and obfuscated text becomes a spoiler
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        for span in &self.spans {
+            let mut markers = String::new();
+            if span.style.bold {
+                markers.push_str("**");
+            }
+            if span.style.italic {
+                markers.push('*');
+            }
+            if span.style.underlined {
+                markers.push_str("__");
+            }
+            if span.style.strikethrough {
+                markers.push_str("~~");
+            }
+            if span.style.obfuscated {
+                markers.push_str("||");
+            }
+            let closing: String = markers.chars().rev().collect();
+
+            for (i, line) in span.text.split('\n').enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                }
+                let escaped = escape_markdown(line);
+                if markers.is_empty() || line.trim().is_empty() {
+                    out.push_str(&escaped);
+                } else {
+                    // Markers must hug the text, so surrounding whitespace stays outside
+                    let trimmed = escaped.trim();
+                    let leading = &escaped[..escaped.len() - escaped.trim_start().len()];
+                    let trailing = &escaped[escaped.trim_end().len()..];
+                    let _ = write!(
+                        out,
+                        "{}{}{}{}{}",
+                        leading, markers, trimmed, closing, trailing
+                    );
+                }
+            }
+        }
+        out
+    }
+
+    /// Markdown of at most `max_len` bytes, ending in `...` when cut. The text is cut
+    /// before rendering, so escapes and style markers always come out whole.
+    pub fn to_markdown_within(&self, max_len: usize) -> String {
+        let mut rendered = self.to_markdown();
+        if rendered.len() <= max_len {
+            return rendered;
+        }
+        let budget = max_len.saturating_sub(3);
+        let mut chars = self.to_plain().chars().count();
+        while rendered.len() > budget && chars > 0 {
+            // Markdown grows with the text, so this converges; each round drops a char
+            chars = (chars * budget / rendered.len()).min(chars - 1);
+            rendered = self.truncated(chars).to_markdown();
+        }
+        format!("{}...", rendered)
+    }
+
+    /// The first `max_chars` characters of the text, styles kept
+    fn truncated(&self, max_chars: usize) -> Self {
+        let mut left = max_chars;
+        let mut spans = Vec::new();
+        for span in &self.spans {
+            if left == 0 {
+                break;
+            }
+            let text: String = span.text.chars().take(left).collect();
+            left -= text.chars().count();
+            spans.push(Span {
+                text,
+                style: span.style,
+            });
+        }
+        Self { spans, budget: 0 }
+    }
+
+    /// Renders inline-styled HTML with line breaks as `<br>`
+    pub fn to_html(&self) -> String {
+        let mut out = String::new();
+        for span in &self.spans {
+            let mut css = Vec::new();
+            if let Some(color) = span.style.color {
+                css.push(format!("color:#{:06x}", color));
+            }
+            if span.style.bold {
+                css.push("font-weight:bold".to_string());
+            }
+            if span.style.italic {
+                css.push("font-style:italic".to_string());
+            }
+            match (span.style.underlined, span.style.strikethrough) {
+                (true, true) => css.push("text-decoration:underline line-through".to_string()),
+                (true, false) => css.push("text-decoration:underline".to_string()),
+                (false, true) => css.push("text-decoration:line-through".to_string()),
+                (false, false) => {}
+            }
+
+            let text = escape_html(&span.text).replace('\n', "<br>");
+            let class = if span.style.obfuscated {
+                " class=\"obfuscated\""
+            } else {
+                ""
+            };
+            if css.is_empty() && class.is_empty() {
+                out.push_str(&text);
+            } else {
+                let _ = write!(
+                    out,
+                    "<span{} style=\"{}\">{}</span>",
+                    class,
+                    css.join(";"),
+                    text
+                );
+            }
+        }
+        out
+    }
+
+    /// Renders tag-based HTML for chat platforms that strip inline styles
+    pub fn to_tagged_html(&self, dialect: HtmlDialect) -> String {
+        let mut out = String::new();
+        for span in &self.spans {
+            let mut open = String::new();
+            let mut close = Vec::new();
+            if dialect == HtmlDialect::Matrix
+                && let Some(color) = span.style.color
+            {
+                let _ = write!(open, "<font data-mx-color=\"#{:06x}\">", color);
+                close.push("</font>");
+            }
+            for (enabled, tag, end) in [
+                (span.style.bold, "<b>", "</b>"),
+                (span.style.italic, "<i>", "</i>"),
+                (span.style.underlined, "<u>", "</u>"),
+                (span.style.strikethrough, "<s>", "</s>"),
+            ] {
+                if enabled {
+                    open.push_str(tag);
+                    close.push(end);
+                }
+            }
+            if span.style.obfuscated {
+                match dialect {
+                    HtmlDialect::Matrix => {
+                        open.push_str("<span data-mx-spoiler>");
+                        close.push("</span>");
+                    }
+                    HtmlDialect::Telegram => {
+                        open.push_str("<tg-spoiler>");
+                        close.push("</tg-spoiler>");
+                    }
+                }
+            }
+
+            // Telegram takes newlines literally and rejects <br>
+            let text = match dialect {
+                HtmlDialect::Matrix => escape_html(&span.text).replace('\n', "<br>"),
+                HtmlDialect::Telegram => escape_html(&span.text),
+            };
+            out.push_str(&open);
+            out.push_str(&text);
+            for end in close.iter().rev() {
+                out.push_str(end);
+            }
+        }
+        out
+    }
+
+    fn push_value(&mut self, value: &Value, inherited: Style, depth: usize) {
+        // Malicious servers can nest components arbitrarily deep
+        if depth > 32 || !self.spend(1) {
+            return;
+        }
+
+        match value {
+            Value::String(text) => self.push_legacy(text, inherited),
+            Value::Array(parts) => {
+                // The first element is the parent of the remaining ones
+                if let Some((first, rest)) = parts.split_first() {
+                    let style = match first {
+                        Value::Object(_) => inherited.merged_with(first),
+                        _ => inherited,
+                    };
+                    self.push_value(first, inherited, depth + 1);
+                    for part in rest {
+                        self.push_value(part, style, depth + 1);
+                    }
+                }
+            }
+            Value::Object(_) => {
+                let style = inherited.merged_with(value);
+
+                if let Some(text) = value.get("text").and_then(Value::as_str) {
+                    self.push_legacy(text, style);
+                } else if let Some(key) = value.get("translate").and_then(Value::as_str) {
+                    self.push_translation(value, key, style, depth);
+                } else if let Some(key) = value.get("keybind").and_then(Value::as_str) {
+                    self.push_legacy(key, style);
+                } else if let Some(selector) = value.get("selector").and_then(Value::as_str) {
+                    self.push_legacy(selector, style);
+                }
+
+                if let Some(extra) = value.get("extra").and_then(Value::as_array) {
+                    for part in extra {
+                        self.push_value(part, style, depth + 1);
+                    }
+                }
+            }
+            Value::Number(n) => self.push_text(&n.to_string(), inherited),
+            Value::Bool(b) => self.push_text(&b.to_string(), inherited),
+            Value::Null => {}
+        }
+    }
+
+    /// Without the client language files, `%s` / `%1$s` slots in the fallback (or the
+    /// key itself) are filled from `with`; unused arguments are appended.
+    fn push_translation(&mut self, value: &Value, key: &str, style: Style, depth: usize) {
+        let args: &[Value] = value
+            .get("with")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+        let template = value.get("fallback").and_then(Value::as_str).unwrap_or(key);
+        if !self.spend(template.len()) {
+            return;
+        }
+
+        let mut used = vec![false; args.len()];
+        let mut next_arg = 0;
+        let mut rest = template;
+        while let Some(pos) = rest.find('%') {
+            self.push_legacy(&rest[..pos], style);
+            rest = &rest[pos + 1..];
+
+            if let Some(after) = rest.strip_prefix('%') {
+                self.push_text("%", style);
+                rest = after;
+                continue;
+            }
+
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let (index, consumed) = if digits > 0 && rest[digits..].starts_with("$s") {
+                (
+                    rest[..digits]
+                        .parse::<usize>()
+                        .ok()
+                        .map(|i| i.saturating_sub(1)),
+                    digits + 2,
+                )
+            } else if rest.starts_with('s') {
+                next_arg += 1;
+                (Some(next_arg - 1), 1)
+            } else {
+                self.push_text("%", style);
+                continue;
+            };
+            rest = &rest[consumed..];
+
+            if let Some(i) = index
+                && let Some(arg) = args.get(i)
+            {
+                used[i] = true;
+                self.push_value(arg, style, depth + 1);
+            }
+        }
+        self.push_legacy(rest, style);
+
+        for (arg, used) in args.iter().zip(used) {
+            if !used {
+                self.push_text(" ", style);
+                self.push_value(arg, style, depth + 1);
+            }
+        }
+    }
+
+    /// Splits text on legacy `§` formatting codes, including `§x§R§R§G§G§B§B` hex colors
+    fn push_legacy(&mut self, text: &str, base: Style) {
+        let mut end = text.len().min(self.budget);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.budget -= end;
+        let text = &text[..end];
+
+        let mut style = base;
+        let mut current = String::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '§' {
+                current.push(c);
+                continue;
+            }
+            let Some(code) = chars.next().map(|c| c.to_ascii_lowercase()) else {
+                break;
+            };
+
+            self.push_text(&std::mem::take(&mut current), style);
+            match code {
+                'x' => {
+                    let mut hex = String::new();
+                    let mut lookahead = chars.clone();
+                    for _ in 0..6 {
+                        match (lookahead.next(), lookahead.next()) {
+                            (Some('§'), Some(digit)) if digit.is_ascii_hexdigit() => {
+                                hex.push(digit)
+                            }
+                            _ => break,
+                        }
+                    }
+                    if hex.len() == 6 {
+                        chars = lookahead;
+                        style = Style {
+                            color: u32::from_str_radix(&hex, 16).ok(),
+                            ..Style::default()
+                        };
+                    }
+                }
+                'k' => style.obfuscated = true,
+                'l' => style.bold = true,
+                'm' => style.strikethrough = true,
+                'n' => style.underlined = true,
+                'o' => style.italic = true,
+                'r' => style = base,
+                _ => {
+                    if let Some(&(_, _, rgb)) = NAMED_COLORS.iter().find(|(_, c, _)| *c == code) {
+                        // A color code also resets every formatting flag
+                        style = Style {
+                            color: Some(rgb),
+                            ..Style::default()
+                        };
+                    }
+                }
+            }
+        }
+        self.push_text(&current, style);
+    }
+
+    fn push_text(&mut self, text: &str, style: Style) {
+        let text = replace_controls(text);
+        if text.is_empty() {
+            return;
+        }
+        match self.spans.last_mut() {
+            Some(last) if last.style == style => last.text.push_str(&text),
+            _ => self.spans.push(Span { text, style }),
+        }
+    }
+
+    /// Takes `cost` from the budget; false once it is used up
+    fn spend(&mut self, cost: usize) -> bool {
+        match self.budget.checked_sub(cost) {
+            Some(left) => {
+                self.budget = left;
+                true
+            }
+            None => {
+                self.budget = 0;
+                false
+            }
+        }
+    }
+}
+
+/// Keeps line breaks, turns tabs into spaces and drops carriage returns; every other C0/C1
+/// control (ESC included) becomes U+FFFD so remote text cannot drive the terminal
+pub fn replace_controls(text: &str) -> String {
+    text.chars()
+        .filter(|&c| c != '\r')
+        .map(|c| match c {
+            '\n' => c,
+            '\t' => ' ',
+            c if c.is_control() => '\u{FFFD}',
+            c => c,
+        })
+        .collect()
+}
+
+fn parse_color(color: &str) -> Option<u32> {
+    if let Some(hex) = color.strip_prefix('#') {
+        return u32::from_str_radix(hex, 16).ok().filter(|c| *c <= 0xFFFFFF);
+    }
+    NAMED_COLORS
+        .iter()
+        .find(|(name, _, _)| *name == color)
+        .map(|&(_, _, rgb)| rgb)
+}
+
+fn mask(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_whitespace() { c } else { '▒' })
+        .collect()
+}
+
+pub fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '*' | '_' | '~' | '|' | '`' | '>' | '#' | '[' | ']'
+        ) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Removes ANSI escape sequences, e.g. before writing to the log file. CSI sequences
+/// end at their final byte, OSC ones at BEL or ST; any other ESC is dropped on its own.
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('[') => {
+                chars.next();
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_legacy_codes() {
+        let motd = ChatComponent::from_json(&json!("§aGreen §lbold§r plain"));
+        assert_eq!(motd.to_plain(), "Green bold plain");
+        assert_eq!(
+            motd.to_ansi(),
+            "\x1b[38;2;85;255;85mGreen \x1b[0m\x1b[38;2;85;255;85;1mbold\x1b[0m plain"
+        );
+        assert_eq!(motd.to_markdown(), "Green **bold** plain");
+    }
+
+    #[test]
+    fn renders_json_components() {
+        let motd = ChatComponent::from_json(&json!({
+            "text": "A<b>",
+            "color": "#ff0000",
+            "extra": [{"text": "*x*", "italic": true}]
+        }));
+        assert_eq!(motd.to_plain(), "A<b>*x*");
+        assert_eq!(
+            motd.to_html(),
+            "<span style=\"color:#ff0000\">A&lt;b&gt;</span>\
+             <span style=\"color:#ff0000;font-style:italic\">*x*</span>"
+        );
+        assert_eq!(motd.to_markdown(), "A<b\\>*\\*x\\**");
+    }
+
+    #[test]
+    fn cuts_markdown_before_escaping() {
+        let motd = ChatComponent::from_json(&json!({
+            "text": "plain ",
+            "extra": [{"text": "*".repeat(2000), "bold": true, "strikethrough": true}]
+        }));
+        let markdown = motd.to_markdown_within(1000);
+        assert!(markdown.len() <= 1000, "{}", markdown.len());
+        let body = markdown
+            .strip_prefix("plain **~~")
+            .and_then(|rest| rest.strip_suffix("~~**..."))
+            .unwrap();
+        assert!(!body.is_empty());
+        assert_eq!(body, "\\*".repeat(body.len() / 2));
+
+        let short = ChatComponent::from_json(&json!("§lshort"));
+        assert_eq!(short.to_markdown_within(1000), "**short**");
+    }
+
+    #[test]
+    fn fills_translation_slots() {
+        let motd = ChatComponent::from_json(&json!({
+            "translate": "greet",
+            "fallback": "%2$s, %1$s! 100%%",
+            "with": ["world", "Hello", "extra"]
+        }));
+        assert_eq!(motd.to_plain(), "Hello, world! 100% extra");
+    }
+
+    #[test]
+    fn bounds_reused_translation_arguments() {
+        let mut value = json!("abcdefgh");
+        for _ in 0..32 {
+            value = json!({"translate": "%1$s%1$s%1$s%1$s", "with": [value]});
+        }
+        let started = std::time::Instant::now();
+        let motd = ChatComponent::from_json(&value);
+        assert!(motd.to_plain().len() <= MAX_RENDER_COST);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bounds_expansion_of_empty_arguments() {
+        let mut value = json!("");
+        for _ in 0..32 {
+            value = json!({"translate": "%1$s%1$s%1$s%1$s", "with": [value]});
+        }
+        let started = std::time::Instant::now();
+        assert_eq!(ChatComponent::from_json(&value).to_plain(), "");
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn replaces_control_characters() {
+        let motd = ChatComponent::from_json(&json!({
+            "text": "a\u{1b}]0;pwned\u{7}b\u{9b}c\r\nd\te",
+            "bold": true
+        }));
+        assert_eq!(motd.to_plain(), "a\u{FFFD}]0;pwned\u{FFFD}b\u{FFFD}c\nd e");
+        // Only the SGR codes added around the span remain
+        assert_eq!(motd.to_ansi().matches('\x1b').count(), 2);
+    }
+
+    #[test]
+    fn strips_ansi_sequences() {
+        assert_eq!(strip_ansi("a\x1b[38;2;1;2;3mb\x1b[0mc"), "abc");
+        assert_eq!(strip_ansi("x\x1bhello"), "xhello");
+        assert_eq!(strip_ansi("\x1b]0;title\x07rest"), "rest");
+        assert_eq!(strip_ansi("\x1b]0;title\x1b\\rest"), "rest");
+        assert_eq!(strip_ansi("end\x1b"), "end");
+    }
+}
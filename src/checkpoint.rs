@@ -0,0 +1,166 @@
+//! Scan state written on shutdown so the next run resumes unfinished ranges
+
+use crate::config::ScanningConfig;
+use crate::stats::ScanProgress;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+/// A task's position in the range it was scanning
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RangeProgress {
+    pub start: Ipv4Addr,
+    /// First address not scanned yet
+    pub next: Ipv4Addr,
+    pub scanned: usize,
+    pub found: usize,
+    pub consecutive_empty: usize,
+}
+
+impl RangeProgress {
+    /// Whether a task would stop scanning this range under the given settings
+    pub fn is_finished(&self, scanning: &ScanningConfig) -> bool {
+        self.scanned >= scanning.max_range_size
+            || self.consecutive_empty >= scanning.consecutive_threshold
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub written_at: String,
+    pub runtime_seconds: u64,
+    pub scanned_total: u64,
+    pub ports_open: u64,
+    pub servers_found: u64,
+    pub ranges: Vec<RangeProgress>,
+}
+
+impl Checkpoint {
+    pub fn new(progress: &ScanProgress, ranges: Vec<RangeProgress>) -> Self {
+        Self {
+            written_at: chrono::Utc::now().to_rfc3339(),
+            runtime_seconds: progress.runtime.as_secs(),
+            scanned_total: progress.scanned_total,
+            ports_open: progress.ports_open,
+            servers_found: progress.servers_found,
+            ranges,
+        }
+    }
+
+    /// Reads and removes the checkpoint, so a crash later does not resume the same ranges again
+    pub fn take(path: impl Into<PathBuf>) -> io::Result<Option<Self>> {
+        let path = path.into();
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        fs::remove_file(&path)?;
+        match serde_json::from_slice(&bytes) {
+            Ok(checkpoint) => Ok(Some(checkpoint)),
+            Err(e) => {
+                warn!("Ignoring corrupt checkpoint {}: {}", path.display(), e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// The ranges left to scan; ranges finished under the current settings are dropped
+    pub fn unfinished(self, scanning: &ScanningConfig) -> Vec<RangeProgress> {
+        self.ranges
+            .into_iter()
+            .filter(|range| !range.is_finished(scanning))
+            .collect()
+    }
+
+    pub fn save(&self, path: impl Into<PathBuf>) -> io::Result<()> {
+        let path = path.into();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn range(start: [u8; 4], scanned: usize, consecutive_empty: usize) -> RangeProgress {
+        let start = Ipv4Addr::from(start);
+        RangeProgress {
+            start,
+            next: Ipv4Addr::from(u32::from(start) + scanned as u32),
+            scanned,
+            found: 1,
+            consecutive_empty,
+        }
+    }
+
+    fn progress() -> ScanProgress {
+        ScanProgress {
+            scanned_total: 1000,
+            ports_open: 12,
+            servers_found: 3,
+            scans_per_minute: 0.0,
+            runtime: Duration::from_secs(90),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "mcsf-checkpoint-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn round_trips_and_is_taken_only_once() {
+        let path = temp_path("round-trip");
+        Checkpoint::new(&progress(), vec![range([10, 0, 0, 0], 256, 0)])
+            .save(&path)
+            .unwrap();
+
+        let restored = Checkpoint::take(&path).unwrap().unwrap();
+        assert_eq!(restored.runtime_seconds, 90);
+        assert_eq!(restored.servers_found, 3);
+        assert_eq!(restored.ranges.len(), 1);
+        assert_eq!(restored.ranges[0].next, Ipv4Addr::new(10, 0, 1, 0));
+        assert_eq!(restored.ranges[0].scanned, 256);
+        assert!(Checkpoint::take(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_checkpoints_are_ignored() {
+        let path = temp_path("corrupt");
+        fs::write(&path, b"{ not json").unwrap();
+        assert!(Checkpoint::take(&path).unwrap().is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn resuming_skips_finished_ranges() {
+        let scanning = ScanningConfig {
+            max_range_size: 2048,
+            consecutive_threshold: 100,
+            ..Default::default()
+        };
+        let checkpoint = Checkpoint::new(
+            &progress(),
+            vec![
+                range([10, 0, 0, 0], 512, 10),
+                range([10, 1, 0, 0], 2048, 0),
+                range([10, 2, 0, 0], 300, 100),
+            ],
+        );
+
+        let unfinished = checkpoint.unfinished(&scanning);
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].start, Ipv4Addr::new(10, 0, 0, 0));
+        assert_eq!(unfinished[0].next, Ipv4Addr::new(10, 0, 2, 0));
+    }
+}
@@ -0,0 +1,159 @@
+//! Command line dispatch
+
+use crate::config::{OUTPUT_DIR, QUEUE_DIR};
+use crate::notifier::NotificationDispatcher;
+use crate::queue::{NotificationQueue, QueuedMessage};
+use crate::scanner::run_scanner;
+use crate::validation;
+use log::{error, info};
+use std::process::ExitCode;
+
+const USAGE: &str =
+    "Usage: Minecraft-Port-Scanner [scan | config check | queue list | queue replay]";
+
+/// Scanning is the default when no subcommand is given
+pub fn is_scan(args: &[String]) -> bool {
+    match args {
+        [] => true,
+        [command] => command == "scan",
+        _ => false,
+    }
+}
+
+pub async fn run(args: &[String]) -> ExitCode {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] | ["scan"] => run_scanner().await,
+        ["config", "check"] => config_check(),
+        ["queue", "list"] => queue_list(),
+        ["queue", "replay"] => queue_replay().await,
+        _ => {
+            error!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Loads and validates `config.toml` without scanning; fails on any error
+fn config_check() -> ExitCode {
+    match validation::load_checked() {
+        Some(_) => ExitCode::SUCCESS,
+        None => ExitCode::FAILURE,
+    }
+}
+
+fn open_queue() -> Option<NotificationQueue> {
+    match NotificationQueue::open(format!("{}/{}", OUTPUT_DIR, QUEUE_DIR)) {
+        Ok(queue) => Some(queue),
+        Err(e) => {
+            error!("Could not open notification queue: {}", e);
+            None
+        }
+    }
+}
+
+/// Prints pending and dead-lettered notifications; fails if any of them could not be read
+fn queue_list() -> ExitCode {
+    let Some(queue) = open_queue() else {
+        return ExitCode::FAILURE;
+    };
+
+    let mut status = ExitCode::SUCCESS;
+    for (label, messages) in [("pending", queue.pending()), ("dead", queue.dead_letters())] {
+        match messages {
+            Ok(messages) => {
+                info!("[QUEUE] {} {} notifications", messages.len(), label);
+                for message in &messages {
+                    log_message(message);
+                }
+            }
+            Err(e) => {
+                error!("Could not read {} notifications: {}", label, e);
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+
+    match queue.pending_servers() {
+        Ok(found) => {
+            info!("[QUEUE] {} found servers not routed yet", found.len());
+            for found in &found {
+                let server = found.server();
+                info!(
+                    "[QUEUE] {} - {}:{} - queued {}",
+                    found.id, server.ip, server.port, found.queued_at
+                );
+            }
+        }
+        Err(e) => {
+            error!("Could not read found servers: {}", e);
+            status = ExitCode::FAILURE;
+        }
+    }
+    status
+}
+
+fn log_message(message: &QueuedMessage) {
+    info!(
+        "[QUEUE] {} - {} - queued {} - {} attempts{}",
+        message.id,
+        message.context,
+        message.queued_at,
+        message.attempts,
+        message
+            .last_error
+            .as_ref()
+            .map(|e| format!(" - last error: {}", e))
+            .unwrap_or_default()
+    );
+}
+
+/// Moves dead letters back to the pending queue and delivers everything pending; refuses
+/// to run while a scan delivers from the same queue
+async fn queue_replay() -> ExitCode {
+    let Some(queue) = open_queue() else {
+        return ExitCode::FAILURE;
+    };
+    let _lock = match queue.lock() {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Could not lock notification queue: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match queue.requeue_dead_letters() {
+        Ok(count) => info!("[QUEUE] Requeued {} dead-lettered notifications", count),
+        Err(e) => {
+            error!("Could not requeue dead letters: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    NotificationDispatcher::deliver_pending(queue.clone()).await;
+
+    let status = match (queue.pending(), queue.dead_letters()) {
+        (Ok(pending), Ok(dead)) => {
+            info!(
+                "[QUEUE] Replay finished: {} pending, {} dead-lettered",
+                pending.len(),
+                dead.len()
+            );
+            ExitCode::SUCCESS
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Could not read notification queue: {}", e);
+            ExitCode::FAILURE
+        }
+    };
+    // Routing needs the scan configuration, so only a scan announces these
+    if let Ok(found) = queue.pending_servers()
+        && !found.is_empty()
+    {
+        info!(
+            "[QUEUE] {} found servers are routed and announced by the next scan",
+            found.len()
+        );
+    }
+    status
+}
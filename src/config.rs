@@ -1,14 +1,33 @@
+use crate::discord::MinecraftServer;
+use crate::network::{contains, parse_cidr};
+use crate::protocol::{canonical_version, deserialize_protocol_version};
+use log::{LevelFilter, info, warn};
 use once_cell::sync::Lazy;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
 use std::time::Instant;
 
 pub static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
 
-pub const LOG_LEVEL_FILTER_RELEASE: log::LevelFilter = log::LevelFilter::Info;
-pub const LOG_LEVEL_FILTER_DEBUG: log::LevelFilter = log::LevelFilter::Debug;
+pub const LOG_LEVEL_FILTER_RELEASE: LevelFilter = LevelFilter::Info;
+pub const LOG_LEVEL_FILTER_DEBUG: LevelFilter = LevelFilter::Debug;
 pub const OUTPUT_DIR: &str = "output";
+pub const RESULTS_FILE: &str = "results.jsonl";
+/// Latency percentiles next to the results, rewritten with every stats report
+pub const TIMINGS_FILE: &str = "timings.json";
+pub const QUEUE_DIR: &str = "queue";
+pub const SEEN_FILE: &str = "seen.json";
+pub const CHECKPOINT_FILE: &str = "checkpoint.json";
+/// Target subnets, one CIDR per line
+pub const SUBNETS_FILE: &str = "assets/ips.txt";
+pub const CONFIG_FILE: &str = "config.toml";
+/// Optional, layered over `CONFIG_FILE`; meant for secrets and kept out of git
+pub const LOCAL_CONFIG_FILE: &str = "config.local.toml";
 
-#[derive(Debug, Deserialize)]
+/// Every section and field is optional and falls back to the defaults below
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
 pub struct Config {
     pub scanning: ScanningConfig,
     pub timeouts: TimeoutsConfig,
@@ -17,46 +36,300 @@ pub struct Config {
     pub test_servers: TestServersConfig,
     pub stats: StatsConfig,
     pub discord: DiscordConfig,
+    pub filters: FiltersConfig,
+    pub routing: RoutingConfig,
+    /// Named destinations that routes can refer to besides Discord webhook URLs
+    pub notifiers: BTreeMap<String, NotifierConfig>,
+    pub privacy: PrivacyConfig,
+    pub metrics: MetricsConfig,
+    pub retry: RetryConfig,
+    pub proxy: ProxyConfig,
+    pub shutdown: ShutdownConfig,
+    pub logging: LoggingConfig,
+    pub reload: ReloadConfig,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct ScanningConfig {
     pub port: u16,
     pub num_tasks: usize,
     pub max_range_size: usize,
     pub consecutive_threshold: usize,
     pub chunk_size: usize,
+    /// CIDRs never probed, e.g. networks that opted out; applied live on reload
+    #[serde(deserialize_with = "deserialize_cidrs")]
+    pub exclude: Vec<(Ipv4Addr, u8)>,
+}
+
+impl ScanningConfig {
+    pub fn excludes(&self, ip: Ipv4Addr) -> bool {
+        self.exclude
+            .iter()
+            .any(|&(network, prefix)| contains(network, prefix, ip))
+    }
+}
+
+impl Default for ScanningConfig {
+    fn default() -> Self {
+        Self {
+            port: 25565,
+            num_tasks: 8,
+            max_range_size: 2048,
+            consecutive_threshold: 100,
+            chunk_size: 100,
+            exclude: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct TimeoutsConfig {
     pub port_check_ms: u64,
     pub connection_ms: u64,
     pub protocol_response_ms: u64,
+    pub adaptive: AdaptiveTimeoutsConfig,
 }
 
-#[derive(Debug, Deserialize)]
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            port_check_ms: 2000,
+            connection_ms: 3000,
+            protocol_response_ms: 5000,
+            adaptive: AdaptiveTimeoutsConfig::default(),
+        }
+    }
+}
+
+/// Learns timeouts from observed connect and status response times.
+/// The static values above are used until enough samples are collected.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AdaptiveTimeoutsConfig {
+    pub enabled: bool,
+    /// Quantile of observed times the timeout should cover; status pings that time out
+    /// count as taking exactly the limit
+    pub percentile: f64,
+    /// Multiplied onto the percentile to leave room for stragglers
+    pub headroom: f64,
+    /// Samples before a distribution is trusted, globally and per network
+    pub min_samples: usize,
+    /// Targets sharing this many leading address bits are tuned together
+    pub network_prefix: u8,
+    pub floor_ms: u64,
+    pub ceiling_ms: u64,
+}
+
+impl Default for AdaptiveTimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            percentile: 0.99,
+            headroom: 1.5,
+            min_samples: 50,
+            network_prefix: 24,
+            floor_ms: 250,
+            ceiling_ms: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct NetworkingConfig {
+    /// Local addresses probes originate from, used round-robin; empty lets the kernel choose
+    pub source_addresses: Vec<Ipv4Addr>,
     pub base_source_port: u16,
+    /// Source ports reserved for each scan task; 0 leaves port choice to the kernel
     pub port_range_per_task: u16,
+    /// How long a port that carried a connection rests before reuse
+    pub time_wait_seconds: u64,
+}
+
+impl Default for NetworkingConfig {
+    fn default() -> Self {
+        Self {
+            source_addresses: Vec::new(),
+            base_source_port: 20000,
+            port_range_per_task: 1000,
+            // Linux keeps closed connections in TIME_WAIT for 60 seconds
+            time_wait_seconds: 60,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct MinecraftConfig {
+    /// Protocol number or release name (`"1.21.1"`) sent in the handshake
+    #[serde(deserialize_with = "deserialize_protocol_version")]
     pub protocol_version: i32,
 }
 
-#[derive(Debug, Deserialize)]
+impl Default for MinecraftConfig {
+    fn default() -> Self {
+        // 1.20.3-1.20.4
+        Self {
+            protocol_version: 765,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
 pub struct TestServersConfig {
     pub test_ips: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct StatsConfig {
     pub stats_interval_seconds: u64,
 }
 
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            stats_interval_seconds: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Serve Prometheus metrics at `http://{listen}/metrics`
+    pub enabled: bool,
+    pub listen: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:9898".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub port_check: RetryPolicy,
+    pub status: RetryPolicy,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Including the first attempt; 1 disables retries
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each further one
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Fraction of the delay that is randomized, 0.0-1.0
+    pub jitter: f64,
+    /// `PingError` kinds worth retrying, e.g. `connect_timeout`, `reset`, `protocol_timeout`
+    pub retry_on: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff_ms: 200,
+            max_backoff_ms: 2000,
+            jitter: 0.5,
+            retry_on: vec![
+                "connect_timeout".to_string(),
+                "reset".to_string(),
+                "protocol_timeout".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// How long in-flight probes get to finish after SIGINT/SIGTERM
+    pub drain_seconds: u64,
+    /// How long pending notifications get to deliver; the rest stays in the on-disk queue
+    pub flush_seconds: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_seconds: 10,
+            flush_seconds: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`; unset keeps the build's default.
+    /// Release builds never log below `info`.
+    #[serde(deserialize_with = "deserialize_level_filter")]
+    pub level: Option<LevelFilter>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReloadConfig {
+    /// How often the config files are checked for changes; 0 leaves reloading to SIGHUP
+    pub poll_seconds: u64,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        Self { poll_seconds: 5 }
+    }
+}
+
+/// Proxies that port checks and status pings are tunnelled through
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Proxy for targets outside every group; unset pings directly
+    pub default: Option<String>,
+    pub servers: BTreeMap<String, ProxyServerConfig>,
+    /// Checked in order, the first group containing the target wins
+    pub groups: Vec<ProxyGroup>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ProxyServerConfig {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyKind {
+    Socks5,
+    /// HTTP `CONNECT` tunnel
+    Http,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ProxyGroup {
+    /// CIDR ranges, e.g. `203.0.113.0/24`
+    pub subnets: Vec<String>,
+    /// Name under `servers`, or `"direct"` to ping without a proxy
+    pub proxy: String,
+}
+
+/// Legacy per-version webhooks, only used when `[routing]` defines no rules
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct DiscordConfig {
     pub webhook_121_active: String,
     pub webhook_120_active: String,
@@ -67,12 +340,365 @@ pub struct DiscordConfig {
     pub webhook_120_empty: String,
     pub webhook_119_empty: String,
     pub webhook_other_empty: String,
+
+    pub digest: DigestConfig,
+    pub dedup: DedupConfig,
+    pub embed: EmbedConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DigestConfig {
+    /// Batch found servers into summary messages instead of one embed each
+    pub enabled: bool,
+    /// Flush pending servers this long after the first one arrived
+    pub window_seconds: u64,
+    /// Flush a webhook early once this many servers are pending for it
+    pub max_servers: usize,
+    /// Post a scan progress summary this often; 0 disables it
+    pub progress_interval_seconds: u64,
+    /// Destinations receiving scan progress summaries
+    pub progress_webhooks: Vec<String>,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_seconds: 60,
+            max_servers: 25,
+            progress_interval_seconds: 0,
+            progress_webhooks: Vec::new(),
+        }
+    }
+}
+
+/// Layout of found-server embeds; templates see the same fields as webhook templates
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EmbedConfig {
+    pub title: String,
+    /// Description template; empty for none
+    pub description: String,
+    pub footer: String,
+    /// Overrides the name configured on the webhook
+    pub username: Option<String>,
+    pub avatar_url: Option<String>,
+    /// Attach the server favicon as the embed thumbnail
+    pub thumbnail: bool,
+    pub show: EmbedFieldsConfig,
+    /// Extra templated fields after the built-in ones
+    pub fields: Vec<EmbedFieldConfig>,
+}
+
+impl Default for EmbedConfig {
+    fn default() -> Self {
+        Self {
+            title: "🎮 {{#if active}}Active{{else}}Empty{{/if}} Server Found!".to_string(),
+            description: String::new(),
+            footer: "Minecraft Port Scanner".to_string(),
+            username: None,
+            avatar_url: None,
+            thumbnail: true,
+            show: EmbedFieldsConfig::default(),
+            fields: Vec::new(),
+        }
+    }
+}
+
+/// Toggles for the built-in embed fields
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EmbedFieldsConfig {
+    pub country: bool,
+    pub asn: bool,
+    pub latency: bool,
+    pub software: bool,
+    pub mods: bool,
+    pub player_sample: bool,
+    pub motd: bool,
+}
+
+impl Default for EmbedFieldsConfig {
+    fn default() -> Self {
+        Self {
+            country: true,
+            asn: false,
+            latency: false,
+            software: true,
+            mods: true,
+            player_sample: false,
+            motd: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EmbedFieldConfig {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub inline: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DedupConfig {
+    /// Announce each server only once per cooldown, remembered across runs
+    pub enabled: bool,
+    /// Seconds before an unchanged server is announced again
+    pub cooldown_seconds: u64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cooldown_seconds: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RoutingConfig {
+    /// Catch-all destinations for servers no rule matched
+    #[serde(default)]
+    pub default: Vec<String>,
+    /// Evaluated in order; the first match wins unless it sets `continue`
+    #[serde(default)]
+    pub rules: Vec<RouteRule>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouteRule {
+    pub name: String,
+    #[serde(default, rename = "match")]
+    pub matcher: RouteMatch,
+    /// Discord webhook URLs or `[notifiers]` names
+    #[serde(default)]
+    pub destinations: Vec<String>,
+    #[serde(default, rename = "continue")]
+    pub continue_matching: bool,
+    /// Embed color for Discord destinations, `"#rrggbb"` or a number
+    #[serde(default, deserialize_with = "deserialize_color")]
+    pub color: Option<u32>,
+}
+
+/// Every present field must match; an empty match accepts all servers
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RouteMatch {
+    /// Glob on the game version or raw version name, e.g. `1.21*`
+    pub version: Option<String>,
+    pub protocol_min: Option<i32>,
+    pub protocol_max: Option<i32>,
+    pub min_players: Option<u32>,
+    pub max_players: Option<u32>,
+    /// Country names or ISO codes
+    #[serde(default)]
+    pub countries: Vec<String>,
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    /// Software families such as `Paper` or `Velocity`
+    #[serde(default)]
+    pub software: Vec<String>,
+    pub proxy: Option<bool>,
+    /// At least one of these mod ids must be advertised
+    #[serde(default)]
+    pub mods: Vec<String>,
+    pub motd_regex: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// Generic HTTP request with a templated body
+    #[default]
+    Webhook,
+    /// Slack incoming webhook, formatted with Block Kit
+    Slack,
+    /// Matrix room, posted through the client-server API
+    Matrix,
+    /// Telegram chat, posted through the Bot API
+    Telegram,
+}
+
+/// How values substituted with `{{...}}` are escaped in a template
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateEscape {
+    /// Safe inside JSON string literals
+    #[default]
+    Json,
+    Html,
+    None,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NotifierConfig {
+    #[serde(default)]
+    pub kind: NotifierKind,
+    /// Request URL; the homeserver for Matrix, an optional API base for Telegram
+    #[serde(default)]
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Handlebars template rendering the body for each found server
+    pub template: Option<String>,
+    /// Read `template` from this file instead
+    pub template_file: Option<String>,
+    /// Body for a batch of servers; without it digests send one request per server
+    pub digest_template: Option<String>,
+    /// Body for scan progress summaries; without it progress is not sent here
+    pub progress_template: Option<String>,
+    #[serde(default)]
+    pub escape: TemplateEscape,
+    /// Matrix room id, e.g. `!abcdef:matrix.org`
+    pub room_id: Option<String>,
+    pub access_token: Option<String>,
+    pub bot_token: Option<String>,
+    /// Telegram chat id or `@channelname`
+    pub chat_id: Option<String>,
+    /// Overrides the platform's default spacing between messages
+    pub min_interval_ms: Option<u64>,
+}
+
+/// Redaction profiles and which sinks they apply to
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    /// Profile for destinations not listed in `destinations`; unset sends full data
+    pub default: Option<String>,
+    /// Profile for the results export
+    pub export: Option<String>,
+    /// Destination (webhook URL or `[notifiers]` name) to profile name, `"none"` for full data
+    pub destinations: BTreeMap<String, String>,
+    pub profiles: BTreeMap<String, RedactionProfile>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RedactionProfile {
+    pub mask_ip: IpMask,
+    /// Replace player UUIDs with a salted hash, stable across runs
+    pub hash_player_ids: bool,
+    /// HMAC key for the hashes; required with `hash_player_ids`
+    pub hash_salt: String,
+    pub drop_player_sample: bool,
+    /// Blank the MOTD, which often names the owner or links a Discord invite
+    pub strip_motd: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpMask {
+    #[default]
+    None,
+    /// `203.0.113.x`, or the /48 prefix for IPv6
+    LastOctet,
+    Full,
+}
+
+fn deserialize_color<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrHex {
+        Number(u32),
+        Hex(String),
+    }
+
+    match NumberOrHex::deserialize(deserializer)? {
+        NumberOrHex::Number(color) => Ok(Some(color)),
+        NumberOrHex::Hex(hex) => u32::from_str_radix(hex.trim().trim_start_matches('#'), 16)
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid color \"{}\"", hex))),
+    }
+}
+
+fn deserialize_cidrs<'de, D>(deserializer: D) -> Result<Vec<(Ipv4Addr, u8)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|cidr| {
+            parse_cidr(cidr)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid CIDR \"{}\"", cidr)))
+        })
+        .collect()
+}
+
+fn deserialize_level_filter<'de, D>(deserializer: D) -> Result<Option<LevelFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let level = String::deserialize(deserializer)?;
+    level
+        .parse()
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(format!("unknown log level \"{}\"", level)))
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FiltersConfig {
+    /// Only export and notify servers advertising at least one of these mod ids
+    #[serde(default)]
+    pub mods: Vec<String>,
+}
+
+impl FiltersConfig {
+    pub fn matches(&self, server: &MinecraftServer) -> bool {
+        if self.mods.is_empty() {
+            return true;
+        }
+        server
+            .mods
+            .as_ref()
+            .is_some_and(|list| self.mods.iter().any(|id| list.has_mod(id)))
+    }
 }
 
 impl Config {
+    /// Loads the layered config, warning about keys no field reads
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_str = std::fs::read_to_string("config.toml")?;
-        let config: Config = toml::from_str(&config_str)?;
+        let (config, unknown) = Self::load_with_unknown_keys()?;
+        for path in unknown {
+            warn!("[CONFIG] {}: unknown key, ignored", path);
+        }
         Ok(config)
     }
+
+    /// Like `load`, but hands back the dotted paths of unknown keys (e.g. a misspelt
+    /// `[scaning]` section) instead of logging them
+    pub fn load_with_unknown_keys() -> Result<(Self, Vec<String>), Box<dyn std::error::Error>> {
+        let mut layers =
+            crate::overrides::load_layers(CONFIG_FILE, LOCAL_CONFIG_FILE, std::env::vars())?;
+        let (config, unknown) = Self::from_table(std::mem::take(&mut layers.table))?;
+        let unknown = unknown.iter().map(|path| layers.describe(path)).collect();
+
+        if let Some(release) = canonical_version(config.minecraft.protocol_version) {
+            info!(
+                "[CONFIG] Handshaking with protocol {} ({})",
+                config.minecraft.protocol_version, release
+            );
+        }
+
+        Ok((config, unknown))
+    }
+
+    pub fn from_table(table: toml::Table) -> Result<(Self, Vec<String>), toml::de::Error> {
+        let mut unknown = Vec::new();
+        let config = serde_ignored::deserialize(toml::Value::Table(table), |path| {
+            unknown.push(path.to_string())
+        })?;
+        Ok((config, unknown))
+    }
 }
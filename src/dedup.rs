@@ -0,0 +1,237 @@
+//! Persistent seen-set suppressing repeat announcements of the same server
+
+use crate::discord::MinecraftServer;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Announcements are recorded in memory at once but written back at most this often
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What was announced last time, to detect material changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SeenEntry {
+    /// Unix timestamp of the last announcement
+    notified_at: i64,
+    version: String,
+    protocol: i32,
+    active: bool,
+}
+
+pub struct SeenSet {
+    path: PathBuf,
+    cooldown_seconds: i64,
+    entries: HashMap<String, SeenEntry>,
+    /// Announcements queued but not delivered yet, counted per server
+    in_flight: HashMap<String, usize>,
+    /// Recorded since the last write
+    dirty: bool,
+    last_saved: Instant,
+}
+
+impl SeenSet {
+    /// Loads the seen-set, dropping entries whose cooldown already ran out
+    pub fn load(path: impl Into<PathBuf>, cooldown_seconds: u64) -> io::Result<Self> {
+        let path = path.into();
+        let cooldown_seconds = i64::try_from(cooldown_seconds).unwrap_or(i64::MAX);
+
+        let entries: HashMap<String, SeenEntry> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring corrupt seen-set {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut seen = Self {
+            path,
+            cooldown_seconds,
+            entries,
+            in_flight: HashMap::new(),
+            dirty: false,
+            last_saved: Instant::now(),
+        };
+        seen.prune();
+        Ok(seen)
+    }
+
+    /// Why the server should be announced, or `None` while it is still cooling down or an
+    /// announcement of it is on its way
+    pub fn reason_to_notify(&self, server: &MinecraftServer) -> Option<&'static str> {
+        if self.in_flight.contains_key(&key(server)) {
+            return None;
+        }
+        let Some(entry) = self.entries.get(&key(server)) else {
+            return Some("new");
+        };
+        if chrono::Utc::now().timestamp() - entry.notified_at >= self.cooldown_seconds {
+            Some("cooldown expired")
+        } else if entry.version != server.version || entry.protocol != server.protocol {
+            Some("version changed")
+        } else if !entry.active && server.players_online > 0 {
+            Some("became active")
+        } else {
+            None
+        }
+    }
+
+    /// Remembers a delivered announcement; the set is written back once `SAVE_INTERVAL`
+    /// passed since the last write
+    pub fn record(&mut self, server: &MinecraftServer) {
+        self.entries.insert(
+            key(server),
+            SeenEntry {
+                notified_at: chrono::Utc::now().timestamp(),
+                version: server.version.clone(),
+                protocol: server.protocol,
+                active: server.players_online > 0,
+            },
+        );
+        self.dirty = true;
+        if self.last_saved.elapsed() >= SAVE_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Marks an announcement of the server as queued for delivery
+    pub fn begin(&mut self, server: &MinecraftServer) {
+        *self.in_flight.entry(key(server)).or_default() += 1;
+    }
+
+    /// Ends an announcement started with `begin`, recording the server if it was delivered
+    pub fn finish(&mut self, server: &MinecraftServer, delivered: bool) {
+        let key = key(server);
+        if let Some(count) = self.in_flight.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&key);
+            }
+        }
+        if delivered {
+            self.record(server);
+        }
+    }
+
+    /// Writes recorded announcements to disk, dropping entries whose cooldown ran out
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.prune();
+        self.last_saved = Instant::now();
+        match self.save() {
+            Ok(()) => self.dirty = false,
+            Err(e) => error!("Could not save seen-set {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn prune(&mut self) {
+        let now = chrono::Utc::now().timestamp();
+        let cooldown_seconds = self.cooldown_seconds;
+        self.entries
+            .retain(|_, entry| now - entry.notified_at < cooldown_seconds);
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.entries)?)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+fn key(server: &MinecraftServer) -> String {
+    format!("{}:{}", server.ip, server.port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minecraft::ServerStatus;
+    use serde_json::json;
+
+    fn server(ip: &str, players: u32) -> MinecraftServer {
+        let status: ServerStatus = serde_json::from_value(json!({
+            "version": {"name": "1.20.4", "protocol": 765},
+            "players": {"online": players, "max": 20},
+            "description": ""
+        }))
+        .unwrap();
+        MinecraftServer::from_status(ip, 25565, &status, 765)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mcsf-seen-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn records_suppress_until_something_changes() {
+        let mut seen = SeenSet::load(temp_path("changes"), 3600).unwrap();
+        let empty = server("10.0.0.1", 0);
+        assert_eq!(seen.reason_to_notify(&empty), Some("new"));
+
+        seen.record(&empty);
+        assert_eq!(seen.reason_to_notify(&empty), None);
+        assert_eq!(
+            seen.reason_to_notify(&server("10.0.0.1", 2)),
+            Some("became active")
+        );
+    }
+
+    #[test]
+    fn servers_in_flight_are_not_announced_twice() {
+        let mut seen = SeenSet::load(temp_path("in-flight"), 3600).unwrap();
+        let found = server("10.0.0.1", 0);
+        seen.begin(&found);
+        seen.begin(&found);
+        assert_eq!(seen.reason_to_notify(&found), None);
+
+        seen.finish(&found, false);
+        assert_eq!(seen.reason_to_notify(&found), None);
+        seen.finish(&found, false);
+        assert_eq!(seen.reason_to_notify(&found), Some("new"));
+
+        seen.begin(&found);
+        seen.finish(&found, true);
+        assert_eq!(seen.reason_to_notify(&found), None);
+    }
+
+    #[test]
+    fn writes_are_batched_until_flushed() {
+        let path = temp_path("batched");
+        let mut seen = SeenSet::load(&path, 3600).unwrap();
+        seen.record(&server("10.0.0.1", 0));
+        seen.record(&server("10.0.0.2", 0));
+        assert!(!path.exists());
+
+        seen.flush();
+        let reloaded = SeenSet::load(&path, 3600).unwrap();
+        assert_eq!(reloaded.reason_to_notify(&server("10.0.0.2", 0)), None);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn expired_entries_are_pruned_on_save() {
+        let path = temp_path("pruned");
+        let mut seen = SeenSet::load(&path, 60).unwrap();
+        seen.record(&server("10.0.0.1", 0));
+        seen.entries.get_mut("10.0.0.1:25565").unwrap().notified_at -= 120;
+        seen.record(&server("10.0.0.2", 0));
+
+        seen.flush();
+        assert_eq!(seen.entries.len(), 1);
+        let reloaded = SeenSet::load(&path, 60).unwrap();
+        assert_eq!(
+            reloaded.reason_to_notify(&server("10.0.0.1", 0)),
+            Some("new")
+        );
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,208 +1,630 @@
-use crate::config::DiscordConfig;
-use log::{debug, error};
-use reqwest::Client;
+use crate::chat::{ChatComponent, escape_markdown, replace_controls};
+use crate::config::{EmbedConfig, RouteRule, TemplateEscape};
+use crate::fingerprint::{Fingerprint, fingerprint};
+use crate::minecraft::ServerStatus;
+use crate::mods::{ModList, extract_mods};
+use crate::notifier::{Attachment, Notifier, OutboundRequest, truncate_at_char_boundary};
+use crate::protocol::{canonical_version, release_for_protocol};
+use crate::stats::ScanProgress;
+use crate::template::{RenderError, TemplateError, Templates, server_context};
+use log::debug;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::fmt;
+use std::sync::Arc;
 
-#[derive(Clone)]
-pub struct DiscordNotifier {
-    client: Client,
-    config: DiscordConfig,
-}
+/// Discord message limits
+const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+const MAX_FIELDS_PER_EMBED: usize = 25;
+const MAX_CHARS_PER_MESSAGE: usize = 6000;
+const MAX_FIELD_VALUE_LEN: usize = 1024;
+const MAX_FOOTER_LEN: usize = 2048;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MinecraftServer {
     pub ip: String,
     pub port: u16,
     pub players_online: u32,
     pub players_max: u32,
+    pub player_sample: Vec<SamplePlayer>,
     pub version: String,
+    pub protocol: i32,
+    /// Release name for `protocol`, e.g. `1.20.3-1.20.4`
+    pub canonical_version: Option<String>,
+    /// Plain-text rendering of `motd`
     pub description: String,
+    #[serde(skip)]
+    pub motd: ChatComponent,
+    pub fingerprint: Fingerprint,
+    pub mods: Option<ModList>,
     pub country: Option<String>,
+    pub country_code: Option<String>,
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+    /// Status request round trip
+    pub latency_ms: Option<u64>,
+    /// Status request to first response byte
+    pub first_byte_ms: Option<u64>,
+    /// TCP connect time of the status connection
+    pub connect_ms: Option<u64>,
+    /// `[proxy.servers]` name the status ping went through
+    pub via_proxy: Option<String>,
+    /// `data:image/png;base64,...` server icon
+    #[serde(skip)]
+    pub favicon: Option<String>,
+}
+
+/// Entry from the status player sample
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamplePlayer {
+    /// Formatting stripped
+    pub name: String,
+    pub id: String,
 }
 
-impl DiscordNotifier {
-    pub fn new(config: DiscordConfig) -> Self {
+impl MinecraftServer {
+    pub fn from_status(
+        ip: &str,
+        port: u16,
+        status: &ServerStatus,
+        handshake_protocol: i32,
+    ) -> Self {
+        let motd = ChatComponent::from_json(&status.description);
+        let description = motd.to_plain();
         Self {
-            client: Client::new(),
-            config,
+            ip: ip.to_string(),
+            port,
+            players_online: status.players.online,
+            players_max: status.players.max,
+            player_sample: status
+                .players
+                .sample
+                .iter()
+                .map(|player| SamplePlayer {
+                    name: ChatComponent::from_json(&serde_json::Value::String(player.name.clone()))
+                        .to_plain(),
+                    id: player.id.clone(),
+                })
+                .collect(),
+            version: status.version.name.clone(),
+            protocol: status.version.protocol,
+            canonical_version: canonical_version(status.version.protocol),
+            fingerprint: fingerprint(status, &description, handshake_protocol),
+            description,
+            motd,
+            mods: extract_mods(status),
+            country: None,
+            country_code: None,
+            asn: None,
+            as_org: None,
+            latency_ms: status.latency.map(|latency| latency.as_millis() as u64),
+            first_byte_ms: status.first_byte.map(|latency| latency.as_millis() as u64),
+            connect_ms: status
+                .connect_time
+                .map(|latency| latency.as_millis() as u64),
+            via_proxy: status.proxy.clone(),
+            favicon: status.favicon.clone(),
         }
     }
 
-    pub async fn notify_server_found(&self, server: MinecraftServer) {
-        let webhook_url = self.get_webhook_for_server(&server);
+    /// Newest game version the server accepts, falling back to the raw version name.
+    /// Proxies echo our handshake protocol, so only backends are labelled from it.
+    pub fn game_version(&self) -> &str {
+        if !self.fingerprint.is_proxy
+            && let Some(release) = release_for_protocol(self.protocol)
+        {
+            return release.last;
+        }
+        self.fingerprint
+            .max_version
+            .as_deref()
+            .unwrap_or(&self.version)
+    }
+}
 
-        if webhook_url.is_empty() {
-            debug!(
-                "No webhook configured for version {} (players: {})",
-                server.version, server.players_online
-            );
-            return;
+impl fmt::Display for MinecraftServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} - {}/{} - {}",
+            self.ip,
+            self.port,
+            self.players_online,
+            self.players_max,
+            replace_controls(&self.version)
+        )?;
+        if let Some(release) = &self.canonical_version {
+            write!(f, " [{}]", release)?;
+        }
+        write!(f, " - {}", self.fingerprint)?;
+        if let Some(mods) = &self.mods {
+            write!(f, " [{} mods]", mods.mods.len())?;
+        }
+        if let Some(proxy) = &self.via_proxy {
+            write!(f, " via {}", proxy)?;
+        }
+        write!(f, " - {}", self.motd.to_ansi())
+    }
+}
+
+/// Compiled `[discord.embed]` layout shared by every Discord destination
+pub struct EmbedTemplate {
+    config: EmbedConfig,
+    templates: Templates,
+}
+
+impl EmbedTemplate {
+    pub fn new(config: &EmbedConfig) -> Result<Self, TemplateError> {
+        // Values end up in JSON strings via serde, so nothing needs escaping here
+        let mut templates = Templates::new(TemplateEscape::None);
+        templates.register("title", &config.title)?;
+        templates.register("description", &config.description)?;
+        for (i, field) in config.fields.iter().enumerate() {
+            templates.register(&format!("field{}.name", i), &field.name)?;
+            templates.register(&format!("field{}.value", i), &field.value)?;
         }
+        Ok(Self {
+            config: config.clone(),
+            templates,
+        })
+    }
 
-        let is_active = server.players_online > 0;
-        let status_emoji = if is_active { "🟢" } else { "🔴" };
-        let status_text = if is_active {
-            "Active Server"
+    /// Renders the found-server embed, with the favicon as an attachment when enabled
+    fn render(
+        &self,
+        server: &MinecraftServer,
+        color: Option<u32>,
+    ) -> Result<(serde_json::Value, Option<Attachment>), RenderError> {
+        let context = server_context(server);
+        let show = &self.config.show;
+        let status_emoji = if server.players_online > 0 {
+            "🟢"
         } else {
-            "Empty Server"
+            "🔴"
         };
 
-        let embed = json!({
-            "embeds": [{
-                "title": format!("🎮 {} Found!", status_text),
-                "color": self.get_color_for_server(&server),
-                "fields": [
-                    {
-                        "name": "🌐 IP Address",
-                        "value": format!("{}:{}", server.ip, server.port),
-                        "inline": true
-                    },
-                    {
-                        "name": format!("{} Players", status_emoji),
-                        "value": format!("{}/{}", server.players_online, server.players_max),
-                        "inline": true
-                    },
-                    {
-                        "name": "🌍 Country",
-                        "value": server.country.as_ref().unwrap_or(&"Unknown".to_string()).clone(),
-                        "inline": true
-                    },
-                    {
-                        "name": "📦 Version",
-                        "value": server.version,
-                        "inline": true
-                    },
-                    {
-                        "name": "📝 Description",
-                        "value": if server.description.is_empty() {
-                            "No description".to_string()
-                        } else {
-                            // Truncate description if too long for Discord
-                            if server.description.len() > 1000 {
-                                format!("{}...", &server.description[..1000])
-                            } else {
-                                server.description
-                            }
-                        },
-                        "inline": false
-                    }
-                ],
-                "timestamp": chrono::Utc::now().to_rfc3339(),
-                "footer": {
-                    "text": "Minecraft Port Scanner"
-                }
-            }]
-        });
+        let mut fields = vec![
+            embed_field(
+                "🌐 IP Address",
+                format!("{}:{}", server.ip, server.port),
+                true,
+            ),
+            embed_field(
+                &format!("{} Players", status_emoji),
+                format!("{}/{}", server.players_online, server.players_max),
+                true,
+            ),
+        ];
+        if show.country {
+            fields.push(embed_field(
+                "🌍 Country",
+                server
+                    .country
+                    .clone()
+                    .unwrap_or_else(|| "Unknown".to_string()),
+                true,
+            ));
+        }
+        if show.asn {
+            let asn = match (server.asn, &server.as_org) {
+                (Some(asn), Some(org)) => format!("AS{} {}", asn, org),
+                (Some(asn), None) => format!("AS{}", asn),
+                _ => "Unknown".to_string(),
+            };
+            fields.push(embed_field("🏢 Network", asn, true));
+        }
+        fields.push(embed_field(
+            "📦 Version",
+            match &server.canonical_version {
+                Some(release) => format!(
+                    "{} ({}, protocol {})",
+                    server.version, release, server.protocol
+                ),
+                None => format!("{} (protocol {})", server.version, server.protocol),
+            },
+            true,
+        ));
+        if show.software {
+            fields.push(embed_field(
+                "🧩 Software",
+                server.fingerprint.to_string(),
+                true,
+            ));
+        }
+        if show.latency {
+            fields.push(embed_field(
+                "📶 Latency",
+                server
+                    .latency_ms
+                    .map(|ms| format!("{} ms", ms))
+                    .unwrap_or_else(|| "Unknown".to_string()),
+                true,
+            ));
+        }
+        if show.player_sample && !server.player_sample.is_empty() {
+            fields.push(embed_field(
+                "👥 Player Sample",
+                escape_markdown(
+                    &server
+                        .player_sample
+                        .iter()
+                        .map(|player| player.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                false,
+            ));
+        }
+        if show.mods {
+            fields.push(embed_field(
+                "🧱 Mods",
+                match &server.mods {
+                    Some(mods) if !mods.mods.is_empty() => format!(
+                        "{} mods ({}): {}",
+                        mods.mods.len(),
+                        mods.format,
+                        mods.summary()
+                    ),
+                    _ => "None advertised".to_string(),
+                },
+                false,
+            ));
+        }
+        if show.motd {
+            fields.push(embed_field(
+                "📝 Description",
+                if server.description.trim().is_empty() {
+                    "No description".to_string()
+                } else {
+                    server.motd.to_markdown_within(MAX_FIELD_VALUE_LEN)
+                },
+                false,
+            ));
+        }
+        for (i, field) in self.config.fields.iter().enumerate() {
+            let name = self
+                .templates
+                .render(&format!("field{}.name", i), &context)?;
+            let value = self
+                .templates
+                .render(&format!("field{}.value", i), &context)?;
+            // Discord rejects fields with an empty name or value
+            if !name.trim().is_empty() && !value.trim().is_empty() {
+                fields.push(embed_field(&name, value, field.inline));
+            }
+        }
+        fields.truncate(MAX_FIELDS_PER_EMBED);
 
-        const MAX_RETRIES: u32 = 3;
-        for attempt in 1..=MAX_RETRIES {
-            match self.client.post(webhook_url).json(&embed).send().await {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        debug!(
-                            "Successfully sent Discord notification for {}:{} ({})",
-                            server.ip,
-                            server.port,
-                            if is_active { "active" } else { "empty" }
-                        );
-                        return;
-                    } else if response.status().as_u16() == 429 {
-                        error!(
-                            "Discord webhook rate limited, attempt {}/{}",
-                            attempt, MAX_RETRIES
-                        );
-                        if attempt < MAX_RETRIES {
-                            tokio::time::sleep(tokio::time::Duration::from_secs(
-                                2 * attempt as u64,
-                            ))
-                            .await;
-                            continue;
-                        }
-                    } else {
-                        error!(
-                            "Discord webhook failed with status: {} (attempt {}/{})",
-                            response.status(),
-                            attempt,
-                            MAX_RETRIES
-                        );
-                        if attempt < MAX_RETRIES {
-                            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                            continue;
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!(
-                        "Failed to send Discord notification (attempt {}/{}): {}",
-                        attempt, MAX_RETRIES, e
-                    );
-                    if attempt < MAX_RETRIES {
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                        continue;
-                    }
-                }
+        let title = self.templates.render("title", &context)?;
+        let title = truncate_at_char_boundary(&title, 256);
+        let footer = truncate_at_char_boundary(&self.config.footer, MAX_FOOTER_LEN);
+        let description = self.templates.render("description", &context)?;
+        let mut description = truncate_at_char_boundary(&description, 4096);
+
+        // Discord rejects embeds over the message size in total; trailing fields go first
+        let field_chars = |field: &serde_json::Value| {
+            ["name", "value"]
+                .iter()
+                .map(|key| field[key].as_str().map_or(0, |text| text.chars().count()))
+                .sum::<usize>()
+        };
+        let fixed_chars = title.chars().count() + footer.chars().count();
+        let mut total = fixed_chars + fields.iter().map(field_chars).sum::<usize>();
+        while total > MAX_CHARS_PER_MESSAGE
+            && let Some(field) = fields.pop()
+        {
+            total -= field_chars(&field);
+        }
+        let description_room = MAX_CHARS_PER_MESSAGE.saturating_sub(total);
+        if let Some((end, _)) = description.char_indices().nth(description_room) {
+            description = &description[..end];
+        }
+
+        let mut embed = json!({
+            "title": title,
+            "color": color.unwrap_or_else(|| get_color_for_server(server)),
+            "fields": fields,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "footer": {
+                "text": footer
             }
+        });
+        if !description.trim().is_empty() {
+            embed["description"] = json!(description);
+        }
+
+        let favicon = server
+            .favicon
+            .as_deref()
+            .filter(|_| self.config.thumbnail)
+            .and_then(|favicon| favicon.strip_prefix("data:image/png;base64,"))
+            .map(|data| Attachment {
+                filename: "favicon.png".to_string(),
+                content_type: "image/png".to_string(),
+                data: data.replace('\n', ""),
+            });
+        if let Some(favicon) = &favicon {
+            embed["thumbnail"] = json!({ "url": format!("attachment://{}", favicon.filename) });
         }
+
+        Ok((self.identify(json!({ "embeds": [embed] })), favicon))
     }
 
-    fn get_webhook_for_server(&self, server: &MinecraftServer) -> &str {
-        let is_active = server.players_online > 0;
+    /// Applies the username and avatar overrides to a webhook payload
+    fn identify(&self, mut payload: serde_json::Value) -> serde_json::Value {
+        if let Some(username) = &self.config.username {
+            payload["username"] = json!(username);
+        }
+        if let Some(avatar_url) = &self.config.avatar_url {
+            payload["avatar_url"] = json!(avatar_url);
+        }
+        payload
+    }
+}
 
-        if server.version.starts_with("1.21") {
-            if is_active {
-                &self.config.webhook_121_active
-            } else {
-                &self.config.webhook_121_empty
-            }
-        } else if server.version.starts_with("1.20") {
-            if is_active {
-                &self.config.webhook_120_active
-            } else {
-                &self.config.webhook_120_empty
+/// A Discord webhook URL used directly as a route destination
+pub struct DiscordWebhook {
+    url: String,
+    embed: Arc<EmbedTemplate>,
+}
+
+impl DiscordWebhook {
+    pub fn new(url: &str, embed: Arc<EmbedTemplate>) -> Self {
+        Self {
+            url: url.to_string(),
+            embed,
+        }
+    }
+}
+
+impl Notifier for DiscordWebhook {
+    fn server_found(
+        &self,
+        server: &MinecraftServer,
+        route: Option<&RouteRule>,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let (payload, favicon) = self
+            .embed
+            .render(server, route.and_then(|route| route.color))?;
+        Ok(vec![OutboundRequest {
+            attachments: favicon.into_iter().collect(),
+            ..OutboundRequest::json(&self.url, payload)
+        }])
+    }
+
+    fn digest(
+        &self,
+        servers: &[MinecraftServer],
+        window_seconds: u64,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        Ok(
+            build_digest_messages(servers, window_seconds, &self.embed.config.footer)
+                .into_iter()
+                .map(|payload| OutboundRequest::json(&self.url, self.embed.identify(payload)))
+                .collect(),
+        )
+    }
+
+    fn progress(&self, progress: &ScanProgress) -> Result<Vec<OutboundRequest>, RenderError> {
+        Ok(vec![OutboundRequest::json(
+            &self.url,
+            self.embed
+                .identify(build_progress_embed(progress, &self.embed.config.footer)),
+        )])
+    }
+}
+
+fn embed_field(name: &str, value: String, inline: bool) -> serde_json::Value {
+    let value = if value.len() > MAX_FIELD_VALUE_LEN {
+        format!("{}...", truncate_at_char_boundary(&value, 1000))
+    } else {
+        value
+    };
+    json!({
+        "name": truncate_at_char_boundary(name, 256),
+        "value": value,
+        "inline": inline
+    })
+}
+
+/// Packs servers into as few messages as Discord's embed, field and size limits allow
+fn build_digest_messages(
+    servers: &[MinecraftServer],
+    window_seconds: u64,
+    footer: &str,
+) -> Vec<serde_json::Value> {
+    let fields: Vec<(String, String)> = servers
+        .iter()
+        .enumerate()
+        .map(|(i, server)| {
+            let mut value = format!(
+                "{} — {}/{} players — {}",
+                server.game_version(),
+                server.players_online,
+                server.players_max,
+                server.fingerprint.family
+            );
+            if let Some(country) = &server.country {
+                value.push_str(&format!(" — {}", country));
             }
-        } else if server.version.starts_with("1.19") {
-            if is_active {
-                &self.config.webhook_119_active
-            } else {
-                &self.config.webhook_119_empty
+            let name = format!("{}. {}:{}", i + 1, server.ip, server.port);
+            (
+                truncate_at_char_boundary(&name, 256).to_string(),
+                truncate_at_char_boundary(&value, 1024).to_string(),
+            )
+        })
+        .collect();
+
+    let title = format!("Servers found ({})", servers.len());
+    let description = format!(
+        "{} responsive servers in the last {}s",
+        servers.len(),
+        window_seconds
+    );
+    let footer = truncate_at_char_boundary(footer, MAX_FOOTER_LEN);
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    // Every embed repeats footer and timestamp; only a message's first adds title and description
+    let embed_chars = footer.chars().count() + timestamp.chars().count();
+    let first_embed_chars = embed_chars + title.chars().count() + description.chars().count();
+
+    let mut messages = Vec::new();
+    let mut embeds: Vec<serde_json::Value> = Vec::new();
+    let mut embed_fields: Vec<serde_json::Value> = Vec::new();
+    let mut message_chars = first_embed_chars;
+
+    for (name, value) in fields {
+        let field_chars = name.chars().count() + value.chars().count();
+
+        if embed_fields.len() == MAX_FIELDS_PER_EMBED {
+            embeds.push(digest_embed(
+                &title,
+                &description,
+                footer,
+                &timestamp,
+                embeds.is_empty(),
+                std::mem::take(&mut embed_fields),
+            ));
+            message_chars += embed_chars;
+        }
+        if embeds.len() == MAX_EMBEDS_PER_MESSAGE
+            || message_chars + field_chars > MAX_CHARS_PER_MESSAGE
+        {
+            if !embed_fields.is_empty() {
+                embeds.push(digest_embed(
+                    &title,
+                    &description,
+                    footer,
+                    &timestamp,
+                    embeds.is_empty(),
+                    std::mem::take(&mut embed_fields),
+                ));
             }
-        } else {
-            if is_active {
-                &self.config.webhook_other_active
-            } else {
-                &self.config.webhook_other_empty
+            if !embeds.is_empty() {
+                messages.push(json!({ "embeds": std::mem::take(&mut embeds) }));
             }
+            message_chars = first_embed_chars;
         }
+
+        message_chars += field_chars;
+        embed_fields.push(json!({ "name": name, "value": value, "inline": false }));
     }
 
-    fn get_color_for_server(&self, server: &MinecraftServer) -> u32 {
-        let is_active = server.players_online > 0;
+    if !embed_fields.is_empty() {
+        embeds.push(digest_embed(
+            &title,
+            &description,
+            footer,
+            &timestamp,
+            embeds.is_empty(),
+            embed_fields,
+        ));
+    }
+    if !embeds.is_empty() {
+        messages.push(json!({ "embeds": embeds }));
+    }
+    messages
+}
 
-        if server.version.starts_with("1.21") {
-            if is_active { 0x00ff00 } else { 0x004400 }
-        } else if server.version.starts_with("1.20") {
-            if is_active { 0x0099ff } else { 0x003366 }
-        } else if server.version.starts_with("1.19") {
-            if is_active { 0xffaa00 } else { 0x664400 }
-        } else {
-            if is_active { 0xff0066 } else { 0x660033 }
+/// Only the first embed of a message carries the title and description
+fn digest_embed(
+    title: &str,
+    description: &str,
+    footer: &str,
+    timestamp: &str,
+    first: bool,
+    fields: Vec<serde_json::Value>,
+) -> serde_json::Value {
+    let mut embed = json!({
+        "color": 0x5865f2,
+        "fields": fields,
+        "timestamp": timestamp,
+        "footer": {
+            "text": footer
         }
+    });
+    if first {
+        embed["title"] = json!(title);
+        embed["description"] = json!(description);
     }
+    embed
 }
 
-async fn get_country_from_ip(ip: &str) -> Option<String> {
-    let url = format!("http://ip-api.com/json/{}?fields=country", ip);
-
-    match reqwest::get(&url).await {
-        Ok(response) => {
-            if let Ok(text) = response.text().await {
-                if let Ok(json) = serde_json::from_str::<serde_json::Value>(&text) {
-                    if let Some(country) = json.get("country").and_then(|c| c.as_str()) {
-                        return Some(country.to_string());
-                    }
+fn build_progress_embed(progress: &ScanProgress, footer: &str) -> serde_json::Value {
+    json!({
+        "embeds": [{
+            "title": "📊 Scan Progress",
+            "color": 0x5865f2,
+            "fields": [
+                {
+                    "name": "IPs scanned",
+                    "value": progress.scanned_total.to_string(),
+                    "inline": true
+                },
+                {
+                    "name": "Open ports",
+                    "value": progress.ports_open.to_string(),
+                    "inline": true
+                },
+                {
+                    "name": "MC servers",
+                    "value": progress.servers_found.to_string(),
+                    "inline": true
+                },
+                {
+                    "name": "Rate",
+                    "value": format!("{:.1} scans/min", progress.scans_per_minute),
+                    "inline": true
+                },
+                {
+                    "name": "Runtime",
+                    "value": format!("{:.1}m", progress.runtime.as_secs_f64() / 60.0),
+                    "inline": true
                 }
+            ],
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "footer": {
+                "text": truncate_at_char_boundary(footer, MAX_FOOTER_LEN)
             }
-        }
+        }]
+    })
+}
+
+fn get_color_for_server(server: &MinecraftServer) -> u32 {
+    let is_active = server.players_online > 0;
+    let version = server.game_version();
+
+    if version.starts_with("1.21") {
+        if is_active { 0x00ff00 } else { 0x004400 }
+    } else if version.starts_with("1.20") {
+        if is_active { 0x0099ff } else { 0x003366 }
+    } else if version.starts_with("1.19") {
+        if is_active { 0xffaa00 } else { 0x664400 }
+    } else {
+        if is_active { 0xff0066 } else { 0x660033 }
+    }
+}
+
+#[derive(Deserialize)]
+struct GeoResponse {
+    country: Option<String>,
+    #[serde(rename = "countryCode")]
+    country_code: Option<String>,
+    /// e.g. `AS24940 Hetzner Online GmbH`
+    #[serde(rename = "as")]
+    autonomous_system: Option<String>,
+}
+
+async fn lookup_geo(ip: &str) -> Option<GeoResponse> {
+    let url = format!(
+        "http://ip-api.com/json/{}?fields=country,countryCode,as",
+        ip
+    );
+
+    match reqwest::get(&url).await {
+        Ok(response) => match response.json::<GeoResponse>().await {
+            Ok(geo) => return Some(geo),
+            Err(e) => debug!("Invalid geo response for IP {}: {}", ip, e),
+        },
         Err(e) => {
             debug!("Failed to get country for IP {}: {}", ip, e);
         }
@@ -210,53 +632,155 @@ async fn get_country_from_ip(ip: &str) -> Option<String> {
     None
 }
 
-pub async fn extract_server_info_with_country(found_message: &str) -> Option<MinecraftServer> {
-    let mut server = extract_server_info(found_message)?;
-
-    server.country = get_country_from_ip(&server.ip).await;
-
-    Some(server)
+/// Fills in country and ASN information from ip-api.com
+pub async fn with_geo_info(mut server: MinecraftServer) -> MinecraftServer {
+    if let Some(geo) = lookup_geo(&server.ip).await {
+        server.country = geo.country.filter(|c| !c.is_empty());
+        server.country_code = geo.country_code.filter(|c| !c.is_empty());
+        if let Some(autonomous_system) = geo.autonomous_system.filter(|a| !a.is_empty()) {
+            let (number, org) = autonomous_system
+                .split_once(' ')
+                .unwrap_or((autonomous_system.as_str(), ""));
+            server.asn = number.strip_prefix("AS").and_then(|n| n.parse().ok());
+            server.as_org = (!org.is_empty()).then(|| org.to_string());
+        }
+    }
+    server
 }
 
-pub fn extract_server_info(found_message: &str) -> Option<MinecraftServer> {
-    if !found_message.starts_with("[FOUND]") {
-        return None;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EmbedFieldConfig;
+
+    fn server(ip: &str, country: &str) -> MinecraftServer {
+        let status: ServerStatus = serde_json::from_value(json!({
+            "version": {"name": "1.20.4", "protocol": 765},
+            "players": {"online": 1, "max": 20},
+            "description": "§aHello"
+        }))
+        .unwrap();
+        let mut server = MinecraftServer::from_status(ip, 25565, &status, 765);
+        server.country = Some(country.to_string());
+        server
     }
 
-    let content = found_message.strip_prefix("[FOUND] ")?;
-    let parts: Vec<&str> = content.split(" - ").collect();
+    fn text_len(value: &serde_json::Value) -> usize {
+        value.as_str().map_or(0, |text| text.chars().count())
+    }
 
-    if parts.len() < 4 {
-        return None;
+    fn embed_chars(embed: &serde_json::Value) -> usize {
+        let fields: usize = embed["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| text_len(&field["name"]) + text_len(&field["value"]))
+            .sum();
+        text_len(&embed["title"])
+            + text_len(&embed["description"])
+            + text_len(&embed["footer"]["text"])
+            + fields
     }
 
-    let ip_port_parts: Vec<&str> = parts[0].split(':').collect();
-    if ip_port_parts.len() != 2 {
-        return None;
+    #[test]
+    fn embed_templates_insert_server_text_verbatim() {
+        let config = EmbedConfig {
+            title: "{{version}}".to_string(),
+            description: "{{description}}".to_string(),
+            fields: vec![EmbedFieldConfig {
+                name: "Raw".to_string(),
+                value: "{{{json version}}}".to_string(),
+                inline: false,
+            }],
+            ..Default::default()
+        };
+        let mut server = server("10.0.0.1", "DE");
+        server.version = "{{address}} \"x\" <b>".to_string();
+        server.description = "line\n@everyone".to_string();
+
+        let (payload, _) = EmbedTemplate::new(&config)
+            .unwrap()
+            .render(&server, None)
+            .unwrap();
+        let embed = &payload["embeds"][0];
+        assert_eq!(embed["title"], "{{address}} \"x\" <b>");
+        assert_eq!(embed["description"], "line\n@everyone");
+        let custom = embed["fields"].as_array().unwrap().last().unwrap();
+        assert_eq!(custom["value"], "\"{{address}} \\\"x\\\" <b>\"");
     }
 
-    let ip = ip_port_parts[0].to_string();
-    let port = ip_port_parts[1].parse::<u16>().ok()?;
+    #[test]
+    fn oversized_embeds_are_trimmed_to_the_message_limit() {
+        let config = EmbedConfig {
+            description: "{{description}}".to_string(),
+            footer: "f".repeat(3000),
+            fields: (0..30)
+                .map(|_| EmbedFieldConfig {
+                    name: "n".repeat(300),
+                    value: "{{description}}".to_string(),
+                    inline: true,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut server = server("10.0.0.1", "DE");
+        server.description = "d".repeat(5000);
 
-    let player_parts: Vec<&str> = parts[1].split('/').collect();
-    if player_parts.len() != 2 {
-        return None;
+        let (payload, _) = EmbedTemplate::new(&config)
+            .unwrap()
+            .render(&server, None)
+            .unwrap();
+        let embed = &payload["embeds"][0];
+        assert!(embed_chars(embed) <= MAX_CHARS_PER_MESSAGE);
+        assert_eq!(text_len(&embed["footer"]["text"]), MAX_FOOTER_LEN);
+        assert!(embed["fields"].as_array().unwrap().len() <= MAX_FIELDS_PER_EMBED);
     }
 
-    let players_online = player_parts[0].parse::<u32>().ok()?;
-    let players_max = player_parts[1].parse::<u32>().ok()?;
+    #[test]
+    fn digest_messages_stay_within_discord_limits() {
+        let servers: Vec<MinecraftServer> = (0..60)
+            .map(|i| server(&format!("10.0.0.{}", i), &"x".repeat(980)))
+            .collect();
+        let messages = build_digest_messages(&servers, 60, &"f".repeat(3000));
 
-    let version = parts[2].to_string();
+        let mut fields = 0;
+        for message in &messages {
+            let embeds = message["embeds"].as_array().unwrap();
+            assert!(embeds.len() <= MAX_EMBEDS_PER_MESSAGE);
+            let mut chars = 0;
+            for embed in embeds {
+                let embed_fields = embed["fields"].as_array().unwrap();
+                assert!(embed_fields.len() <= MAX_FIELDS_PER_EMBED);
+                assert_eq!(text_len(&embed["footer"]["text"]), MAX_FOOTER_LEN);
+                chars += text_len(&embed["title"])
+                    + text_len(&embed["description"])
+                    + text_len(&embed["footer"]["text"])
+                    + text_len(&embed["timestamp"]);
+                for field in embed_fields {
+                    chars += text_len(&field["name"]) + text_len(&field["value"]);
+                }
+                fields += embed_fields.len();
+            }
+            assert!(chars <= MAX_CHARS_PER_MESSAGE, "{} chars", chars);
+        }
+        assert_eq!(fields, servers.len());
+    }
 
-    let description = parts[3..].join(" - ");
+    #[test]
+    fn digest_fills_embeds_up_to_the_field_limit() {
+        let servers: Vec<MinecraftServer> = (0..30)
+            .map(|i| server(&format!("10.0.0.{}", i), "DE"))
+            .collect();
+        let messages = build_digest_messages(&servers, 60, "footer");
 
-    Some(MinecraftServer {
-        ip,
-        port,
-        players_online,
-        players_max,
-        version,
-        description,
-        country: None,
-    })
+        assert_eq!(messages.len(), 1);
+        let embeds = messages[0]["embeds"].as_array().unwrap();
+        assert_eq!(embeds.len(), 2);
+        assert_eq!(
+            embeds[0]["fields"].as_array().unwrap().len(),
+            MAX_FIELDS_PER_EMBED
+        );
+        assert!(embeds[0].get("title").is_some());
+        assert!(embeds[1].get("title").is_none());
+    }
 }
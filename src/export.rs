@@ -0,0 +1,122 @@
+//! Append-only JSON Lines export of found servers
+
+use crate::config::{OUTPUT_DIR, RESULTS_FILE, RedactionProfile, TIMINGS_FILE};
+use crate::discord::MinecraftServer;
+use crate::histogram::LatencyHistogram;
+use log::error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+struct ExportRecord<'a> {
+    found_at: String,
+    description_html: String,
+    #[serde(flatten)]
+    server: &'a MinecraftServer,
+}
+
+/// Percentiles of one timing histogram, in milliseconds
+#[derive(Serialize)]
+struct TimingSummary {
+    samples: u64,
+    p50_ms: f64,
+    p95_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+impl TimingSummary {
+    fn new(histogram: &LatencyHistogram) -> Self {
+        let [p50_ms, p95_ms, p99_ms] = histogram.percentiles_ms();
+        Self {
+            samples: histogram.len(),
+            p50_ms,
+            p95_ms,
+            p99_ms,
+            max_ms: histogram.max().as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+pub struct ResultsExporter {
+    file: File,
+    redaction: Option<Arc<RedactionProfile>>,
+}
+
+impl ResultsExporter {
+    pub fn open() -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(format!("{}/{}", OUTPUT_DIR, RESULTS_FILE))?;
+        Ok(Self {
+            file,
+            redaction: None,
+        })
+    }
+
+    /// Redacts every record before it is written
+    pub fn with_redaction(mut self, profile: Arc<RedactionProfile>) -> Self {
+        self.redaction = Some(profile);
+        self
+    }
+
+    /// Writes one line per server; failures are logged and never stop the scan
+    pub fn write(&mut self, server: &MinecraftServer) {
+        let redacted = self.redaction.as_ref().map(|profile| profile.apply(server));
+        let server = redacted.as_ref().unwrap_or(server);
+        let record = ExportRecord {
+            found_at: chrono::Utc::now().to_rfc3339(),
+            description_html: server.motd.to_html(),
+            server,
+        };
+
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(
+                    "Failed to serialize result for {}:{}: {}",
+                    server.ip, server.port, e
+                );
+                return;
+            }
+        };
+
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            error!("Failed to write {}: {}", RESULTS_FILE, e);
+        }
+    }
+
+    /// Replaces the timings export with the current percentiles; empty histograms are left out
+    pub fn write_timings(&self, timings: &[(&str, &LatencyHistogram)]) {
+        let summaries: BTreeMap<&str, TimingSummary> = timings
+            .iter()
+            .filter(|(_, histogram)| !histogram.is_empty())
+            .map(|(name, histogram)| (*name, TimingSummary::new(histogram)))
+            .collect();
+        let export = serde_json::json!({
+            "updated_at": chrono::Utc::now().to_rfc3339(),
+            "timings": summaries,
+        });
+
+        let path = format!("{}/{}", OUTPUT_DIR, TIMINGS_FILE);
+        let tmp = format!("{}.tmp", path);
+        let written = serde_json::to_vec_pretty(&export)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| fs::write(&tmp, bytes))
+            .and_then(|()| fs::rename(&tmp, &path));
+        if let Err(e) = written {
+            error!("Failed to write {}: {}", TIMINGS_FILE, e);
+        }
+    }
+
+    /// Makes sure everything written so far is on disk
+    pub fn flush(&mut self) {
+        if let Err(e) = self.file.sync_data() {
+            error!("Failed to sync {}: {}", RESULTS_FILE, e);
+        }
+    }
+}
@@ -0,0 +1,476 @@
+//! Server software and proxy fingerprinting based on the status response
+
+use crate::minecraft::ServerStatus;
+use crate::mods::extract_mods;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SoftwareFamily {
+    Vanilla,
+    CraftBukkit,
+    Spigot,
+    Paper,
+    Purpur,
+    Pufferfish,
+    Folia,
+    Forge,
+    NeoForge,
+    Fabric,
+    Quilt,
+    Mohist,
+    Arclight,
+    Velocity,
+    BungeeCord,
+    Waterfall,
+    Geyser,
+    Unknown,
+}
+
+/// Name markers looked up in the lowercased `version.name`, most specific first
+const NAME_MARKERS: &[(&str, SoftwareFamily)] = &[
+    ("folia", SoftwareFamily::Folia),
+    ("purpur", SoftwareFamily::Purpur),
+    ("pufferfish", SoftwareFamily::Pufferfish),
+    ("paper", SoftwareFamily::Paper),
+    ("spigot", SoftwareFamily::Spigot),
+    ("craftbukkit", SoftwareFamily::CraftBukkit),
+    ("mohist", SoftwareFamily::Mohist),
+    ("arclight", SoftwareFamily::Arclight),
+    ("neoforge", SoftwareFamily::NeoForge),
+    ("forge", SoftwareFamily::Forge),
+    ("fabric", SoftwareFamily::Fabric),
+    ("quilt", SoftwareFamily::Quilt),
+    ("velocity", SoftwareFamily::Velocity),
+    ("waterfall", SoftwareFamily::Waterfall),
+    ("bungeecord", SoftwareFamily::BungeeCord),
+    ("geyser", SoftwareFamily::Geyser),
+];
+
+impl SoftwareFamily {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SoftwareFamily::Vanilla => "Vanilla",
+            SoftwareFamily::CraftBukkit => "CraftBukkit",
+            SoftwareFamily::Spigot => "Spigot",
+            SoftwareFamily::Paper => "Paper",
+            SoftwareFamily::Purpur => "Purpur",
+            SoftwareFamily::Pufferfish => "Pufferfish",
+            SoftwareFamily::Folia => "Folia",
+            SoftwareFamily::Forge => "Forge",
+            SoftwareFamily::NeoForge => "NeoForge",
+            SoftwareFamily::Fabric => "Fabric",
+            SoftwareFamily::Quilt => "Quilt",
+            SoftwareFamily::Mohist => "Mohist",
+            SoftwareFamily::Arclight => "Arclight",
+            SoftwareFamily::Velocity => "Velocity",
+            SoftwareFamily::BungeeCord => "BungeeCord",
+            SoftwareFamily::Waterfall => "Waterfall",
+            SoftwareFamily::Geyser => "Geyser",
+            SoftwareFamily::Unknown => "Unknown",
+        }
+    }
+
+    pub fn is_proxy(&self) -> bool {
+        matches!(
+            self,
+            SoftwareFamily::Velocity
+                | SoftwareFamily::BungeeCord
+                | SoftwareFamily::Waterfall
+                | SoftwareFamily::Geyser
+        )
+    }
+
+    pub fn is_modded(&self) -> bool {
+        matches!(
+            self,
+            SoftwareFamily::Forge
+                | SoftwareFamily::NeoForge
+                | SoftwareFamily::Fabric
+                | SoftwareFamily::Quilt
+                | SoftwareFamily::Mohist
+                | SoftwareFamily::Arclight
+        )
+    }
+}
+
+impl fmt::Display for SoftwareFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub family: SoftwareFamily,
+    pub is_proxy: bool,
+    pub modded: bool,
+    /// Server also accepts Bedrock clients through Geyser/Floodgate
+    pub bedrock_bridge: bool,
+    pub min_version: Option<String>,
+    pub max_version: Option<String>,
+}
+
+impl Fingerprint {
+    /// Supported version range, e.g. `1.8.x-1.21.x` or `1.20.4`
+    pub fn version_range(&self) -> Option<String> {
+        match (&self.min_version, &self.max_version) {
+            (Some(min), Some(max)) if min != max => Some(format!("{}-{}", min, max)),
+            (_, Some(version)) | (Some(version), None) => Some(version.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.family)?;
+        if self.bedrock_bridge && self.family != SoftwareFamily::Geyser {
+            write!(f, "+Geyser")?;
+        }
+        write!(f, " ({}", if self.is_proxy { "proxy" } else { "backend" })?;
+        if self.modded && !self.family.is_modded() {
+            write!(f, ", modded")?;
+        }
+        if let Some(range) = self.version_range() {
+            write!(f, ", {}", range)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Classifies a server from its status response.
+///
+/// `handshake_protocol` is the protocol number we sent in the handshake: proxies echo it
+/// back when it is inside their supported range, while backends report their own.
+pub fn fingerprint(
+    status: &ServerStatus,
+    description: &str,
+    handshake_protocol: i32,
+) -> Fingerprint {
+    let name = status.version.name.to_lowercase();
+    let versions = game_versions(&status.version.name);
+
+    let mut family = NAME_MARKERS
+        .iter()
+        .find(|(marker, _)| name.contains(marker))
+        .map(|(_, family)| *family)
+        .unwrap_or(SoftwareFamily::Unknown);
+
+    let fml1 = status
+        .modinfo
+        .as_ref()
+        .and_then(|m| m.get("type"))
+        .and_then(|t| t.as_str())
+        .is_some_and(|t| t.eq_ignore_ascii_case("fml"));
+
+    // NeoForge speaks the Forge handshake; only its own mod entry tells them apart
+    if family == SoftwareFamily::Unknown {
+        if status.forge_data.is_some() || fml1 {
+            family = if extract_mods(status).is_some_and(|mods| mods.has_mod("neoforge")) {
+                SoftwareFamily::NeoForge
+            } else {
+                SoftwareFamily::Forge
+            };
+        } else if status.is_modded != Some(true) && is_plain_version(&status.version.name) {
+            family = SoftwareFamily::Vanilla;
+        }
+    }
+
+    let is_range = versions.len() > 1 || status.version.name.contains(".x");
+    let echoes_handshake = status.version.protocol == handshake_protocol;
+    let is_proxy =
+        family.is_proxy() || (family == SoftwareFamily::Unknown && is_range && echoes_handshake);
+
+    // `preventsChatReports` is not a signal: Paper plugins such as FreedomChat set it too
+    let modded =
+        family.is_modded() || status.forge_data.is_some() || fml1 || status.is_modded == Some(true);
+
+    let description = description.to_lowercase();
+    let bedrock_bridge = family == SoftwareFamily::Geyser
+        || name.contains("floodgate")
+        || description.contains("geyser")
+        || description.contains("bedrock");
+
+    Fingerprint {
+        family,
+        is_proxy,
+        modded,
+        bedrock_bridge,
+        min_version: versions.first().cloned(),
+        max_version: versions.last().cloned(),
+    }
+}
+
+/// Extracts `1.x`, `1.x.y` and `1.x.x` style game versions in order of appearance
+fn game_versions(name: &str) -> Vec<String> {
+    let mut versions = Vec::new();
+    let bytes = name.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let at_boundary = i == 0 || (!bytes[i - 1].is_ascii_alphanumeric() && bytes[i - 1] != b'.');
+        if at_boundary && bytes[i..].starts_with(b"1.") {
+            let end = name[i..]
+                .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == 'x'))
+                .map(|offset| i + offset)
+                .unwrap_or(name.len());
+            let token = name[i..end].trim_end_matches('.');
+            let mut parts = token.split('.');
+            let valid = parts.next() == Some("1")
+                && parts.next().is_some_and(|minor| {
+                    !minor.is_empty() && minor.bytes().all(|b| b.is_ascii_digit())
+                });
+            if valid {
+                versions.push(token.to_string());
+            }
+            i = end.max(i + 1);
+        } else {
+            i += 1;
+        }
+    }
+
+    versions
+}
+
+/// Vanilla servers report a bare release (`1.20.4`) or snapshot (`23w13a`) name
+fn is_plain_version(name: &str) -> bool {
+    let name = name.trim();
+    let is_release = name.starts_with("1.")
+        && name
+            .split('.')
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()));
+    let bytes = name.as_bytes();
+    let is_snapshot = bytes.len() == 6
+        && bytes[..2].iter().all(|b| b.is_ascii_digit())
+        && bytes[2] == b'w'
+        && bytes[3..5].iter().all(|b| b.is_ascii_digit())
+        && bytes[5].is_ascii_lowercase();
+    let is_pre_release = name.starts_with("1.") && (name.contains("-pre") || name.contains("-rc"));
+
+    is_release || is_snapshot || is_pre_release
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    const HANDSHAKE: i32 = 765;
+
+    struct Case {
+        name: &'static str,
+        status: Value,
+        family: SoftwareFamily,
+        is_proxy: bool,
+        modded: bool,
+        range: Option<&'static str>,
+    }
+
+    fn cases() -> Vec<Case> {
+        vec![
+            Case {
+                name: "vanilla",
+                status: json!({
+                    "version": {"name": "1.20.4", "protocol": 765},
+                    "enforcesSecureChat": true,
+                    "description": {"text": "A Minecraft Server"},
+                    "players": {"max": 20, "online": 0}
+                }),
+                family: SoftwareFamily::Vanilla,
+                is_proxy: false,
+                modded: false,
+                range: Some("1.20.4"),
+            },
+            Case {
+                name: "vanilla snapshot",
+                status: json!({
+                    "version": {"name": "24w14a", "protocol": 1073742009},
+                    "description": "A Minecraft Server",
+                    "players": {"max": 20, "online": 0}
+                }),
+                family: SoftwareFamily::Vanilla,
+                is_proxy: false,
+                modded: false,
+                range: None,
+            },
+            Case {
+                name: "paper with FreedomChat",
+                status: json!({
+                    "version": {"name": "Paper 1.20.4", "protocol": 765},
+                    "preventsChatReports": true,
+                    "description": {"text": "", "extra": [{"text": "Survival", "color": "green"}]},
+                    "players": {"max": 100, "online": 12, "sample": []}
+                }),
+                family: SoftwareFamily::Paper,
+                is_proxy: false,
+                modded: false,
+                range: Some("1.20.4"),
+            },
+            Case {
+                name: "velocity",
+                status: json!({
+                    "version": {"name": "Velocity 3.3.0-SNAPSHOT", "protocol": 765},
+                    "description": {"text": "A Velocity Server"},
+                    "players": {"max": 500, "online": 3}
+                }),
+                family: SoftwareFamily::Velocity,
+                is_proxy: true,
+                modded: false,
+                range: None,
+            },
+            Case {
+                name: "bungeecord",
+                status: json!({
+                    "version": {"name": "BungeeCord 1.8.x-1.21.x", "protocol": 765},
+                    "description": "Another Bungee server",
+                    "players": {"max": 1, "online": 0}
+                }),
+                family: SoftwareFamily::BungeeCord,
+                is_proxy: true,
+                modded: false,
+                range: Some("1.8.x-1.21.x"),
+            },
+            Case {
+                name: "unbranded proxy echoing the handshake",
+                status: json!({
+                    "version": {"name": "1.8.x-1.21.x", "protocol": 765},
+                    "description": "Network",
+                    "players": {"max": 1000, "online": 42}
+                }),
+                family: SoftwareFamily::Unknown,
+                is_proxy: true,
+                modded: false,
+                range: Some("1.8.x-1.21.x"),
+            },
+            Case {
+                name: "forge 1.12 (FML1)",
+                status: json!({
+                    "version": {"name": "1.12.2", "protocol": 340},
+                    "description": {"text": "A Minecraft Server"},
+                    "players": {"max": 20, "online": 1},
+                    "modinfo": {"type": "FML", "modList": [
+                        {"modid": "minecraft", "version": "1.12.2"},
+                        {"modid": "forge", "version": "14.23.5.2860"},
+                        {"modid": "jei", "version": "4.16.1.301"}
+                    ]}
+                }),
+                family: SoftwareFamily::Forge,
+                is_proxy: false,
+                modded: true,
+                range: Some("1.12.2"),
+            },
+            Case {
+                name: "forge 1.16 (FML2)",
+                status: json!({
+                    "version": {"name": "1.16.5", "protocol": 754},
+                    "description": {"text": "A Minecraft Server"},
+                    "players": {"max": 20, "online": 0},
+                    "forgeData": {
+                        "channels": [{"res": "forge:tier_sorting", "version": "1.0", "required": false}],
+                        "mods": [
+                            {"modId": "forge", "modmarker": "36.2.39"},
+                            {"modId": "minecraft", "modmarker": "1.16.5"}
+                        ],
+                        "fmlNetworkVersion": 2
+                    }
+                }),
+                family: SoftwareFamily::Forge,
+                is_proxy: false,
+                modded: true,
+                range: Some("1.16.5"),
+            },
+            Case {
+                name: "neoforge",
+                status: json!({
+                    "version": {"name": "1.20.4", "protocol": 765},
+                    "isModded": true,
+                    "description": {"text": "A Minecraft Server"},
+                    "players": {"max": 20, "online": 0},
+                    "forgeData": {
+                        "channels": [],
+                        "mods": [
+                            {"modId": "minecraft", "modmarker": "1.20.4"},
+                            {"modId": "neoforge", "modmarker": "20.4.237"}
+                        ],
+                        "fmlNetworkVersion": 4
+                    }
+                }),
+                family: SoftwareFamily::NeoForge,
+                is_proxy: false,
+                modded: true,
+                range: Some("1.20.4"),
+            },
+            Case {
+                name: "isModded without a mod list",
+                status: json!({
+                    "version": {"name": "1.20.4", "protocol": 765},
+                    "isModded": true,
+                    "description": "A Minecraft Server",
+                    "players": {"max": 20, "online": 0}
+                }),
+                family: SoftwareFamily::Unknown,
+                is_proxy: false,
+                modded: true,
+                range: Some("1.20.4"),
+            },
+            // Fabric adds nothing to the status response; No Chat Reports does not make it
+            // tell-tale either, so it reads as vanilla
+            Case {
+                name: "fabric with No Chat Reports",
+                status: json!({
+                    "version": {"name": "1.20.1", "protocol": 763},
+                    "preventsChatReports": true,
+                    "description": {"text": "A Minecraft Server"},
+                    "players": {"max": 20, "online": 0}
+                }),
+                family: SoftwareFamily::Vanilla,
+                is_proxy: false,
+                modded: false,
+                range: Some("1.20.1"),
+            },
+            Case {
+                name: "branded fabric",
+                status: json!({
+                    "version": {"name": "Fabric 1.20.1", "protocol": 763},
+                    "description": "A Minecraft Server",
+                    "players": {"max": 20, "online": 0}
+                }),
+                family: SoftwareFamily::Fabric,
+                is_proxy: false,
+                modded: true,
+                range: Some("1.20.1"),
+            },
+        ]
+    }
+
+    #[test]
+    fn classifies_real_status_responses() {
+        for case in cases() {
+            let status: ServerStatus = serde_json::from_value(case.status).unwrap();
+            let print = fingerprint(&status, "", HANDSHAKE);
+            assert_eq!(print.family, case.family, "{}", case.name);
+            assert_eq!(print.is_proxy, case.is_proxy, "{}", case.name);
+            assert_eq!(print.modded, case.modded, "{}", case.name);
+            assert_eq!(
+                print.version_range().as_deref(),
+                case.range,
+                "{}",
+                case.name
+            );
+        }
+    }
+
+    #[test]
+    fn geyser_mentions_mark_a_bedrock_bridge() {
+        let status: ServerStatus = serde_json::from_value(json!({
+            "version": {"name": "Paper 1.20.4", "protocol": 765},
+            "description": "Join on Java or Bedrock!",
+            "players": {"max": 20, "online": 0}
+        }))
+        .unwrap();
+        let print = fingerprint(&status, "Join on Java or Bedrock!", HANDSHAKE);
+        assert!(print.bedrock_bridge);
+        assert_eq!(print.to_string(), "Paper+Geyser (backend, 1.20.4)");
+    }
+}
@@ -0,0 +1,142 @@
+//! Log-linear latency histogram in the style of HdrHistogram
+
+use std::time::Duration;
+
+/// Each power of two is split into 2^SUB_BUCKET_BITS buckets, keeping values within ~3%
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// Values are recorded in microseconds and clamped at ~134s, well past any timeout
+const MAX_MICROS: u64 = (1 << 27) - 1;
+
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; bucket_index(MAX_MICROS) + 1],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let micros = (duration.as_micros() as u64).min(MAX_MICROS);
+        self.counts[bucket_index(micros)] += 1;
+        self.total += 1;
+        self.max = self.max.max(micros);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    /// Upper bound of the bucket holding the `quantile` (0.0-1.0) sample
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((quantile * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(bucket_upper_bound(index).min(self.max));
+            }
+        }
+        Duration::from_micros(self.max)
+    }
+
+    /// p50, p95 and p99 in milliseconds
+    pub fn percentiles_ms(&self) -> [f64; 3] {
+        [0.50, 0.95, 0.99].map(|quantile| self.percentile(quantile).as_secs_f64() * 1000.0)
+    }
+
+    /// `12/40/180` milliseconds at p50/p95/p99
+    pub fn summary(&self) -> String {
+        let [p50, p95, p99] = self.percentiles_ms();
+        format!("{:.0}/{:.0}/{:.0}", p50, p95, p99)
+    }
+}
+
+fn bucket_index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (micros >> shift) - SUB_BUCKETS;
+    ((shift as u64 + 1) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let sub_bucket = index % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub_bucket + 1) << shift) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_bounds_cover_their_values_within_a_few_percent() {
+        for micros in [0, 1, 31, 32, 33, 63, 64, 1000, 12_345, 999_999, MAX_MICROS] {
+            let upper = bucket_upper_bound(bucket_index(micros));
+            assert!(
+                upper >= micros,
+                "{} above its bucket bound {}",
+                micros,
+                upper
+            );
+            assert!(
+                upper - micros <= micros / 16,
+                "{} rounded up to {}",
+                micros,
+                upper
+            );
+        }
+        assert_eq!(
+            bucket_index(MAX_MICROS) + 1,
+            LatencyHistogram::new().counts.len()
+        );
+    }
+
+    #[test]
+    fn percentiles_follow_the_recorded_distribution() {
+        let mut histogram = LatencyHistogram::new();
+        assert!(histogram.is_empty());
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.len(), 100);
+        assert_eq!(histogram.max(), Duration::from_millis(100));
+
+        let [p50, p95, p99] = histogram.percentiles_ms();
+        assert!((50.0..=52.0).contains(&p50), "p50 {}", p50);
+        assert!((95.0..=98.0).contains(&p95), "p95 {}", p95);
+        assert!((99.0..=100.0).contains(&p99), "p99 {}", p99);
+        assert_eq!(histogram.percentile(1.0), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn clamps_durations_past_the_range() {
+        let mut histogram = LatencyHistogram::new();
+        histogram.record(Duration::from_secs(3600));
+        assert_eq!(histogram.max(), Duration::from_micros(MAX_MICROS));
+        assert_eq!(histogram.summary(), "134218/134218/134218");
+    }
+}
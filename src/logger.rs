@@ -1,22 +1,23 @@
 //! Logger module for configuring and initializing logging functionality
 //! Source: [`sparrow`](https://github.com/JeroenGar/sparrow/tree/main)
 
+use crate::chat::strip_ansi;
 use crate::config::{EPOCH, LOG_LEVEL_FILTER_DEBUG, LOG_LEVEL_FILTER_RELEASE, OUTPUT_DIR};
 use log::{LevelFilter, debug};
 use std::fs;
+use std::io::IsTerminal;
 use std::sync::Once;
 use std::thread::Thread;
 use std::time::Duration;
 
 static INIT: Once = std::sync::Once::new();
 
-/// Initializes the logging system with the specified log level filter
+/// Initializes the logging system with the specified log level filter, also writing to a
+/// fresh `log_file` when one is given.
 /// This function is safe to call multiple times - it will only initialize once
-pub fn init_logger(level_filter: LevelFilter) {
+pub fn init_logger(level_filter: LevelFilter, log_file: Option<&str>) {
     INIT.call_once(|| {
-        // Remove old log file
-        let _ = fs::remove_file(format!("{}/log.txt", OUTPUT_DIR));
-        fern::Dispatch::new()
+        let mut dispatch = fern::Dispatch::new()
             // Perform allocation-free log formatting
             .format(|out, message, record| {
                 let handle: Thread = std::thread::current();
@@ -38,21 +39,55 @@ pub fn init_logger(level_filter: LevelFilter) {
 
                 out.finish(format_args!("{:<25}{}", prefix, message))
             })
-            // Add blanket level filter - all messages at or above the specified level will be logged
-            .level(level_filter)
-            .chain(std::io::stdout())
-            .chain(fern::log_file(format!("{OUTPUT_DIR}/log.txt")).unwrap())
-            .apply()
-            .expect("could not initialize logger");
-        debug!("[EPOCH]: {}", jiff::Timestamp::now().to_string());
+            // Filtering happens through `log::max_level`, so `set_level` can change it later
+            .level(LevelFilter::Trace)
+            .chain(
+                // Colored MOTDs only make sense on an interactive terminal
+                fern::Dispatch::new()
+                    .format(|out, message, _record| {
+                        if std::io::stdout().is_terminal() {
+                            out.finish(*message)
+                        } else {
+                            out.finish(format_args!("{}", strip_ansi(&message.to_string())))
+                        }
+                    })
+                    .chain(std::io::stdout()),
+            );
+        if let Some(path) = log_file {
+            // Remove old log file
+            let _ = fs::remove_file(path);
+            dispatch = dispatch.chain(
+                fern::Dispatch::new()
+                    .format(|out, message, _record| {
+                        out.finish(format_args!("{}", strip_ansi(&message.to_string())))
+                    })
+                    .chain(fern::log_file(path).unwrap()),
+            );
+        }
+        dispatch.apply().expect("could not initialize logger");
+        log::set_max_level(level_filter);
+        debug!("[EPOCH]: {}", jiff::Timestamp::now());
     });
 }
 
-pub fn setup_environment() {
-    fs::create_dir_all(OUTPUT_DIR).expect("Could not create output directory");
+/// Changes the level at runtime; `None` restores the build's default
+pub fn set_level(level: Option<LevelFilter>) {
+    log::set_max_level(level.unwrap_or_else(default_level));
+}
 
+fn default_level() -> LevelFilter {
     match cfg!(debug_assertions) {
-        true => init_logger(LOG_LEVEL_FILTER_DEBUG),
-        false => init_logger(LOG_LEVEL_FILTER_RELEASE),
+        true => LOG_LEVEL_FILTER_DEBUG,
+        false => LOG_LEVEL_FILTER_RELEASE,
+    }
+}
+
+/// `run_log` starts a new `output/log.txt`; only scans keep one, so the other subcommands
+/// can run next to a scan without wiping its log or creating `output/`
+pub fn setup_environment(run_log: bool) {
+    if run_log {
+        fs::create_dir_all(OUTPUT_DIR).expect("Could not create output directory");
     }
+    let path = format!("{}/log.txt", OUTPUT_DIR);
+    init_logger(default_level(), run_log.then_some(path.as_str()));
 }
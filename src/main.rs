@@ -1,17 +1,44 @@
+mod chat;
+mod checkpoint;
+mod cli;
 mod config;
+mod dedup;
 mod discord;
+mod export;
+mod fingerprint;
+mod histogram;
 mod logger;
+mod matrix;
+mod metrics;
 mod minecraft;
+mod mods;
 mod network;
+mod notifier;
+mod overrides;
+mod ports;
+mod privacy;
+mod protocol;
+mod proxy;
+mod queue;
+mod reload;
+mod retry;
+mod routing;
 mod scanner;
+mod shutdown;
+mod slack;
 mod stats;
+mod telegram;
+mod template;
+mod timeouts;
+mod validation;
+mod webhook;
 
 use crate::logger::setup_environment;
-
-use crate::scanner::run_scanner;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() {
-    setup_environment();
-    run_scanner().await;
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    setup_environment(cli::is_scan(&args));
+    cli::run(&args).await
 }
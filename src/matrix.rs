@@ -0,0 +1,170 @@
+//! Matrix rooms, posted to as `m.room.message` notices through the client-server API
+
+use crate::chat::{HtmlDialect, escape_html};
+use crate::config::{NotifierConfig, RouteRule};
+use crate::discord::MinecraftServer;
+use crate::notifier::{
+    Notifier, OutboundRequest, ServerCard, digest_line, pack_lines, progress_fields,
+};
+use crate::stats::ScanProgress;
+use crate::template::RenderError;
+use reqwest::Url;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Events are capped at 64 KiB; leave room for the plain-text body and envelope
+const MAX_FORMATTED_LEN: usize = 24_000;
+
+static TRANSACTION: AtomicU64 = AtomicU64::new(0);
+
+pub struct MatrixRoom {
+    /// `{homeserver}/_matrix/client/v3/rooms/{room_id}/send/m.room.message/`
+    send_url: Url,
+    access_token: String,
+    min_interval_ms: u64,
+}
+
+impl MatrixRoom {
+    pub fn new(config: &NotifierConfig) -> Result<Self, Box<dyn Error>> {
+        if config.url.is_empty() {
+            return Err("url (the homeserver) is required".into());
+        }
+        let room_id = config.room_id.as_deref().ok_or("room_id is required")?;
+        let access_token = config
+            .access_token
+            .clone()
+            .ok_or("access_token is required")?;
+
+        let mut send_url = Url::parse(&config.url)?;
+        send_url
+            .path_segments_mut()
+            .map_err(|_| "url cannot be a base URL")?
+            .pop_if_empty()
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room_id,
+                "send",
+                "m.room.message",
+                "",
+            ]);
+
+        Ok(Self {
+            send_url,
+            access_token,
+            min_interval_ms: config.min_interval_ms.unwrap_or(0),
+        })
+    }
+
+    /// Each event gets its own transaction id, so a retried request is never posted twice
+    fn request(&self, body: String, formatted_body: String) -> OutboundRequest {
+        let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let transaction = format!(
+            "mcsf-{}-{}",
+            nanos,
+            TRANSACTION.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let mut headers = BTreeMap::new();
+        headers.insert(
+            "Authorization".to_string(),
+            format!("Bearer {}", self.access_token),
+        );
+
+        OutboundRequest {
+            url: format!("{}{}", self.send_url, transaction),
+            method: "PUT".to_string(),
+            headers,
+            payload: json!({
+                "msgtype": "m.notice",
+                "body": body,
+                "format": "org.matrix.custom.html",
+                "formatted_body": formatted_body,
+            }),
+            body: None,
+            min_interval_ms: self.min_interval_ms,
+            attachments: Vec::new(),
+        }
+    }
+}
+
+impl Notifier for MatrixRoom {
+    fn server_found(
+        &self,
+        server: &MinecraftServer,
+        _route: Option<&RouteRule>,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let card = ServerCard::new(server);
+
+        let mut body = format!("🎮 {}", card.title);
+        let mut html = format!("<h4>🎮 {}</h4><ul>", card.title);
+        for (name, value) in &card.fields {
+            body.push_str(&format!("\n{}: {}", name, value));
+            html.push_str(&format!("<li><b>{}:</b> {}</li>", name, escape_html(value)));
+        }
+        html.push_str("</ul>");
+        if !server.description.trim().is_empty() {
+            body.push_str(&format!("\n\n{}", server.description));
+            html.push_str(&format!(
+                "<blockquote>{}</blockquote>",
+                server.motd.to_tagged_html(HtmlDialect::Matrix)
+            ));
+        }
+
+        if html.len() > MAX_FORMATTED_LEN {
+            html = escape_html(&body).replace('\n', "<br>");
+        }
+        Ok(vec![self.request(body, html)])
+    }
+
+    fn digest(
+        &self,
+        servers: &[MinecraftServer],
+        window_seconds: u64,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let title = format!(
+            "Servers found ({}) in the last {}s",
+            servers.len(),
+            window_seconds
+        );
+        let lines: Vec<String> = servers.iter().map(digest_line).collect();
+
+        Ok(pack_lines(&lines, MAX_FORMATTED_LEN / 2)
+            .into_iter()
+            .map(|chunk| {
+                let items: String = chunk
+                    .lines()
+                    .map(|line| format!("<li>{}</li>", escape_html(line)))
+                    .collect();
+                self.request(
+                    format!("{}\n{}", title, chunk),
+                    format!("<h4>{}</h4><ul>{}</ul>", escape_html(&title), items),
+                )
+            })
+            .collect())
+    }
+
+    fn progress(&self, progress: &ScanProgress) -> Result<Vec<OutboundRequest>, RenderError> {
+        let fields = progress_fields(progress);
+        let body = std::iter::once("📊 Scan Progress".to_string())
+            .chain(
+                fields
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, value)),
+            )
+            .collect::<Vec<_>>()
+            .join("\n");
+        let items: String = fields
+            .iter()
+            .map(|(name, value)| format!("<li><b>{}:</b> {}</li>", name, escape_html(value)))
+            .collect();
+        Ok(vec![self.request(
+            body,
+            format!("<h4>📊 Scan Progress</h4><ul>{}</ul>", items),
+        )])
+    }
+}
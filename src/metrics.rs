@@ -0,0 +1,421 @@
+//! Prometheus metrics, served over HTTP when `[metrics]` is enabled
+
+use crate::discord::MinecraftServer;
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, timeout};
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Upper bounds in seconds, spanning LAN round trips up to the longest sensible timeout
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Scrapes are tiny; anything slower is a stuck client
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counter partitioned by label values, in the order of `labels`
+pub struct LabelledCounter {
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl LabelledCounter {
+    fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, label_values: &[&str]) {
+        self.add(label_values, 1);
+    }
+
+    pub fn add(&self, label_values: &[&str], n: u64) {
+        let key = label_values.iter().map(|value| value.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_default() += n;
+    }
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = self.buckets.iter().position(|bound| seconds <= *bound) {
+            self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    pub targets_scanned: Counter,
+    pub open_ports: Counter,
+    /// By version family (`1.21`) and software
+    pub servers_found: LabelledCounter,
+    /// By stage (`port_check`, `status`) and `PingError` kind
+    pub probe_errors: LabelledCounter,
+    /// Probes that were retried, by stage and whether a retry succeeded
+    pub probe_retries: LabelledCounter,
+    pub port_checks_in_flight: Gauge,
+    /// Probes that found their task's source port range exhausted
+    pub source_port_fallbacks: Counter,
+    pub status_pings_in_flight: Gauge,
+    /// Outbound notification requests not yet delivered or dead-lettered
+    pub notification_queue_depth: Gauge,
+    pub notifications_delivered: Counter,
+    /// Failed attempts, including those that are retried
+    pub notification_delivery_failures: Counter,
+    pub notifications_dead_lettered: Counter,
+    /// TCP connect time of successful port checks
+    pub connect_duration: Histogram,
+    /// Status request round trip
+    pub status_rtt: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            targets_scanned: Counter::default(),
+            open_ports: Counter::default(),
+            servers_found: LabelledCounter::new(&["version", "software"]),
+            probe_errors: LabelledCounter::new(&["stage", "kind"]),
+            probe_retries: LabelledCounter::new(&["stage", "outcome"]),
+            port_checks_in_flight: Gauge::default(),
+            source_port_fallbacks: Counter::default(),
+            status_pings_in_flight: Gauge::default(),
+            notification_queue_depth: Gauge::default(),
+            notifications_delivered: Counter::default(),
+            notification_delivery_failures: Counter::default(),
+            notifications_dead_lettered: Counter::default(),
+            connect_duration: Histogram::new(LATENCY_BUCKETS),
+            status_rtt: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+
+    pub fn record_found(&self, server: &MinecraftServer) {
+        self.servers_found.inc(&[
+            &version_family(server.game_version()),
+            server.fingerprint.family.name(),
+        ]);
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "mcsf_targets_scanned_total",
+            "Addresses probed for an open port",
+            &self.targets_scanned,
+        );
+        counter(
+            &mut out,
+            "mcsf_open_ports_total",
+            "Addresses with the scan port open",
+            &self.open_ports,
+        );
+        labelled_counter(
+            &mut out,
+            "mcsf_servers_found_total",
+            "Servers that answered a status request",
+            &self.servers_found,
+        );
+        labelled_counter(
+            &mut out,
+            "mcsf_probe_errors_total",
+            "Failed probes by stage and error kind",
+            &self.probe_errors,
+        );
+        labelled_counter(
+            &mut out,
+            "mcsf_probe_retries_total",
+            "Probes that needed more than one attempt, by final outcome",
+            &self.probe_retries,
+        );
+
+        header(
+            &mut out,
+            "mcsf_probes_in_flight",
+            "Probes currently waiting on the network",
+            "gauge",
+        );
+        for (stage, gauge) in [
+            ("port_check", &self.port_checks_in_flight),
+            ("status", &self.status_pings_in_flight),
+        ] {
+            let _ = writeln!(
+                out,
+                "mcsf_probes_in_flight{{stage=\"{}\"}} {}",
+                stage,
+                gauge.0.load(Ordering::Relaxed)
+            );
+        }
+
+        counter(
+            &mut out,
+            "mcsf_source_port_fallbacks_total",
+            "Probes that used a kernel-assigned port because the task's range was exhausted",
+            &self.source_port_fallbacks,
+        );
+
+        header(
+            &mut out,
+            "mcsf_notification_queue_depth",
+            "Notification requests awaiting delivery",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "mcsf_notification_queue_depth {}",
+            self.notification_queue_depth.0.load(Ordering::Relaxed)
+        );
+        counter(
+            &mut out,
+            "mcsf_notifications_delivered_total",
+            "Notification requests accepted by their destination",
+            &self.notifications_delivered,
+        );
+        counter(
+            &mut out,
+            "mcsf_notification_delivery_failures_total",
+            "Failed notification delivery attempts, including retried ones",
+            &self.notification_delivery_failures,
+        );
+        counter(
+            &mut out,
+            "mcsf_notifications_dead_lettered_total",
+            "Notification requests given up on",
+            &self.notifications_dead_lettered,
+        );
+
+        histogram(
+            &mut out,
+            "mcsf_connect_duration_seconds",
+            "TCP connect time of successful port checks",
+            &self.connect_duration,
+        );
+        histogram(
+            &mut out,
+            "mcsf_status_rtt_seconds",
+            "Status request round trip",
+            &self.status_rtt,
+        );
+        out
+    }
+}
+
+/// `1.21` for `1.21.4`; anything not shaped like a release is `other` to bound cardinality
+fn version_family(version: &str) -> String {
+    let mut parts = version.split('.');
+    match (parts.next(), parts.next()) {
+        (Some("1"), Some(minor)) if minor.parse::<u32>().is_ok() => format!("1.{}", minor),
+        _ => "other".to_string(),
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, counter.0.load(Ordering::Relaxed));
+}
+
+fn labelled_counter(out: &mut String, name: &str, help: &str, counter: &LabelledCounter) {
+    header(out, name, help, "counter");
+    for (values, count) in counter.values.lock().unwrap().iter() {
+        let labels: Vec<String> = counter
+            .labels
+            .iter()
+            .zip(values)
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+            .collect();
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), count);
+    }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, help, "histogram");
+    let mut cumulative = 0;
+    for (bound, count) in histogram.buckets.iter().zip(&histogram.counts) {
+        cumulative += count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let total = histogram.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, total);
+    let _ = writeln!(
+        out,
+        "{}_sum {}",
+        name,
+        histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    );
+    let _ = writeln!(out, "{}_count {}", name, total);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` until the process exits
+pub async fn serve(listen: String) {
+    let listener = match TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not start metrics endpoint on {}: {}", listen, e);
+            return;
+        }
+    };
+    info!("Serving Prometheus metrics on http://{}/metrics", listen);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = timeout(SCRAPE_TIMEOUT, handle_scrape(stream)).await {
+                        debug!("Metrics request from {} timed out: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => error!("Metrics endpoint accept failed: {}", e),
+        }
+    }
+}
+
+async fn handle_scrape(mut stream: TcpStream) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = METRICS.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_render_cumulative_buckets() {
+        let metrics = Metrics::new();
+        for ms in [3, 30, 20_000] {
+            metrics.status_rtt.observe(Duration::from_millis(ms));
+        }
+        let out = metrics.render();
+        for line in [
+            "# TYPE mcsf_status_rtt_seconds histogram",
+            "mcsf_status_rtt_seconds_bucket{le=\"0.005\"} 1",
+            "mcsf_status_rtt_seconds_bucket{le=\"0.025\"} 1",
+            "mcsf_status_rtt_seconds_bucket{le=\"0.05\"} 2",
+            "mcsf_status_rtt_seconds_bucket{le=\"10\"} 2",
+            "mcsf_status_rtt_seconds_bucket{le=\"+Inf\"} 3",
+            "mcsf_status_rtt_seconds_sum 20.033",
+            "mcsf_status_rtt_seconds_count 3",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = Metrics::new();
+        metrics
+            .probe_errors
+            .inc(&["status", "bad \"kind\"\\\n} 1\nfake_metric"]);
+        let out = metrics.render();
+        assert!(out.lines().any(|line| line
+            == r#"mcsf_probe_errors_total{stage="status",kind="bad \"kind\"\\\n} 1\nfake_metric"} 1"#));
+        assert!(!out.lines().any(|line| line.starts_with("fake_metric")));
+    }
+
+    #[test]
+    fn version_families_bound_label_cardinality() {
+        assert_eq!(version_family("1.21.4"), "1.21");
+        assert_eq!(version_family("1.8"), "1.8");
+        assert_eq!(version_family("Paper 1.20.4"), "other");
+        assert_eq!(version_family("1.x-anything"), "other");
+        assert_eq!(version_family(""), "other");
+    }
+}
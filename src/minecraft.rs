@@ -1,133 +1,230 @@
+use crate::proxy::Proxy;
 use serde::Deserialize;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::{Duration, timeout};
+use tokio::net::{TcpSocket, TcpStream, lookup_host};
+use tokio::time::{Duration, Instant, timeout};
 
 #[derive(Debug)]
 pub enum PingError {
-    Timeout,
+    /// No answer to the TCP handshake in time
+    ConnectTimeout,
     ConnectionRefused,
+    /// Reset or closed by the peer before a full response arrived
+    ConnectionReset,
+    /// Our own source port is taken or the ephemeral range is exhausted
+    AddrInUse,
     NetworkError(String),
+    /// Connected, but no status response in time
+    ProtocolTimeout,
+    MalformedVarint,
+    BadJson(String),
+    /// Pre-1.7 server answering with a `0xFF` kick packet
+    LegacyKick,
     ProtocolError(String),
+    /// The proxy could not be reached, rejected us or failed the tunnel
+    Proxy(String),
 }
 
 impl std::fmt::Display for PingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PingError::Timeout => write!(f, "Timeout"),
+            PingError::ConnectTimeout => write!(f, "Connect timeout"),
             PingError::ConnectionRefused => write!(f, "Connection refused"),
+            PingError::ConnectionReset => write!(f, "Connection reset"),
+            PingError::AddrInUse => write!(f, "Source address in use"),
             PingError::NetworkError(msg) => write!(f, "Network error: {}", msg),
+            PingError::ProtocolTimeout => write!(f, "Status response timeout"),
+            PingError::MalformedVarint => write!(f, "Malformed varint"),
+            PingError::BadJson(msg) => write!(f, "Bad status JSON: {}", msg),
+            PingError::LegacyKick => write!(f, "Legacy server kick"),
             PingError::ProtocolError(msg) => write!(f, "Protocol error: {}", msg),
+            PingError::Proxy(msg) => write!(f, "Proxy error: {}", msg),
         }
     }
 }
 
 impl std::error::Error for PingError {}
 
+/// Every label `PingError::kind` returns
+pub const PING_ERROR_KINDS: &[&str] = &[
+    "connect_timeout",
+    "refused",
+    "reset",
+    "addr_in_use",
+    "network",
+    "protocol_timeout",
+    "bad_varint",
+    "bad_json",
+    "legacy_kick",
+    "protocol",
+    "proxy",
+];
+
+impl PingError {
+    /// Short label for stats and metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PingError::ConnectTimeout => "connect_timeout",
+            PingError::ConnectionRefused => "refused",
+            PingError::ConnectionReset => "reset",
+            PingError::AddrInUse => "addr_in_use",
+            PingError::NetworkError(_) => "network",
+            PingError::ProtocolTimeout => "protocol_timeout",
+            PingError::MalformedVarint => "bad_varint",
+            PingError::BadJson(_) => "bad_json",
+            PingError::LegacyKick => "legacy_kick",
+            PingError::ProtocolError(_) => "protocol",
+            PingError::Proxy(_) => "proxy",
+        }
+    }
+
+    pub fn from_io(e: io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::ConnectionRefused => PingError::ConnectionRefused,
+            ErrorKind::TimedOut => PingError::ConnectTimeout,
+            ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable => PingError::AddrInUse,
+            ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof => PingError::ConnectionReset,
+            _ => PingError::NetworkError(e.to_string()),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ServerStatus {
     pub version: Version,
     pub players: Players,
     #[serde(default)]
     pub description: serde_json::Value,
+    /// FML2/FML3 handshake data advertised by Forge 1.13+ servers
+    #[serde(default, rename = "forgeData")]
+    pub forge_data: Option<serde_json::Value>,
+    /// FML1 mod list advertised by Forge 1.7 - 1.12 servers
+    #[serde(default)]
+    pub modinfo: Option<serde_json::Value>,
+    /// Set by NeoForge and some other modded servers; it does not say which loader
+    #[serde(default, rename = "isModded")]
+    pub is_modded: Option<bool>,
+    /// `data:image/png;base64,...` server icon
+    #[serde(default)]
+    pub favicon: Option<String>,
+    /// Time from sending the status request to receiving the response
+    #[serde(skip)]
+    pub latency: Option<Duration>,
+    /// Time from sending the status request to the first response byte
+    #[serde(skip)]
+    pub first_byte: Option<Duration>,
+    /// TCP connect time of the status connection, including the proxy handshake
+    #[serde(skip)]
+    pub connect_time: Option<Duration>,
+    /// Name of the proxy the status ping went through
+    #[serde(skip)]
+    pub proxy: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Version {
     pub name: String,
+    #[serde(default)]
+    pub protocol: i32,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Players {
     pub max: u32,
     pub online: u32,
+    /// Some servers use this for extra MOTD lines instead of player names
+    #[serde(default)]
+    pub sample: Vec<PlayerSample>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PlayerSample {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+}
+
+/// Largest status response accepted; vanilla caps strings at 32767 UTF-16 units
+pub const MAX_STATUS_LEN: i32 = 1 << 20;
+
+/// Opens an IPv4 connection from `source`; `0.0.0.0:0` leaves address and port to the kernel
+pub async fn connect(
+    server_ip: &str,
+    server_port: u16,
+    source: SocketAddrV4,
+) -> io::Result<TcpStream> {
+    let target = lookup_host((server_ip, server_port))
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no IPv4 address"))?;
+    let socket = TcpSocket::new_v4()?;
+    socket.set_nodelay(true)?;
+    if source != SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0) {
+        socket.set_reuseaddr(true)?;
+        socket.bind(source.into())?;
+    }
+    socket.connect(target).await
+}
+
+/// Connects and immediately drops the connection
+/// Whether the port accepts connections; through a proxy the tunnel has to open, so the
+/// target sees the proxy's address rather than ours
 pub async fn quick_port_check(
     server_ip: &str,
     server_port: u16,
-    source_port: Option<u16>,
+    source: SocketAddrV4,
+    proxy: Option<&Proxy>,
     timeout_ms: u64,
-) -> Result<bool, PingError> {
-    let socket_address = format!("{}:{}", server_ip, server_port);
-
-    let stream_result = if let Some(src_port) = source_port {
-        let local_addr = format!("0.0.0.0:{}", src_port);
-        timeout(Duration::from_millis(timeout_ms), async {
-            let socket = tokio::net::TcpSocket::new_v4()
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-
-            socket.set_reuseaddr(true)?;
-            socket.set_nodelay(true)?;
-
-            socket.bind(local_addr.parse().unwrap())?;
-            socket.connect(socket_address.parse().unwrap()).await
-        })
-        .await
-    } else {
-        timeout(Duration::from_millis(timeout_ms), async {
-            let socket = tokio::net::TcpSocket::new_v4()
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-            socket.set_nodelay(true)?;
-            socket.connect(socket_address.parse().unwrap()).await
-        })
-        .await
-    };
+) -> Result<(), PingError> {
+    let stream_result = timeout(Duration::from_millis(timeout_ms), async {
+        match proxy {
+            Some(proxy) => proxy.connect(server_ip, server_port, source).await,
+            None => connect(server_ip, server_port, source)
+                .await
+                .map_err(PingError::from_io),
+        }
+    })
+    .await;
 
     match stream_result {
-        Ok(Ok(_stream)) => Ok(true),
-        Ok(Err(e)) => match e.kind() {
-            std::io::ErrorKind::ConnectionRefused => Ok(false),
-            std::io::ErrorKind::TimedOut => Ok(false),
-            std::io::ErrorKind::AddrInUse => Ok(false),
-            _ => Ok(false),
-        },
-        Err(_) => Ok(false),
+        Ok(Ok(_stream)) => Ok(()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(PingError::ConnectTimeout),
     }
 }
 
 pub async fn ping_server_fast(
     server_ip: &str,
     server_port: u16,
-    source_port: Option<u16>,
+    source: SocketAddrV4,
+    proxy: Option<&Proxy>,
     connection_timeout_ms: u64,
     protocol_timeout_ms: u64,
     protocol_version: i32,
 ) -> Result<ServerStatus, PingError> {
-    let socket_address = format!("{}:{}", server_ip, server_port);
-
-    let stream = if let Some(src_port) = source_port {
-        let local_addr = format!("0.0.0.0:{}", src_port);
-        timeout(Duration::from_millis(connection_timeout_ms), async {
-            let socket = tokio::net::TcpSocket::new_v4()
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-            socket.set_reuseaddr(true)?;
-            socket.bind(local_addr.parse().unwrap())?;
-            socket.connect(socket_address.parse().unwrap()).await
-        })
-        .await
-    } else {
-        timeout(
-            Duration::from_millis(connection_timeout_ms),
-            TcpStream::connect(&socket_address),
-        )
-        .await
-    };
+    let connect_started = Instant::now();
+    let stream = timeout(Duration::from_millis(connection_timeout_ms), async {
+        match proxy {
+            Some(proxy) => proxy.connect(server_ip, server_port, source).await,
+            None => connect(server_ip, server_port, source)
+                .await
+                .map_err(PingError::from_io),
+        }
+    })
+    .await;
 
-    let stream = match stream {
+    let mut tcp_stream = match stream {
         Ok(Ok(stream)) => stream,
-        Ok(Err(e)) => {
-            return Err(match e.kind() {
-                std::io::ErrorKind::ConnectionRefused => PingError::ConnectionRefused,
-                std::io::ErrorKind::TimedOut => PingError::Timeout,
-                std::io::ErrorKind::AddrInUse => PingError::NetworkError("Port in use".to_string()),
-                _ => PingError::NetworkError(e.to_string()),
-            });
-        }
-        Err(_) => return Err(PingError::Timeout),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(PingError::ConnectTimeout),
     };
-
-    let mut tcp_stream = stream;
+    let connect_time = connect_started.elapsed();
 
     let ip_bytes = server_ip.as_bytes();
     let mut handshake_packet = Vec::with_capacity(32);
@@ -144,36 +241,52 @@ pub async fn ping_server_fast(
     packet_to_send.extend(handshake_packet);
 
     let status_request = [0x01, 0x00];
+    let requested_at = Instant::now();
     tcp_stream
         .write_all(&packet_to_send)
         .await
-        .map_err(|e| PingError::NetworkError(e.to_string()))?;
+        .map_err(PingError::from_io)?;
     tcp_stream
         .write_all(&status_request)
         .await
-        .map_err(|e| PingError::NetworkError(e.to_string()))?;
+        .map_err(PingError::from_io)?;
 
     let response_result = timeout(Duration::from_millis(protocol_timeout_ms), async {
-        let _response_packet_length = read_varint_async(&mut tcp_stream)
-            .await
-            .map_err(|e| PingError::ProtocolError(e.to_string()))?;
-        let _response_packet_id = read_varint_async(&mut tcp_stream)
-            .await
-            .map_err(|e| PingError::ProtocolError(e.to_string()))?;
-        let json_length = read_varint_async(&mut tcp_stream)
-            .await
-            .map_err(|e| PingError::ProtocolError(e.to_string()))?;
+        let first_byte = tcp_stream.read_u8().await.map_err(PingError::from_io)?;
+        let first_byte_at = requested_at.elapsed();
+        if first_byte == 0xFF {
+            return Err(PingError::LegacyKick);
+        }
+        let _response_packet_length = read_varint_async(&mut tcp_stream, Some(first_byte)).await?;
+        let response_packet_id = read_varint_async(&mut tcp_stream, None).await?;
+        if response_packet_id != 0x00 {
+            return Err(PingError::ProtocolError(format!(
+                "unexpected packet id {:#04x}",
+                response_packet_id
+            )));
+        }
+        let json_length = read_varint_async(&mut tcp_stream, None).await?;
+        if !(0..=MAX_STATUS_LEN).contains(&json_length) {
+            return Err(PingError::ProtocolError(format!(
+                "implausible status length {}",
+                json_length
+            )));
+        }
 
         let mut json_buffer = vec![0; json_length as usize];
         tcp_stream
             .read_exact(&mut json_buffer)
             .await
-            .map_err(|e| PingError::NetworkError(e.to_string()))?;
+            .map_err(PingError::from_io)?;
 
         let json_string =
-            String::from_utf8(json_buffer).map_err(|e| PingError::ProtocolError(e.to_string()))?;
-        let server_status: ServerStatus = serde_json::from_str(&json_string)
-            .map_err(|e| PingError::ProtocolError(e.to_string()))?;
+            String::from_utf8(json_buffer).map_err(|e| PingError::BadJson(e.to_string()))?;
+        let mut server_status: ServerStatus =
+            serde_json::from_str(&json_string).map_err(|e| PingError::BadJson(e.to_string()))?;
+        server_status.latency = Some(requested_at.elapsed());
+        server_status.first_byte = Some(first_byte_at);
+        server_status.connect_time = Some(connect_time);
+        server_status.proxy = proxy.map(|proxy| proxy.name.clone());
 
         Ok::<ServerStatus, PingError>(server_status)
     })
@@ -182,30 +295,13 @@ pub async fn ping_server_fast(
     match response_result {
         Ok(Ok(status)) => Ok(status),
         Ok(Err(e)) => Err(e),
-        Err(_) => Err(PingError::Timeout),
+        Err(_) => Err(PingError::ProtocolTimeout),
     }
 }
 
-pub fn extract_description(desc: &serde_json::Value) -> String {
-    if let Some(text) = desc.get("text") {
-        return text.as_str().unwrap_or("").to_string();
-    }
-
-    if let Some(extra) = desc.get("extra") {
-        if let Some(array) = extra.as_array() {
-            return array
-                .iter()
-                .filter_map(|v| v.get("text").and_then(|t| t.as_str()))
-                .collect::<Vec<_>>()
-                .join("");
-        }
-    }
-
-    desc.to_string()
-}
-
-/// Encode Minecraft protocol varint
-fn encode_varint(mut value: i32) -> Vec<u8> {
+/// Encode Minecraft protocol varint; negative values take the full five bytes
+fn encode_varint(value: i32) -> Vec<u8> {
+    let mut value = value as u32;
     let mut out = vec![];
     loop {
         if (value & !0x7F) == 0 {
@@ -218,24 +314,39 @@ fn encode_varint(mut value: i32) -> Vec<u8> {
     }
 }
 
-/// Read Minecraft protocol varint from stream
+/// Read Minecraft protocol varint from stream, starting with `first_byte` if already read
 async fn read_varint_async<R: AsyncReadExt + Unpin>(
     reader: &mut R,
-) -> Result<i32, Box<dyn std::error::Error>> {
+    mut first_byte: Option<u8>,
+) -> Result<i32, PingError> {
     let mut num_read = 0;
     let mut result = 0;
     loop {
-        let mut buf = [0u8; 1];
-        reader.read_exact(&mut buf).await?;
-        let byte = buf[0];
+        let byte = match first_byte.take() {
+            Some(byte) => byte,
+            None => reader.read_u8().await.map_err(PingError::from_io)?,
+        };
         result |= ((byte & 0x7F) as i32) << (7 * num_read);
         num_read += 1;
-        if num_read > 5 {
-            return Err("VarInt too big".into());
-        }
         if (byte & 0x80) == 0 {
             break;
         }
+        if num_read == 5 {
+            return Err(PingError::MalformedVarint);
+        }
     }
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_varints_including_negative_values() {
+        assert_eq!(encode_varint(0), [0x00]);
+        assert_eq!(encode_varint(765), [0xfd, 0x05]);
+        assert_eq!(encode_varint(-1), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(encode_varint(i32::MIN), [0x80, 0x80, 0x80, 0x80, 0x08]);
+    }
+}
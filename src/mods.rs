@@ -0,0 +1,364 @@
+//! Mod and channel list extraction from Forge `modinfo` / `forgeData` status fields
+
+use crate::minecraft::{MAX_STATUS_LEN, ServerStatus};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModEntry {
+    pub id: String,
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelEntry {
+    pub name: String,
+    pub version: String,
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModList {
+    /// `FML1`, `FML2` or `FML3`
+    pub format: String,
+    pub mods: Vec<ModEntry>,
+    pub channels: Vec<ChannelEntry>,
+    /// Server cut the list short to keep the status response small
+    pub truncated: bool,
+}
+
+impl ModList {
+    /// Checks for a mod id, ignoring ASCII case
+    pub fn has_mod(&self, id: &str) -> bool {
+        self.mods.iter().any(|m| m.id.eq_ignore_ascii_case(id))
+    }
+
+    /// Comma separated mod ids, without the always-present loader entries
+    pub fn summary(&self) -> String {
+        self.mods
+            .iter()
+            .filter(|m| {
+                !matches!(
+                    m.id.as_str(),
+                    "minecraft" | "forge" | "neoforge" | "mcp" | "FML"
+                )
+            })
+            .map(|m| m.id.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Decodes the mod list advertised by a status response, if any
+pub fn extract_mods(status: &ServerStatus) -> Option<ModList> {
+    if let Some(forge_data) = &status.forge_data {
+        return parse_forge_data(forge_data);
+    }
+    status.modinfo.as_ref().and_then(parse_modinfo)
+}
+
+/// FML1 (1.7 - 1.12): `{"type": "FML", "modList": [{"modid": .., "version": ..}]}`
+fn parse_modinfo(modinfo: &Value) -> Option<ModList> {
+    let list = modinfo.get("modList")?.as_array()?;
+    let mods = list
+        .iter()
+        .filter_map(|m| {
+            Some(ModEntry {
+                id: m.get("modid")?.as_str()?.to_string(),
+                version: m.get("version").and_then(Value::as_str).map(str::to_string),
+            })
+        })
+        .collect();
+
+    Some(ModList {
+        format: "FML1".to_string(),
+        mods,
+        channels: Vec::new(),
+        truncated: false,
+    })
+}
+
+/// FML2 (1.13 - 1.17) lists mods and channels as JSON, FML3 (1.18+) packs them into `d`
+fn parse_forge_data(forge_data: &Value) -> Option<ModList> {
+    let network_version = forge_data
+        .get("fmlNetworkVersion")
+        .and_then(Value::as_i64)
+        .unwrap_or(2);
+    let truncated = forge_data
+        .get("truncated")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+
+    if let Some(encoded) = forge_data.get("d").and_then(Value::as_str) {
+        let mut list = decode_optimized(encoded)?;
+        list.format = format!("FML{}", network_version);
+        list.truncated |= truncated;
+        return Some(list);
+    }
+
+    let mods = forge_data
+        .get("mods")
+        .and_then(Value::as_array)
+        .map(|mods| {
+            mods.iter()
+                .filter_map(|m| {
+                    Some(ModEntry {
+                        id: m.get("modId")?.as_str()?.to_string(),
+                        version: m
+                            .get("modmarker")
+                            .and_then(Value::as_str)
+                            .map(str::to_string),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let channels = forge_data
+        .get("channels")
+        .and_then(Value::as_array)
+        .map(|channels| {
+            channels
+                .iter()
+                .filter_map(|c| {
+                    Some(ChannelEntry {
+                        name: c.get("res")?.as_str()?.to_string(),
+                        version: c
+                            .get("version")
+                            .and_then(Value::as_str)
+                            .unwrap_or("")
+                            .to_string(),
+                        required: c.get("required").and_then(Value::as_bool).unwrap_or(false),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(ModList {
+        format: format!("FML{}", network_version),
+        mods,
+        channels,
+        truncated,
+    })
+}
+
+/// Unpacks Forge's `ServerStatusPing` encoding: the first two UTF-16 units hold the
+/// byte length, every following unit carries 15 bits of payload.
+fn decode_optimized(encoded: &str) -> Option<ModList> {
+    let units: Vec<u16> = encoded.encode_utf16().collect();
+    if units.len() < 2 {
+        return None;
+    }
+
+    // The length comes from the server: it must fit the payload actually sent
+    let size = (units[0] & 0x7FFF) as usize | (((units[1] & 0x7FFF) as usize) << 15);
+    if size > (units.len() - 2) * 15 / 8 || size > MAX_STATUS_LEN as usize {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(size);
+    let mut buffer: u32 = 0;
+    let mut bits_in_buffer = 0;
+
+    for &unit in &units[2..] {
+        while bits_in_buffer >= 8 {
+            bytes.push(buffer as u8);
+            buffer >>= 8;
+            bits_in_buffer -= 8;
+        }
+        buffer |= ((unit & 0x7FFF) as u32) << bits_in_buffer;
+        bits_in_buffer += 15;
+    }
+    while bytes.len() < size {
+        bytes.push(buffer as u8);
+        buffer >>= 8;
+    }
+    bytes.truncate(size);
+
+    let mut reader = ByteReader {
+        bytes: &bytes,
+        pos: 0,
+    };
+    let truncated = reader.read_bool()?;
+    let mod_count = reader.read_u16()?;
+
+    let mut mods = Vec::with_capacity((mod_count as usize).min(bytes.len()));
+    let mut channels = Vec::new();
+    for _ in 0..mod_count {
+        let flags = reader.read_varint()?;
+        let channel_count = flags >> 1;
+        let ignore_server_only = flags & 1 != 0;
+        let id = reader.read_string()?;
+        let version = if ignore_server_only {
+            None
+        } else {
+            Some(reader.read_string()?)
+        };
+        for _ in 0..channel_count {
+            let name = reader.read_string()?;
+            let version = reader.read_string()?;
+            let required = reader.read_bool()?;
+            channels.push(ChannelEntry {
+                name: format!("{}:{}", id, name),
+                version,
+                required,
+            });
+        }
+        mods.push(ModEntry { id, version });
+    }
+
+    let other_channels = reader.read_varint()?;
+    for _ in 0..other_channels {
+        let name = reader.read_string()?;
+        let version = reader.read_string()?;
+        let required = reader.read_bool()?;
+        channels.push(ChannelEntry {
+            name,
+            version,
+            required,
+        });
+    }
+
+    Some(ModList {
+        format: String::new(),
+        mods,
+        channels,
+        truncated,
+    })
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl ByteReader<'_> {
+    fn read_u8(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn read_bool(&mut self) -> Option<bool> {
+        self.read_u8().map(|b| b != 0)
+    }
+
+    fn read_u16(&mut self) -> Option<u16> {
+        Some(((self.read_u8()? as u16) << 8) | self.read_u8()? as u16)
+    }
+
+    fn read_varint(&mut self) -> Option<u32> {
+        let mut result = 0u32;
+        for i in 0..5 {
+            let byte = self.read_u8()?;
+            result |= ((byte & 0x7F) as u32) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    fn read_string(&mut self) -> Option<String> {
+        let len = self.read_varint()? as usize;
+        let end = self.pos.checked_add(len)?;
+        let raw = self.bytes.get(self.pos..end)?;
+        self.pos = end;
+        String::from_utf8(raw.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs bytes the way Forge's `ServerStatusPing` does
+    fn encode(bytes: &[u8]) -> String {
+        let mut units = vec![
+            (bytes.len() & 0x7FFF) as u16,
+            ((bytes.len() >> 15) & 0x7FFF) as u16,
+        ];
+        let mut buffer: u32 = 0;
+        let mut bits = 0;
+        for &byte in bytes {
+            buffer |= (byte as u32) << bits;
+            bits += 8;
+            while bits >= 15 {
+                units.push((buffer & 0x7FFF) as u16);
+                buffer >>= 15;
+                bits -= 15;
+            }
+        }
+        if bits > 0 {
+            units.push((buffer & 0x7FFF) as u16);
+        }
+        String::from_utf16(&units).unwrap()
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = vec![value.len() as u8];
+        bytes.extend(value.as_bytes());
+        bytes
+    }
+
+    /// One mod `jei` 1.0 with channel `main`, no other channels
+    fn payload() -> Vec<u8> {
+        let mut bytes = vec![0, 0, 1, 2];
+        bytes.extend(string("jei"));
+        bytes.extend(string("1.0"));
+        bytes.extend(string("main"));
+        bytes.extend(string("1"));
+        bytes.push(1);
+        bytes.push(0);
+        bytes
+    }
+
+    #[test]
+    fn decodes_packed_mod_list() {
+        let list = decode_optimized(&encode(&payload())).unwrap();
+        assert!(!list.truncated);
+        assert_eq!(list.mods.len(), 1);
+        assert_eq!(list.mods[0].id, "jei");
+        assert_eq!(list.mods[0].version.as_deref(), Some("1.0"));
+        assert_eq!(list.channels.len(), 1);
+        assert_eq!(list.channels[0].name, "jei:main");
+        assert!(list.channels[0].required);
+    }
+
+    #[test]
+    fn rejects_size_beyond_payload() {
+        // Claims 2^30 - 1 bytes with a handful of payload units
+        let mut units = vec![0x7FFF, 0x7FFF];
+        units.extend([0x1234; 4]);
+        assert!(decode_optimized(&String::from_utf16(&units).unwrap()).is_none());
+    }
+
+    #[test]
+    fn masks_header_units() {
+        // Unmasked, the high bits would claim about 2 GiB; masked the size is 0x7FFF
+        let units = vec![0xFFFF, 0x0000, 0x0001];
+        assert!(decode_optimized(&String::from_utf16(&units).unwrap()).is_none());
+    }
+
+    #[test]
+    fn rejects_size_above_status_limit() {
+        let size = MAX_STATUS_LEN as usize + 1;
+        let mut units = vec![(size & 0x7FFF) as u16, (size >> 15) as u16];
+        units.resize(2 + size * 8 / 15 + 1, 0);
+        assert!(decode_optimized(&String::from_utf16(&units).unwrap()).is_none());
+    }
+
+    #[test]
+    fn rejects_short_and_cut_off_input() {
+        assert!(decode_optimized("").is_none());
+        assert!(decode_optimized("a").is_none());
+        let bytes = payload();
+        assert!(decode_optimized(&encode(&bytes[..bytes.len() - 3])).is_none());
+    }
+
+    #[test]
+    fn mod_count_does_not_drive_allocation() {
+        // Claims 65535 mods in a four byte payload
+        assert!(decode_optimized(&encode(&[0, 0xFF, 0xFF, 0])).is_none());
+    }
+}
@@ -1,15 +1,16 @@
+use crate::config::SUBNETS_FILE;
 use log::{info, warn};
 use rand::Rng;
 use std::fs;
 use std::net::Ipv4Addr;
 
 pub fn load_subnets() -> Vec<(Ipv4Addr, u8)> {
-    let content = match fs::read_to_string("assets/ips.txt") {
+    let content = match fs::read_to_string(SUBNETS_FILE) {
         Ok(content) => content,
         Err(e) => {
             warn!(
-                "Could not read assets/ips.txt: {}, falling back to random IPs",
-                e
+                "Could not read {}: {}, falling back to random IPs",
+                SUBNETS_FILE, e
             );
             return Vec::new();
         }
@@ -22,12 +23,8 @@ pub fn load_subnets() -> Vec<(Ipv4Addr, u8)> {
             continue;
         }
 
-        if let Some((ip_str, prefix_str)) = line.split_once('/') {
-            if let (Ok(ip), Ok(prefix)) = (ip_str.parse::<Ipv4Addr>(), prefix_str.parse::<u8>()) {
-                if prefix <= 32 {
-                    subnets.push((ip, prefix));
-                }
-            }
+        if let Some(subnet) = parse_cidr(line) {
+            subnets.push(subnet);
         }
     }
 
@@ -35,6 +32,21 @@ pub fn load_subnets() -> Vec<(Ipv4Addr, u8)> {
     subnets
 }
 
+/// `203.0.113.0/24` into its network address and prefix length
+pub fn parse_cidr(cidr: &str) -> Option<(Ipv4Addr, u8)> {
+    let (ip_str, prefix_str) = cidr.split_once('/')?;
+    let ip = ip_str.parse::<Ipv4Addr>().ok()?;
+    let prefix = prefix_str.parse::<u8>().ok()?;
+    (prefix <= 32).then_some((ip, prefix))
+}
+
+/// Whether `ip` lies in `network/prefix`
+pub fn contains(network: Ipv4Addr, prefix: u8, ip: Ipv4Addr) -> bool {
+    let shift = 32 - prefix.min(32) as u32;
+    u32::from(network).checked_shr(shift).unwrap_or(0)
+        == u32::from(ip).checked_shr(shift).unwrap_or(0)
+}
+
 /// Generate a random IP address within a given subnet
 pub fn random_ip_from_subnet(network: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
     let mut rng = rand::rng();
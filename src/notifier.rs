@@ -0,0 +1,1285 @@
+//! Destination-agnostic notification delivery: notifiers render requests, one worker per
+//! destination sends them
+
+use crate::config::{DigestConfig, EmbedConfig, NotifierConfig, NotifierKind, RouteRule};
+use crate::dedup::SeenSet;
+use crate::discord::{DiscordWebhook, EmbedTemplate, MinecraftServer, with_geo_info};
+use crate::matrix::MatrixRoom;
+use crate::metrics::METRICS;
+use crate::privacy::{Privacy, RedactingNotifier};
+use crate::queue::{NotificationQueue, QueuedMessage, QueuedServer};
+use crate::routing::Router;
+use crate::slack::SlackWebhook;
+use crate::stats::ScanProgress;
+use crate::telegram::TelegramChat;
+use crate::template::RenderError;
+use crate::webhook::WebhookNotifier;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{debug, error, info, warn};
+use reqwest::header::HeaderMap;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, sleep, sleep_until};
+
+/// Failed attempts (network errors, 5xx) before a notification is dead-lettered.
+/// Rate limiting never counts as a failure.
+const MAX_FAILED_ATTEMPTS: u32 = 5;
+const MAX_BACKOFF_SECS: u64 = 60;
+
+/// Wait after a 429 that did not say how long, or said it unreadably
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Longest rate limit wait honoured, whatever the destination asks for
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60 * 60);
+
+/// One HTTP request rendered by a notifier, persisted as-is in the on-disk queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboundRequest {
+    #[serde(rename = "webhook")]
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// JSON body, unless `body` is set
+    #[serde(default)]
+    pub payload: serde_json::Value,
+    /// Pre-rendered body sent verbatim
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Minimum spacing between requests to the same URL, for platforms that
+    /// throttle before answering 429
+    #[serde(default)]
+    pub min_interval_ms: u64,
+    /// Files uploaded with `payload` as multipart form data
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    /// Base64 encoded file contents
+    pub data: String,
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+impl OutboundRequest {
+    pub fn json(url: &str, payload: serde_json::Value) -> Self {
+        Self {
+            url: url.to_string(),
+            method: default_method(),
+            headers: BTreeMap::new(),
+            payload,
+            body: None,
+            min_interval_ms: 0,
+            attachments: Vec::new(),
+        }
+    }
+}
+
+/// A destination type turning notifications into HTTP requests
+pub trait Notifier: Send + Sync {
+    /// `route` is the first rule that sent the server here, if any
+    fn server_found(
+        &self,
+        server: &MinecraftServer,
+        route: Option<&RouteRule>,
+    ) -> Result<Vec<OutboundRequest>, RenderError>;
+
+    /// Servers collected over `window_seconds`; by default announced one by one
+    fn digest(
+        &self,
+        servers: &[MinecraftServer],
+        _window_seconds: u64,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        announce_each(self, servers)
+    }
+
+    /// Scan progress summary; by default not sent
+    fn progress(&self, _progress: &ScanProgress) -> Result<Vec<OutboundRequest>, RenderError> {
+        Ok(Vec::new())
+    }
+}
+
+/// Announces every server of a digest on its own
+pub fn announce_each<N: Notifier + ?Sized>(
+    notifier: &N,
+    servers: &[MinecraftServer],
+) -> Result<Vec<OutboundRequest>, RenderError> {
+    let mut requests = Vec::with_capacity(servers.len());
+    for server in servers {
+        requests.extend(notifier.server_found(server, None)?);
+    }
+    Ok(requests)
+}
+
+/// Platform-neutral content of a found-server announcement
+pub struct ServerCard {
+    pub title: &'static str,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl ServerCard {
+    pub fn new(server: &MinecraftServer) -> Self {
+        let mut fields = vec![
+            ("Address", format!("{}:{}", server.ip, server.port)),
+            (
+                "Players",
+                format!("{}/{}", server.players_online, server.players_max),
+            ),
+            (
+                "Version",
+                match &server.canonical_version {
+                    Some(release) => format!(
+                        "{} ({}, protocol {})",
+                        server.version, release, server.protocol
+                    ),
+                    None => format!("{} (protocol {})", server.version, server.protocol),
+                },
+            ),
+            ("Software", server.fingerprint.to_string()),
+            (
+                "Country",
+                server
+                    .country
+                    .clone()
+                    .unwrap_or_else(|| "Unknown".to_string()),
+            ),
+        ];
+        if let Some(mods) = &server.mods
+            && !mods.mods.is_empty()
+        {
+            let summary = mods.summary();
+            let summary = if summary.len() > 1000 {
+                format!("{}...", truncate_at_char_boundary(&summary, 1000))
+            } else {
+                summary
+            };
+            fields.push((
+                "Mods",
+                format!("{} ({}): {}", mods.mods.len(), mods.format, summary),
+            ));
+        }
+
+        Self {
+            title: if server.players_online > 0 {
+                "Active Server Found"
+            } else {
+                "Empty Server Found"
+            },
+            fields,
+        }
+    }
+}
+
+/// One line per server for digests on text-based platforms
+pub fn digest_line(server: &MinecraftServer) -> String {
+    let mut line = format!(
+        "{}:{} — {} — {}/{} players — {}",
+        server.ip,
+        server.port,
+        server.game_version(),
+        server.players_online,
+        server.players_max,
+        server.fingerprint.family
+    );
+    if let Some(country) = &server.country {
+        line.push_str(&format!(" — {}", country));
+    }
+    line
+}
+
+pub fn progress_fields(progress: &ScanProgress) -> Vec<(&'static str, String)> {
+    vec![
+        ("IPs scanned", progress.scanned_total.to_string()),
+        ("Open ports", progress.ports_open.to_string()),
+        ("MC servers", progress.servers_found.to_string()),
+        (
+            "Rate",
+            format!("{:.1} scans/min", progress.scans_per_minute),
+        ),
+        (
+            "Runtime",
+            format!("{:.1}m", progress.runtime.as_secs_f64() / 60.0),
+        ),
+    ]
+}
+
+/// Joins lines with newlines into as few chunks of at most `max_len` bytes as possible
+pub fn pack_lines(lines: &[String], max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    for line in lines {
+        let line = truncate_at_char_boundary(line, max_len);
+        if !chunk.is_empty() && chunk.len() + 1 + line.len() > max_len {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        if !chunk.is_empty() {
+            chunk.push('\n');
+        }
+        chunk.push_str(line);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+pub fn truncate_at_char_boundary(text: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Notifiers configured under `[notifiers.<name>]`, looked up by route destination
+#[derive(Clone)]
+pub struct Notifiers {
+    named: HashMap<String, Arc<dyn Notifier>>,
+    discord_embed: Arc<EmbedTemplate>,
+    privacy: Arc<Privacy>,
+}
+
+impl Default for Notifiers {
+    fn default() -> Self {
+        Self::new(
+            &BTreeMap::new(),
+            &EmbedConfig::default(),
+            Privacy::default(),
+        )
+        .expect("the default embed template always compiles")
+    }
+}
+
+impl Notifiers {
+    pub fn new(
+        configs: &BTreeMap<String, NotifierConfig>,
+        embed: &EmbedConfig,
+        privacy: Privacy,
+    ) -> Result<Self, Box<dyn Error>> {
+        let discord_embed =
+            Arc::new(EmbedTemplate::new(embed).map_err(|e| format!("discord.embed: {}", e))?);
+        let mut named: HashMap<String, Arc<dyn Notifier>> = HashMap::new();
+        for (name, config) in configs {
+            let context = |e: Box<dyn Error>| format!("notifiers.{}: {}", name, e);
+            let notifier: Arc<dyn Notifier> = match config.kind {
+                NotifierKind::Webhook => Arc::new(WebhookNotifier::new(config).map_err(context)?),
+                NotifierKind::Slack => Arc::new(SlackWebhook::new(config).map_err(context)?),
+                NotifierKind::Matrix => Arc::new(MatrixRoom::new(config).map_err(context)?),
+                NotifierKind::Telegram => Arc::new(TelegramChat::new(config).map_err(context)?),
+            };
+            named.insert(name.clone(), notifier);
+        }
+        Ok(Self {
+            named,
+            discord_embed,
+            privacy: Arc::new(privacy),
+        })
+    }
+
+    /// A named notifier, otherwise the destination is taken as a Discord webhook URL.
+    /// Wrapped in the destination's redaction profile, if any.
+    pub fn resolve(&self, destination: &str) -> Arc<dyn Notifier> {
+        let notifier: Arc<dyn Notifier> = match self.named.get(destination) {
+            Some(notifier) => notifier.clone(),
+            None => Arc::new(DiscordWebhook::new(destination, self.discord_embed.clone())),
+        };
+        match self.privacy.for_destination(destination) {
+            Some(profile) => Arc::new(RedactingNotifier::new(notifier, profile)),
+            None => notifier,
+        }
+    }
+}
+
+enum Notification {
+    Server(Box<QueuedServer>),
+    Progress(ScanProgress),
+    Reconfigure(Box<(Router, Notifiers, DigestConfig)>),
+}
+
+/// Handle for queueing found servers; one task routes and renders them, delivery happens on
+/// a task per destination so a rate limited destination never holds up the others
+#[derive(Clone)]
+pub struct NotificationDispatcher {
+    queue: mpsc::UnboundedSender<Notification>,
+    /// Found servers are written here before they enter the channel
+    store: Option<NotificationQueue>,
+}
+
+/// Waits up to `deadline` for the worker returned by `NotificationDispatcher::spawn` to
+/// deliver everything, then stops it; returns whether it finished in time. Messages it did
+/// not deliver stay in the on-disk queue.
+pub async fn drain(mut handle: JoinHandle<()>, deadline: Duration) -> bool {
+    if tokio::time::timeout(deadline, &mut handle).await.is_ok() {
+        return true;
+    }
+    handle.abort();
+    false
+}
+
+/// Rate limit state reported by a destination for one URL
+struct RateLimitBucket {
+    remaining: u32,
+    reset_at: Instant,
+}
+
+struct DeliveryWorker {
+    client: Client,
+    router: Router,
+    notifiers: Notifiers,
+    /// Delivery task per destination URL, started on its first message
+    destinations: HashMap<String, mpsc::UnboundedSender<Delivery>>,
+    workers: Vec<JoinHandle<()>>,
+    /// Without an on-disk queue, messages only live in memory
+    queue: Option<NotificationQueue>,
+    digest: DigestConfig,
+    /// Servers waiting for the next digest, per destination in first-seen order.
+    /// Mirrored to the on-disk queue until the digest is rendered.
+    digest_pending: Vec<(String, Vec<MinecraftServer>)>,
+    digest_started: Option<Instant>,
+    last_progress: Option<Instant>,
+    /// Servers announced recently; without it every sighting is announced
+    seen: Option<Arc<Mutex<SeenSet>>>,
+}
+
+struct Delivery {
+    message: QueuedMessage,
+    announcement: Option<Arc<Announcement>>,
+}
+
+/// Servers covered by a group of messages; they count as seen once any of them is delivered
+struct Announcement {
+    servers: Vec<MinecraftServer>,
+    seen: Arc<Mutex<SeenSet>>,
+    remaining: AtomicUsize,
+    delivered: AtomicBool,
+}
+
+impl Announcement {
+    fn finished(&self, delivered: bool) {
+        if delivered {
+            self.delivered.store(true, Ordering::Relaxed);
+        }
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let delivered = self.delivered.load(Ordering::Relaxed);
+            let mut seen = self.seen.lock().unwrap();
+            for server in &self.servers {
+                seen.finish(server, delivered);
+            }
+        }
+    }
+}
+
+/// Sends the messages of one destination URL in queue order
+struct DestinationWorker {
+    client: Client,
+    queue: Option<NotificationQueue>,
+    bucket: Option<RateLimitBucket>,
+    last_sent: Option<Instant>,
+    global_reset_at: Option<Instant>,
+}
+
+impl NotificationDispatcher {
+    /// Starts the delivery worker, which first resumes messages left in the on-disk queue,
+    /// then drains the channel and exits once every handle is dropped
+    pub fn spawn(
+        router: Router,
+        notifiers: Notifiers,
+        digest: DigestConfig,
+        queue: Option<NotificationQueue>,
+        seen: Option<SeenSet>,
+    ) -> (Self, JoinHandle<()>) {
+        let (sender, rx) = mpsc::unbounded_channel();
+        let store = queue.clone();
+        let worker = DeliveryWorker::new(router, notifiers, digest, queue, seen);
+        (
+            Self {
+                queue: sender,
+                store,
+            },
+            tokio::spawn(worker.run(rx)),
+        )
+    }
+
+    /// Delivers everything in the on-disk queue, then returns (used by `queue replay`)
+    pub async fn deliver_pending(queue: NotificationQueue) {
+        let router = Router::new(&Default::default(), &Default::default())
+            .expect("empty routing rules always compile");
+        let mut worker = DeliveryWorker::new(
+            router,
+            Notifiers::default(),
+            DigestConfig::default(),
+            Some(queue),
+            None,
+        );
+        worker.resume_pending();
+        worker.finish().await;
+    }
+
+    /// Queues a server for delivery; it is on disk before this returns, so a crash before
+    /// it was routed announces it on the next start
+    pub fn notify_server_found(&self, server: MinecraftServer) {
+        let found = QueuedServer::new(server);
+        if let Some(store) = &self.store
+            && let Err(e) = store.save_server(&found)
+        {
+            let server = found.server();
+            error!(
+                "Could not persist found server {}:{}: {}",
+                server.ip, server.port, e
+            );
+        }
+        METRICS.notification_queue_depth.inc();
+        if self
+            .queue
+            .send(Notification::Server(Box::new(found)))
+            .is_err()
+        {
+            error!("Notification delivery worker stopped, notification lost");
+        }
+    }
+
+    /// Replaces routes, notifiers and digest settings for messages queued after this one
+    pub fn reconfigure(&self, router: Router, notifiers: Notifiers, digest: DigestConfig) {
+        let _ = self.queue.send(Notification::Reconfigure(Box::new((
+            router, notifiers, digest,
+        ))));
+    }
+
+    /// Queues a scan progress summary; the worker throttles these to the configured interval
+    pub fn notify_progress(&self, progress: ScanProgress) {
+        let _ = self.queue.send(Notification::Progress(progress));
+    }
+}
+
+impl DeliveryWorker {
+    fn new(
+        router: Router,
+        notifiers: Notifiers,
+        digest: DigestConfig,
+        queue: Option<NotificationQueue>,
+        seen: Option<SeenSet>,
+    ) -> Self {
+        Self {
+            client: Client::new(),
+            router,
+            notifiers,
+            destinations: HashMap::new(),
+            workers: Vec::new(),
+            queue,
+            digest,
+            digest_pending: Vec::new(),
+            digest_started: None,
+            last_progress: None,
+            seen: seen.map(|seen| Arc::new(Mutex::new(seen))),
+        }
+    }
+
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Notification>) {
+        self.resume_pending();
+        self.resume_digest().await;
+
+        loop {
+            let flush_at = self
+                .digest_started
+                .map(|started| started + Duration::from_secs(self.digest.window_seconds));
+
+            tokio::select! {
+                notification = rx.recv() => match notification {
+                    Some(Notification::Server(found)) => {
+                        self.handle_found(*found).await;
+                        METRICS.notification_queue_depth.dec();
+                    }
+                    Some(Notification::Progress(progress)) => self.post_progress(progress),
+                    Some(Notification::Reconfigure(parts)) => {
+                        let (router, notifiers, digest) = *parts;
+                        // Pending servers were routed to destinations the new config may drop
+                        self.flush_digest();
+                        self.router = router;
+                        self.notifiers = notifiers;
+                        self.digest = digest;
+                    }
+                    None => break,
+                },
+                _ = sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                    self.flush_digest();
+                }
+            }
+        }
+
+        self.flush_digest();
+        self.finish().await;
+        if let Some(seen) = &self.seen {
+            seen.lock().unwrap().flush();
+        }
+    }
+
+    /// Waits until every destination worker delivered what it was given
+    async fn finish(&mut self) {
+        self.destinations.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.await;
+        }
+    }
+
+    /// Delivers messages a previous run queued but never confirmed
+    fn resume_pending(&mut self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let pending = match queue.pending() {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Could not read notification queue: {}", e);
+                return;
+            }
+        };
+        if !pending.is_empty() {
+            info!("Resuming {} queued notifications", pending.len());
+        }
+        METRICS.notification_queue_depth.add(pending.len() as i64);
+        for message in pending {
+            self.dispatch(message, None);
+        }
+    }
+
+    /// Routes the servers a previous run found but never routed, then sends the digests it
+    /// collected right away; their window has passed
+    async fn resume_digest(&mut self) {
+        let Some(queue) = self.queue.clone() else {
+            return;
+        };
+        let resumed = match queue.digest() {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Could not read pending digest: {}", e);
+                Vec::new()
+            }
+        };
+        let flush = !resumed.is_empty();
+        if flush {
+            let servers: usize = resumed.iter().map(|(_, servers)| servers.len()).sum();
+            info!("Resuming digest of {} servers", servers);
+            self.digest_pending = resumed;
+        }
+
+        let found = match queue.pending_servers() {
+            Ok(found) => found,
+            Err(e) => {
+                error!("Could not read queued servers: {}", e);
+                Vec::new()
+            }
+        };
+        if !found.is_empty() {
+            info!("Resuming {} found servers", found.len());
+            // Requests rendered from a server before the crash make it already handled
+            let rendered: Vec<String> = queue
+                .pending()
+                .into_iter()
+                .chain(queue.dead_letters())
+                .flatten()
+                .filter_map(|message| message.source)
+                .collect();
+            for found in found {
+                if rendered.contains(&found.id) {
+                    self.forget_found(&found);
+                } else {
+                    self.handle_found(found).await;
+                }
+            }
+        }
+
+        if flush {
+            self.flush_digest();
+        }
+    }
+
+    /// Routes and renders a found server; its on-disk entry is removed once the rendered
+    /// requests (or the pending digest) hold it, and only then are those delivered
+    async fn handle_found(&mut self, found: QueuedServer) {
+        let server = with_geo_info(found.server()).await;
+        let announcements = self.route_server(server, &found.id);
+        self.forget_found(&found);
+        self.deliver_announcements(announcements);
+    }
+
+    fn forget_found(&self, found: &QueuedServer) {
+        if let Some(queue) = &self.queue
+            && let Err(e) = queue.remove_server(found)
+        {
+            error!("Could not remove routed server from queue: {}", e);
+        }
+    }
+
+    /// Renders and persists the announcements a server needs now, with the servers each of
+    /// them covers; digest servers are only collected
+    fn route_server(
+        &mut self,
+        server: MinecraftServer,
+        source: &str,
+    ) -> Vec<(Vec<MinecraftServer>, Vec<QueuedMessage>)> {
+        // Each destination remembers the first route that matched it
+        let mut destinations: Vec<(String, RouteRule)> = Vec::new();
+        for route in self.router.route(&server) {
+            debug!(
+                "Server {}:{} matched route \"{}\"",
+                server.ip, server.port, route.name
+            );
+            for destination in &route.destinations {
+                if !destinations.iter().any(|(known, _)| known == destination) {
+                    destinations.push((destination.clone(), route.clone()));
+                }
+            }
+        }
+
+        if destinations.is_empty() {
+            debug!(
+                "No destination configured for version {} (players: {})",
+                server.version, server.players_online
+            );
+            return Vec::new();
+        }
+
+        // Servers are only recorded as seen once an announcement of them was delivered
+        if let Some(seen) = &self.seen {
+            match seen.lock().unwrap().reason_to_notify(&server) {
+                Some(reason) => {
+                    debug!("Announcing {}:{} ({})", server.ip, server.port, reason);
+                }
+                None => {
+                    debug!(
+                        "Skipping {}:{}, already announced within the cooldown or on its way",
+                        server.ip, server.port
+                    );
+                    return Vec::new();
+                }
+            }
+        }
+
+        if self.digest.enabled {
+            let destinations = destinations.into_iter().map(|(d, _)| d).collect();
+            return self.add_to_digest(server, destinations);
+        }
+
+        let context = format!(
+            "{}:{} ({})",
+            server.ip,
+            server.port,
+            if server.players_online > 0 {
+                "active"
+            } else {
+                "empty"
+            }
+        );
+        let mut messages = Vec::new();
+        for (destination, route) in &destinations {
+            let rendered = self
+                .notifiers
+                .resolve(destination)
+                .server_found(&server, Some(route));
+            messages.extend(self.persist_rendered(destination, rendered, &context, Some(source)));
+        }
+        vec![(vec![server], messages)]
+    }
+
+    /// Returns the digests that reached `max_servers` and were rendered early
+    fn add_to_digest(
+        &mut self,
+        server: MinecraftServer,
+        destinations: Vec<String>,
+    ) -> Vec<(Vec<MinecraftServer>, Vec<QueuedMessage>)> {
+        self.digest_started.get_or_insert_with(Instant::now);
+
+        let mut full = Vec::new();
+        for destination in destinations {
+            let index = match self
+                .digest_pending
+                .iter()
+                .position(|(pending, _)| *pending == destination)
+            {
+                Some(index) => index,
+                None => {
+                    self.digest_pending.push((destination, Vec::new()));
+                    self.digest_pending.len() - 1
+                }
+            };
+            // Not yet seen until the digest is delivered, so a rescan would add it again
+            let servers = &mut self.digest_pending[index].1;
+            match servers
+                .iter()
+                .position(|pending| pending.ip == server.ip && pending.port == server.port)
+            {
+                Some(existing) => servers[existing] = server.clone(),
+                None => servers.push(server.clone()),
+            }
+
+            if self.digest_pending[index].1.len() >= self.digest.max_servers.max(1) {
+                let (destination, servers) = self.digest_pending.remove(index);
+                let messages = self.queue_digest(&destination, &servers);
+                full.push((servers, messages));
+            }
+        }
+
+        if self.digest_pending.is_empty() {
+            self.digest_started = None;
+        }
+        self.persist_digest();
+        full
+    }
+
+    fn flush_digest(&mut self) {
+        self.digest_started = None;
+        let mut rendered = Vec::new();
+        for (destination, servers) in std::mem::take(&mut self.digest_pending) {
+            let messages = self.queue_digest(&destination, &servers);
+            rendered.push((servers, messages));
+        }
+        self.persist_digest();
+        self.deliver_announcements(rendered);
+    }
+
+    /// Hands announcements to the destination workers; servers count as seen once any
+    /// message announcing them was delivered
+    fn deliver_announcements(
+        &mut self,
+        announcements: Vec<(Vec<MinecraftServer>, Vec<QueuedMessage>)>,
+    ) {
+        for (servers, messages) in announcements {
+            if messages.is_empty() {
+                continue;
+            }
+            let announcement = self.seen.as_ref().map(|seen| {
+                let mut locked = seen.lock().unwrap();
+                for server in &servers {
+                    locked.begin(server);
+                }
+                Arc::new(Announcement {
+                    servers,
+                    seen: seen.clone(),
+                    remaining: AtomicUsize::new(messages.len()),
+                    delivered: AtomicBool::new(false),
+                })
+            });
+            for message in messages {
+                self.dispatch(message, announcement.clone());
+            }
+        }
+    }
+
+    /// Renders a digest into the on-disk queue; the caller delivers it
+    fn queue_digest(&self, destination: &str, servers: &[MinecraftServer]) -> Vec<QueuedMessage> {
+        if servers.is_empty() {
+            return Vec::new();
+        }
+        let context = format!("digest of {} servers", servers.len());
+        let rendered = self
+            .notifiers
+            .resolve(destination)
+            .digest(servers, self.digest.window_seconds);
+        self.persist_rendered(destination, rendered, &context, None)
+    }
+
+    /// Mirrors the pending digest servers to the on-disk queue
+    fn persist_digest(&self) {
+        if let Some(queue) = &self.queue
+            && let Err(e) = queue.save_digest(&self.digest_pending)
+        {
+            error!("Could not persist pending digest: {}", e);
+        }
+    }
+
+    fn post_progress(&mut self, progress: ScanProgress) {
+        let interval = Duration::from_secs(self.digest.progress_interval_seconds);
+        if interval.is_zero() || self.digest.progress_webhooks.is_empty() {
+            return;
+        }
+        if self
+            .last_progress
+            .is_some_and(|last| last.elapsed() < interval)
+        {
+            return;
+        }
+        self.last_progress = Some(Instant::now());
+
+        for destination in self.digest.progress_webhooks.clone() {
+            let rendered = self.notifiers.resolve(&destination).progress(&progress);
+            self.send_rendered(&destination, rendered, "scan progress");
+        }
+    }
+
+    fn send_rendered(
+        &mut self,
+        destination: &str,
+        rendered: Result<Vec<OutboundRequest>, RenderError>,
+        context: &str,
+    ) {
+        for message in self.persist_rendered(destination, rendered, context, None) {
+            self.dispatch(message, None);
+        }
+    }
+
+    /// Persists rendered requests to the on-disk queue before any of them is delivered
+    fn persist_rendered(
+        &self,
+        destination: &str,
+        rendered: Result<Vec<OutboundRequest>, RenderError>,
+        context: &str,
+        source: Option<&str>,
+    ) -> Vec<QueuedMessage> {
+        let requests = match rendered {
+            Ok(requests) => requests,
+            Err(e) => {
+                error!(
+                    "Could not render notification for {} to {}: {}",
+                    context, destination, e
+                );
+                return Vec::new();
+            }
+        };
+        requests
+            .into_iter()
+            .map(|request| {
+                let mut message = QueuedMessage::new(request, context);
+                if let Some(source) = source {
+                    message = message.with_source(source);
+                }
+                if let Some(queue) = &self.queue
+                    && let Err(e) = queue.save(&message)
+                {
+                    error!("Could not persist notification for {}: {}", context, e);
+                }
+                METRICS.notification_queue_depth.inc();
+                message
+            })
+            .collect()
+    }
+
+    /// Queues a persisted message on the worker of its destination URL
+    fn dispatch(&mut self, message: QueuedMessage, announcement: Option<Arc<Announcement>>) {
+        let url = message.request.url.clone();
+        let sender = self.destinations.entry(url).or_insert_with(|| {
+            let (sender, rx) = mpsc::unbounded_channel();
+            let worker = DestinationWorker {
+                client: self.client.clone(),
+                queue: self.queue.clone(),
+                bucket: None,
+                last_sent: None,
+                global_reset_at: None,
+            };
+            self.workers.push(tokio::spawn(worker.run(rx)));
+            sender
+        });
+        let _ = sender.send(Delivery {
+            message,
+            announcement,
+        });
+    }
+}
+
+impl DestinationWorker {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Delivery>) {
+        while let Some(delivery) = rx.recv().await {
+            let delivered = self.deliver(delivery.message).await;
+            METRICS.notification_queue_depth.dec();
+            if let Some(announcement) = delivery.announcement {
+                announcement.finished(delivered);
+            }
+        }
+    }
+
+    /// Sends a queued request, waiting out rate limits for as long as the destination asks.
+    /// Messages are removed from the queue on success and dead-lettered on permanent failure.
+    /// Returns whether the message was delivered.
+    async fn deliver(&mut self, mut message: QueuedMessage) -> bool {
+        let webhook_url = message.request.url.clone();
+        let method = match Method::from_bytes(message.request.method.as_bytes()) {
+            Ok(method) => method,
+            Err(_) => {
+                message.last_error = Some(format!("invalid method {}", message.request.method));
+                self.dead_letter(&message);
+                return false;
+            }
+        };
+        loop {
+            self.wait_for_rate_limit(message.request.min_interval_ms)
+                .await;
+
+            let mut builder = self.client.request(method.clone(), &webhook_url);
+            for (name, value) in &message.request.headers {
+                builder = builder.header(name, value);
+            }
+            builder = match &message.request.body {
+                Some(body) => builder.body(body.clone()),
+                None if !message.request.attachments.is_empty() => {
+                    builder.multipart(multipart_form(&message.request))
+                }
+                None => builder.json(&message.request.payload),
+            };
+
+            let error = match builder.send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    let headers = response.headers().clone();
+                    self.update_bucket(&headers);
+
+                    if status.is_success() {
+                        debug!("Successfully sent notification for {}", message.context);
+                        METRICS.notifications_delivered.inc();
+                        if let Some(queue) = &self.queue
+                            && let Err(e) = queue.remove(&message)
+                        {
+                            error!("Could not remove delivered notification from queue: {}", e);
+                        }
+                        return true;
+                    }
+
+                    if status == StatusCode::TOO_MANY_REQUESTS {
+                        let body: serde_json::Value = response.json().await.unwrap_or_default();
+                        let retry_after = retry_after_secs(&headers, &body)
+                            .and_then(wait_duration)
+                            .unwrap_or(DEFAULT_RETRY_AFTER);
+                        let global = headers.contains_key("x-ratelimit-global")
+                            || body.get("global").and_then(|v| v.as_bool()) == Some(true);
+                        let reset_at = Instant::now() + retry_after;
+
+                        warn!(
+                            "{}Rate limit hit for {}, retrying in {:.2}s",
+                            if global { "Global " } else { "" },
+                            message.context,
+                            retry_after.as_secs_f64()
+                        );
+                        if global {
+                            self.global_reset_at = Some(reset_at);
+                        } else {
+                            self.bucket = Some(RateLimitBucket {
+                                remaining: 0,
+                                reset_at,
+                            });
+                        }
+                        continue;
+                    }
+
+                    if status.is_client_error() {
+                        METRICS.notification_delivery_failures.inc();
+                        error!(
+                            "Destination rejected notification for {} with status {}",
+                            message.context, status
+                        );
+                        message.last_error = Some(format!("rejected with status {}", status));
+                        self.dead_letter(&message);
+                        return false;
+                    }
+
+                    format!("status {}", status)
+                }
+                Err(e) => transport_error(e),
+            };
+
+            message.attempts += 1;
+            METRICS.notification_delivery_failures.inc();
+            error!(
+                "Failed to send notification for {} (attempt {}/{}): {}",
+                message.context, message.attempts, MAX_FAILED_ATTEMPTS, error
+            );
+            message.last_error = Some(error);
+
+            if message.attempts >= MAX_FAILED_ATTEMPTS {
+                self.dead_letter(&message);
+                return false;
+            }
+            if let Some(queue) = &self.queue
+                && let Err(e) = queue.save(&message)
+            {
+                error!("Could not update queued notification: {}", e);
+            }
+            sleep(Duration::from_secs(
+                (1u64 << message.attempts).min(MAX_BACKOFF_SECS),
+            ))
+            .await;
+        }
+    }
+
+    fn dead_letter(&self, message: &QueuedMessage) {
+        METRICS.notifications_dead_lettered.inc();
+        match &self.queue {
+            Some(queue) => match queue.dead_letter(message) {
+                Ok(()) => error!(
+                    "Moved notification for {} to the dead-letter queue ({})",
+                    message.context,
+                    message.last_error.as_deref().unwrap_or("unknown error")
+                ),
+                Err(e) => error!(
+                    "Could not dead-letter notification for {}, it is lost: {}",
+                    message.context, e
+                ),
+            },
+            None => error!(
+                "Giving up on notification for {} ({})",
+                message.context,
+                message.last_error.as_deref().unwrap_or("unknown error")
+            ),
+        }
+    }
+
+    async fn wait_for_rate_limit(&mut self, min_interval_ms: u64) {
+        if let Some(reset_at) = self.global_reset_at.take() {
+            sleep_until(reset_at).await;
+        }
+        if let Some(last) = self.last_sent {
+            sleep_until(last + Duration::from_millis(min_interval_ms)).await;
+        }
+        if min_interval_ms > 0 {
+            self.last_sent = Some(Instant::now());
+        }
+        if let Some(bucket) = self.bucket.take_if(|bucket| bucket.remaining == 0) {
+            if bucket.reset_at > Instant::now() {
+                debug!(
+                    "Waiting {:.2}s for rate limit bucket to reset",
+                    (bucket.reset_at - Instant::now()).as_secs_f64()
+                );
+            }
+            sleep_until(bucket.reset_at).await;
+        }
+    }
+
+    /// Tracks Discord-style `X-RateLimit-Remaining` / `X-RateLimit-Reset-After`
+    fn update_bucket(&mut self, headers: &HeaderMap) {
+        let remaining = headers
+            .get("x-ratelimit-remaining")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u32>().ok());
+        let reset_after = header_f64(headers, "x-ratelimit-reset-after").and_then(wait_duration);
+
+        if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+            self.bucket = Some(RateLimitBucket {
+                remaining,
+                reset_at: Instant::now() + reset_after,
+            });
+        }
+    }
+}
+
+/// Discord-style upload: the JSON payload as `payload_json` plus `files[n]` parts
+fn multipart_form(request: &OutboundRequest) -> Form {
+    let mut form = Form::new().text("payload_json", request.payload.to_string());
+    for (i, attachment) in request.attachments.iter().enumerate() {
+        let data = match BASE64.decode(&attachment.data) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Dropping invalid attachment {}: {}", attachment.filename, e);
+                continue;
+            }
+        };
+        let part = Part::bytes(data).file_name(attachment.filename.clone());
+        let part = match part.mime_str(&attachment.content_type) {
+            Ok(part) => part,
+            Err(e) => {
+                warn!("Dropping attachment {}: {}", attachment.filename, e);
+                continue;
+            }
+        };
+        form = form.part(format!("files[{}]", i), part);
+    }
+    form
+}
+
+/// Seconds to wait after a 429, in whichever form the platform reports it
+fn retry_after_secs(headers: &HeaderMap, body: &serde_json::Value) -> Option<f64> {
+    // Discord and Slack send a header; Discord repeats it in the body
+    header_f64(headers, "retry-after")
+        .or_else(|| body.get("retry_after").and_then(|v| v.as_f64()))
+        // Matrix: {"errcode": "M_LIMIT_EXCEEDED", "retry_after_ms": 2000}
+        .or_else(|| {
+            body.get("retry_after_ms")
+                .and_then(|v| v.as_f64())
+                .map(|ms| ms / 1000.0)
+        })
+        // Telegram: {"ok": false, "parameters": {"retry_after": 3}}
+        .or_else(|| {
+            body.pointer("/parameters/retry_after")
+                .and_then(|v| v.as_f64())
+        })
+}
+
+/// Describes a failed request without its URL, which holds secrets such as the Telegram
+/// bot token; the message ends up in logs and in `last_error` on disk
+fn transport_error(e: reqwest::Error) -> String {
+    e.without_url().to_string()
+}
+
+/// Converts a wait sent by a destination, capped at `MAX_RATE_LIMIT_WAIT`; `None` for NaN
+fn wait_duration(secs: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(secs.clamp(0.0, MAX_RATE_LIMIT_WAIT.as_secs_f64())).ok()
+}
+
+fn header_f64(headers: &HeaderMap, name: &str) -> Option<f64> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<f64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use serde_json::json;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[tokio::test]
+    async fn transport_errors_leave_out_the_url() {
+        let error = Client::new()
+            .post("http://127.0.0.1:9/bot123456:SECRET/sendMessage")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("SECRET"));
+        assert!(!transport_error(error).contains("SECRET"));
+    }
+
+    /// Accepts requests and answers them with `200 OK`, or holds them open forever
+    async fn destination(answer: bool) -> (String, mpsc::UnboundedReceiver<()>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (received, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 4096];
+                let _ = stream.read(&mut request).await;
+                let _ = received.send(());
+                if answer {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await;
+                } else {
+                    std::future::pending::<()>().await;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn a_stalled_destination_does_not_hold_up_the_others() {
+        let (stalled, mut stalled_rx) = destination(false).await;
+        let (healthy, mut healthy_rx) = destination(true).await;
+        let router = Router::new(&Default::default(), &Default::default()).unwrap();
+        let mut worker = DeliveryWorker::new(
+            router,
+            Notifiers::default(),
+            DigestConfig::default(),
+            None,
+            None,
+        );
+
+        let message = |url: &str| QueuedMessage::new(OutboundRequest::json(url, json!({})), "test");
+        worker.dispatch(message(&stalled), None);
+        worker.dispatch(message(&stalled), None);
+        worker.dispatch(message(&healthy), None);
+
+        let wait = Duration::from_secs(5);
+        tokio::time::timeout(wait, stalled_rx.recv()).await.unwrap();
+        tokio::time::timeout(wait, healthy_rx.recv()).await.unwrap();
+        assert_eq!(worker.workers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn draining_stops_at_the_deadline_and_keeps_the_queue() {
+        let (stalled, _stalled_rx) = destination(false).await;
+        let root = std::env::temp_dir().join(format!("mcsf-drain-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let queue = NotificationQueue::open(&root).unwrap();
+        let router = Router::new(&Default::default(), &Default::default()).unwrap();
+        let mut worker = DeliveryWorker::new(
+            router,
+            Notifiers::default(),
+            DigestConfig::default(),
+            Some(queue.clone()),
+            None,
+        );
+        let message = QueuedMessage::new(OutboundRequest::json(&stalled, json!({})), "test");
+        queue.save(&message).unwrap();
+        worker.dispatch(message, None);
+
+        let handle = tokio::spawn(async move { worker.finish().await });
+        assert!(!drain(handle, Duration::from_millis(200)).await);
+        assert_eq!(queue.pending().unwrap().len(), 1);
+
+        assert!(drain(tokio::spawn(async {}), Duration::from_millis(200)).await);
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn reads_retry_after_in_every_platform_form() {
+        let none = HeaderMap::new();
+        assert_eq!(
+            retry_after_secs(&headers("retry-after", " 2.5 "), &json!({})),
+            Some(2.5)
+        );
+        assert_eq!(
+            retry_after_secs(&none, &json!({"retry_after": 0.75})),
+            Some(0.75)
+        );
+        assert_eq!(
+            retry_after_secs(&none, &json!({"retry_after_ms": 2000})),
+            Some(2.0)
+        );
+        assert_eq!(
+            retry_after_secs(&none, &json!({"parameters": {"retry_after": 3}})),
+            Some(3.0)
+        );
+        assert_eq!(
+            retry_after_secs(&headers("retry-after", "soon"), &json!({})),
+            None
+        );
+    }
+
+    #[test]
+    fn clamps_hostile_waits() {
+        assert_eq!(wait_duration(1.5), Some(Duration::from_millis(1500)));
+        assert_eq!(wait_duration(-5.0), Some(Duration::ZERO));
+        assert_eq!(wait_duration(f64::INFINITY), Some(MAX_RATE_LIMIT_WAIT));
+        assert_eq!(wait_duration(1e300), Some(MAX_RATE_LIMIT_WAIT));
+        assert_eq!(wait_duration(f64::NAN), None);
+
+        let inf = retry_after_secs(&headers("retry-after", "inf"), &json!({}));
+        assert_eq!(inf.and_then(wait_duration), Some(MAX_RATE_LIMIT_WAIT));
+        let nan = retry_after_secs(&headers("retry-after", "NaN"), &json!({}));
+        assert_eq!(nan.and_then(wait_duration), None);
+    }
+
+    #[test]
+    fn packs_lines_into_chunks() {
+        let lines: Vec<String> = ["aaaa", "bbbb", "cc", "dddddddddd"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(pack_lines(&lines, 9), vec!["aaaa\nbbbb", "cc", "ddddddddd"]);
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        assert_eq!(truncate_at_char_boundary("héllo", 2), "h");
+        assert_eq!(truncate_at_char_boundary("héllo", 3), "hé");
+        assert_eq!(truncate_at_char_boundary("hi", 10), "hi");
+    }
+}
@@ -0,0 +1,269 @@
+//! Layered config files, `${VAR}` interpolation and `MCSF_*` environment overrides
+
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::io;
+use toml::{Table, Value};
+
+/// `MCSF_DISCORD__WEBHOOK_OTHER_ACTIVE` overrides `discord.webhook_other_active`
+const ENV_PREFIX: &str = "MCSF_";
+const ENV_SEPARATOR: &str = "__";
+
+/// The merged config table and which `MCSF_*` variable set each overridden field
+pub struct Layers {
+    pub table: Table,
+    /// Dotted field path, e.g. `scanning.port`, to the variable that set it
+    overrides: BTreeMap<String, String>,
+}
+
+impl Layers {
+    /// Names the variable behind `path` (or any field below it), so an unknown key
+    /// coming from a mistyped `MCSF_SCANING__PORT` points at the variable, not a file
+    pub fn describe(&self, path: &str) -> String {
+        let prefix = format!("{}.", path);
+        match self
+            .overrides
+            .iter()
+            .find(|(field, _)| *field == path || field.starts_with(&prefix))
+        {
+            Some((_, name)) => format!("{} (set by {})", path, name),
+            None => path.to_string(),
+        }
+    }
+}
+
+/// Merges `local` (if present) over `base`, expanding `${VAR}` in both, then applies
+/// `MCSF_*` variables on top. A missing `base` counts as empty, so defaults and
+/// environment overrides alone are a complete config. `env` is the process
+/// environment, usually `std::env::vars()`.
+pub fn load_layers(
+    base: &str,
+    local: &str,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<Layers, Box<dyn Error>> {
+    let env: HashMap<String, String> = env.into_iter().collect();
+    let mut table = read_layer(base, &env)?.unwrap_or_else(|| {
+        info!("[CONFIG] {} not found, starting from defaults", base);
+        Table::new()
+    });
+    if let Some(overlay) = read_layer(local, &env)? {
+        info!("[CONFIG] Layering {} over {}", local, base);
+        merge(&mut table, overlay);
+    }
+
+    let mut vars: Vec<(&String, &String)> = env
+        .iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    vars.sort();
+    let mut overrides = BTreeMap::new();
+    for (name, raw) in vars {
+        let path = apply_env(&mut table, name, raw)?;
+        overrides.insert(path, name.clone());
+    }
+    if !overrides.is_empty() {
+        let names: Vec<&str> = overrides.values().map(String::as_str).collect();
+        info!("[CONFIG] Environment overrides: {}", names.join(", "));
+    }
+    Ok(Layers { table, overrides })
+}
+
+fn read_layer(path: &str, env: &HashMap<String, String>) -> Result<Option<Table>, Box<dyn Error>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path, e).into()),
+    };
+    let mut table: Table = toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    for (key, value) in table.iter_mut() {
+        interpolate(value, key, env).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(Some(table))
+}
+
+/// Tables merge key by key; any other value, arrays included, replaces the base value
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn interpolate(value: &mut Value, path: &str, env: &HashMap<String, String>) -> Result<(), String> {
+    match value {
+        Value::String(text) => {
+            *text = expand(text, env).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate(item, &format!("{}[{}]", path, i), env)?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                interpolate(item, &format!("{}.{}", path, key), env)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces `${VAR}` with the variable's value; `$${` stays a literal `${`
+fn expand(text: &str, env: &HashMap<String, String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let tail = &rest[dollar..];
+        if let Some(after) = tail.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some(after) = tail.strip_prefix("${") {
+            let end = after.find('}').ok_or("unterminated ${")?;
+            let name = &after[..end];
+            let value = env
+                .get(name)
+                .ok_or_else(|| format!("environment variable {} is not set", name))?;
+            expanded.push_str(value);
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &tail[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Sets the field named by `MCSF_SECTION__FIELD`, lowercased. Values are read as TOML
+/// (`true`, `5000`, `["a", "b"]`) and otherwise taken as a string; fields that are
+/// already strings always stay strings. Returns the dotted path of the field set.
+fn apply_env(table: &mut Table, name: &str, raw: &str) -> Result<String, String> {
+    let keys: Vec<String> = name[ENV_PREFIX.len()..]
+        .split(ENV_SEPARATOR)
+        .map(str::to_lowercase)
+        .collect();
+    if keys.iter().any(String::is_empty) {
+        return Err(format!("{}: empty field name", name));
+    }
+    let (field, sections) = keys.split_last().expect("split yields at least one key");
+
+    let mut current = table;
+    for section in sections {
+        let entry = current
+            .entry(section.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        current = match entry {
+            Value::Table(table) => table,
+            _ => return Err(format!("{}: {} is not a section", name, section)),
+        };
+    }
+
+    let value = match current.get(field) {
+        Some(Value::String(_)) => Value::String(raw.to_string()),
+        _ => toml::from_str::<Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or_else(|| Value::String(raw.to_string())),
+    };
+    current.insert(field.clone(), value);
+    Ok(keys.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(text: &str) -> Table {
+        toml::from_str(text).unwrap()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn missing_files_are_empty_layers() {
+        let layers = load_layers(
+            "/nonexistent/config.toml",
+            "/nonexistent/config.local.toml",
+            env(&[("PATH", "/bin")]),
+        );
+        assert!(layers.unwrap().table.is_empty());
+    }
+
+    #[test]
+    fn env_overrides_name_their_variable() {
+        let layers = load_layers(
+            "/nonexistent/config.toml",
+            "/nonexistent/config.local.toml",
+            env(&[("MCSF_SCANING__PORT", "25566"), ("SCANING", "ignored")]),
+        )
+        .unwrap();
+        assert_eq!(layers.table, table("[scaning]\nport = 25566"));
+        assert_eq!(
+            layers.describe("scaning"),
+            "scaning (set by MCSF_SCANING__PORT)"
+        );
+        assert_eq!(
+            layers.describe("scaning.port"),
+            "scaning.port (set by MCSF_SCANING__PORT)"
+        );
+        assert_eq!(layers.describe("scan"), "scan");
+    }
+
+    #[test]
+    fn merges_tables_and_replaces_everything_else() {
+        let mut base = table("a = 1\nlist = [1, 2]\n[section]\nkeep = true\nchange = \"old\"");
+        merge(
+            &mut base,
+            table("list = [3]\n[section]\nchange = \"new\"\nadded = 5"),
+        );
+        assert_eq!(
+            base,
+            table("a = 1\nlist = [3]\n[section]\nkeep = true\nchange = \"new\"\nadded = 5")
+        );
+    }
+
+    #[test]
+    fn expands_variables_and_escapes() {
+        let env = env(&[("TOKEN", "secret")]);
+        assert_eq!(
+            expand("Bot ${TOKEN}, $${literal} and $5", &env).unwrap(),
+            "Bot secret, ${literal} and $5"
+        );
+        assert!(expand("${UNSET}", &env).is_err());
+        assert!(expand("${TOKEN", &env).is_err());
+    }
+
+    #[test]
+    fn env_values_keep_string_fields_strings() {
+        let mut config = table("[discord]\nwebhook = \"a\"\n[scanning]\nport = 25565");
+        apply_env(&mut config, "MCSF_DISCORD__WEBHOOK", "123").unwrap();
+        assert_eq!(
+            apply_env(&mut config, "MCSF_SCANNING__PORT", "25566").unwrap(),
+            "scanning.port"
+        );
+        apply_env(&mut config, "MCSF_METRICS__ENABLED", "true").unwrap();
+        apply_env(&mut config, "MCSF_NOTIFIERS__TEAM__URL", "not toml").unwrap();
+        assert_eq!(
+            config,
+            table(concat!(
+                "[discord]\nwebhook = \"123\"\n[scanning]\nport = 25566\n",
+                "[metrics]\nenabled = true\n[notifiers.team]\nurl = \"not toml\""
+            ))
+        );
+
+        assert!(apply_env(&mut config, "MCSF_SCANNING__PORT__X", "1").is_err());
+        assert!(apply_env(&mut config, "MCSF_SCANNING____PORT", "1").is_err());
+    }
+}
@@ -0,0 +1,247 @@
+//! Source address and port allocation for outgoing probes
+
+use crate::metrics::METRICS;
+use crate::minecraft::PingError;
+use log::warn;
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Local addresses probes originate from, handed out round-robin
+#[derive(Clone)]
+pub struct SourceAddresses {
+    addresses: Arc<[Ipv4Addr]>,
+    next: Arc<AtomicUsize>,
+}
+
+impl SourceAddresses {
+    /// Checks every address is assigned to this host; an empty list lets the kernel choose
+    pub fn new(addresses: &[Ipv4Addr]) -> Result<Self, String> {
+        for address in addresses {
+            TcpListener::bind((*address, 0)).map_err(|e| {
+                format!(
+                    "networking.source_addresses: cannot bind {}: {}",
+                    address, e
+                )
+            })?;
+        }
+        Ok(Self {
+            addresses: addresses.into(),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Next address in turn, or `0.0.0.0` when none are configured
+    pub fn next(&self) -> Ipv4Addr {
+        if self.addresses.is_empty() {
+            return Ipv4Addr::UNSPECIFIED;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.addresses.len();
+        self.addresses[index]
+    }
+}
+
+struct State {
+    /// Least recently released first, so a port rests as long as possible before reuse
+    free: VecDeque<u16>,
+    /// Ports whose connection may still sit in TIME_WAIT, in release order
+    cooling: VecDeque<(u16, Instant)>,
+    capacity: usize,
+    exhausted_warned: bool,
+}
+
+/// One task's source ports; probes lease a port and hand it back when done
+#[derive(Clone)]
+pub struct SourcePorts {
+    task_id: usize,
+    addresses: SourceAddresses,
+    time_wait: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl SourcePorts {
+    /// Ports `first..first + count`, cut off at 65535; an empty range always uses kernel ports
+    pub fn new(
+        task_id: usize,
+        addresses: SourceAddresses,
+        first: u32,
+        count: u32,
+        time_wait: Duration,
+    ) -> Self {
+        let end = first.saturating_add(count).min(u16::MAX as u32 + 1);
+        let free: VecDeque<u16> = (first.min(end)..end).map(|port| port as u16).collect();
+        if free.len() < count as usize {
+            warn!(
+                "[TASK {}] Only {} of {} source ports fit below 65535 from port {}, the rest are kernel-assigned",
+                task_id + 1,
+                free.len(),
+                count,
+                first
+            );
+        }
+        Self {
+            task_id,
+            addresses,
+            time_wait,
+            state: Arc::new(Mutex::new(State {
+                capacity: free.len(),
+                free,
+                cooling: VecDeque::new(),
+                exhausted_warned: false,
+            })),
+        }
+    }
+
+    /// A free port, or a lease without one (kernel-assigned) when the range is exhausted
+    pub fn acquire(&self) -> PortLease {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        while let Some((port, released_at)) = state.cooling.front().copied() {
+            if now.duration_since(released_at) < self.time_wait {
+                break;
+            }
+            state.cooling.pop_front();
+            state.free.push_back(port);
+        }
+
+        let port = state.free.pop_front();
+        if port.is_none() {
+            METRICS.source_port_fallbacks.inc();
+            if !state.exhausted_warned && state.capacity > 0 {
+                state.exhausted_warned = true;
+                warn!(
+                    "[TASK {}] Source port range exhausted, falling back to kernel-assigned ports",
+                    self.task_id + 1
+                );
+            }
+        }
+        PortLease {
+            address: self.addresses.next(),
+            port,
+            pool: self.clone(),
+            established: true,
+        }
+    }
+}
+
+/// A leased source port, returned to the pool on drop
+pub struct PortLease {
+    address: Ipv4Addr,
+    port: Option<u16>,
+    pool: SourcePorts,
+    established: bool,
+}
+
+impl PortLease {
+    /// Address to bind; port 0 when the kernel picks the port
+    pub fn source(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.address, self.port.unwrap_or(0))
+    }
+
+    /// Notes how the probe ended: only connections that got established leave the
+    /// port in TIME_WAIT, so ports of timed-out or refused attempts are reused at once
+    pub fn finish<T>(mut self, result: &Result<T, PingError>) {
+        self.established = !matches!(
+            result,
+            Err(PingError::ConnectTimeout | PingError::ConnectionRefused)
+        );
+    }
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        let Some(port) = self.port else {
+            return;
+        };
+        let mut state = self.pool.state.lock().unwrap();
+        if self.established {
+            state.cooling.push_back((port, Instant::now()));
+        } else {
+            state.free.push_back(port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(first: u32, count: u32, time_wait: Duration) -> SourcePorts {
+        SourcePorts::new(
+            0,
+            SourceAddresses::new(&[]).unwrap(),
+            first,
+            count,
+            time_wait,
+        )
+    }
+
+    fn port(lease: &PortLease) -> u16 {
+        lease.source().port()
+    }
+
+    #[test]
+    fn addresses_rotate_in_order() {
+        let local = [Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)];
+        let addresses = SourceAddresses::new(&local).unwrap();
+        let shared = addresses.clone();
+        assert_eq!(addresses.next(), local[0]);
+        assert_eq!(shared.next(), local[1]);
+        assert_eq!(addresses.next(), local[0]);
+    }
+
+    #[test]
+    fn no_addresses_leave_the_choice_to_the_kernel() {
+        let addresses = SourceAddresses::new(&[]).unwrap();
+        assert_eq!(addresses.next(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(addresses.next(), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn leases_ports_in_order_and_falls_back_when_exhausted() {
+        let pool = ports(40000, 2, Duration::from_secs(60));
+        let first = pool.acquire();
+        let second = pool.acquire();
+        assert_eq!((port(&first), port(&second)), (40000, 40001));
+
+        let fallback = pool.acquire();
+        assert_eq!(port(&fallback), 0);
+        drop(fallback);
+        assert_eq!(port(&pool.acquire()), 0);
+    }
+
+    #[test]
+    fn established_ports_rest_before_reuse() {
+        let pool = ports(40000, 1, Duration::from_secs(60));
+        pool.acquire().finish(&Ok::<(), PingError>(()));
+        assert_eq!(port(&pool.acquire()), 0);
+
+        let pool = ports(40000, 1, Duration::ZERO);
+        pool.acquire().finish(&Ok::<(), PingError>(()));
+        assert_eq!(port(&pool.acquire()), 40000);
+    }
+
+    #[test]
+    fn refused_and_timed_out_ports_are_reused_at_once() {
+        let pool = ports(40000, 1, Duration::from_secs(60));
+        pool.acquire()
+            .finish(&Err::<(), _>(PingError::ConnectionRefused));
+        let lease = pool.acquire();
+        assert_eq!(port(&lease), 40000);
+        lease.finish(&Err::<(), _>(PingError::ConnectTimeout));
+        assert_eq!(port(&pool.acquire()), 40000);
+    }
+
+    #[test]
+    fn ranges_are_cut_off_at_65535() {
+        let pool = ports(65535, 10, Duration::from_secs(60));
+        let last = pool.acquire();
+        assert_eq!(port(&last), 65535);
+        assert_eq!(port(&pool.acquire()), 0);
+
+        let pool = ports(70000, 10, Duration::from_secs(60));
+        assert_eq!(port(&pool.acquire()), 0);
+    }
+}
@@ -0,0 +1,222 @@
+//! Redaction applied to servers before they reach shared notification channels or exports
+
+use crate::chat::ChatComponent;
+use crate::config::{IpMask, PrivacyConfig, RedactionProfile, RouteRule};
+use crate::discord::MinecraftServer;
+use crate::notifier::{Notifier, OutboundRequest};
+use crate::stats::ScanProgress;
+use crate::template::RenderError;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Profile name that opts a destination out of the default profile
+const NO_REDACTION: &str = "none";
+
+/// Shortest `hash_salt` accepted, e.g. the 32 characters of `openssl rand -hex 16` pass
+const MIN_SALT_LEN: usize = 16;
+
+/// `[privacy]` with profile names resolved
+#[derive(Default)]
+pub struct Privacy {
+    default: Option<Arc<RedactionProfile>>,
+    destinations: HashMap<String, Option<Arc<RedactionProfile>>>,
+    export: Option<Arc<RedactionProfile>>,
+}
+
+impl Privacy {
+    pub fn new(config: &PrivacyConfig) -> Result<Self, String> {
+        for (name, profile) in &config.profiles {
+            if profile.hash_player_ids
+                && !profile.drop_player_sample
+                && profile.hash_salt.chars().count() < MIN_SALT_LEN
+            {
+                return Err(format!(
+                    "privacy.profiles.{}.hash_salt: hash_player_ids needs a secret of at least {} characters; player UUIDs are public, so hashes with a guessable salt are reversible",
+                    name, MIN_SALT_LEN
+                ));
+            }
+        }
+
+        let profiles: HashMap<&str, Arc<RedactionProfile>> = config
+            .profiles
+            .iter()
+            .map(|(name, profile)| (name.as_str(), Arc::new(profile.clone())))
+            .collect();
+        let lookup = |field: &str, name: &Option<String>| match name.as_deref() {
+            None | Some(NO_REDACTION) => Ok(None),
+            Some(name) => profiles
+                .get(name)
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| format!("privacy.{}: unknown profile \"{}\"", field, name)),
+        };
+
+        let mut destinations = HashMap::new();
+        for (destination, name) in &config.destinations {
+            let profile = lookup(
+                &format!("destinations.\"{}\"", destination),
+                &Some(name.clone()),
+            )?;
+            destinations.insert(destination.clone(), profile);
+        }
+
+        Ok(Self {
+            default: lookup("default", &config.default)?,
+            destinations,
+            export: lookup("export", &config.export)?,
+        })
+    }
+
+    pub fn for_destination(&self, destination: &str) -> Option<Arc<RedactionProfile>> {
+        match self.destinations.get(destination) {
+            Some(profile) => profile.clone(),
+            None => self.default.clone(),
+        }
+    }
+
+    pub fn for_export(&self) -> Option<Arc<RedactionProfile>> {
+        self.export.clone()
+    }
+}
+
+impl RedactionProfile {
+    /// Copy of `server` with the profile's fields masked; routing and dedup keep the original
+    pub fn apply(&self, server: &MinecraftServer) -> MinecraftServer {
+        let mut server = server.clone();
+        server.ip = mask_ip(&server.ip, self.mask_ip);
+        if self.drop_player_sample {
+            server.player_sample.clear();
+        } else if self.hash_player_ids {
+            for player in &mut server.player_sample {
+                player.id = hash_id(&self.hash_salt, &player.id);
+            }
+        }
+        if self.strip_motd {
+            server.description.clear();
+            server.motd = ChatComponent::default();
+        }
+        server
+    }
+}
+
+fn mask_ip(ip: &str, mask: IpMask) -> String {
+    match (mask, ip.parse::<IpAddr>()) {
+        (IpMask::None, _) => ip.to_string(),
+        (IpMask::LastOctet, Ok(IpAddr::V4(v4))) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}.{}.{}.x", a, b, c)
+        }
+        (IpMask::LastOctet, Ok(IpAddr::V6(v6))) => {
+            let segments = v6.segments();
+            format!("{:x}:{:x}:{:x}::x", segments[0], segments[1], segments[2])
+        }
+        _ => "hidden".to_string(),
+    }
+}
+
+/// First 16 bytes of HMAC-SHA256 of the id keyed by the salt, hex encoded
+fn hash_id(salt: &str, id: &str) -> String {
+    if id.is_empty() {
+        return String::new();
+    }
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(id.to_ascii_lowercase().as_bytes());
+    mac.finalize().into_bytes()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Redacts servers before handing them to the wrapped notifier
+pub struct RedactingNotifier {
+    inner: Arc<dyn Notifier>,
+    profile: Arc<RedactionProfile>,
+}
+
+impl RedactingNotifier {
+    pub fn new(inner: Arc<dyn Notifier>, profile: Arc<RedactionProfile>) -> Self {
+        Self { inner, profile }
+    }
+}
+
+impl Notifier for RedactingNotifier {
+    fn server_found(
+        &self,
+        server: &MinecraftServer,
+        route: Option<&RouteRule>,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        self.inner.server_found(&self.profile.apply(server), route)
+    }
+
+    fn digest(
+        &self,
+        servers: &[MinecraftServer],
+        window_seconds: u64,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let servers: Vec<MinecraftServer> = servers
+            .iter()
+            .map(|server| self.profile.apply(server))
+            .collect();
+        self.inner.digest(&servers, window_seconds)
+    }
+
+    fn progress(&self, progress: &ScanProgress) -> Result<Vec<OutboundRequest>, RenderError> {
+        self.inner.progress(progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with(profile: RedactionProfile) -> PrivacyConfig {
+        let mut config = PrivacyConfig::default();
+        config.profiles.insert("public".to_string(), profile);
+        config
+    }
+
+    #[test]
+    fn hashing_requires_a_long_salt() {
+        let hashing = |salt: &str| RedactionProfile {
+            hash_player_ids: true,
+            hash_salt: salt.to_string(),
+            ..Default::default()
+        };
+        assert!(Privacy::new(&config_with(hashing(""))).is_err());
+        assert!(Privacy::new(&config_with(hashing("change-me"))).is_err());
+        assert!(Privacy::new(&config_with(hashing("0123456789abcdef"))).is_ok());
+
+        let dropped = RedactionProfile {
+            drop_player_sample: true,
+            ..hashing("")
+        };
+        assert!(Privacy::new(&config_with(dropped)).is_ok());
+    }
+
+    #[test]
+    fn hashes_depend_on_the_salt_but_not_the_case() {
+        let id = "069A79F4-44E9-4726-A5BE-FCA90E38AAF5";
+        let hash = hash_id("0123456789abcdef", id);
+        assert_eq!(hash.len(), 32);
+        assert_eq!(hash, hash_id("0123456789abcdef", &id.to_lowercase()));
+        assert_ne!(hash, hash_id("fedcba9876543210", id));
+        assert_eq!(hash_id("0123456789abcdef", ""), "");
+        // RFC 4231 test case 2, truncated to 16 bytes
+        assert_eq!(
+            hash_id("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c7"
+        );
+    }
+
+    #[test]
+    fn masks_ips() {
+        assert_eq!(mask_ip("203.0.113.7", IpMask::LastOctet), "203.0.113.x");
+        assert_eq!(mask_ip("2001:db8:1::5", IpMask::LastOctet), "2001:db8:1::x");
+        assert_eq!(mask_ip("203.0.113.7", IpMask::Full), "hidden");
+        assert_eq!(mask_ip("203.0.113.7", IpMask::None), "203.0.113.7");
+    }
+}
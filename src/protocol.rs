@@ -0,0 +1,251 @@
+//! Protocol number to Minecraft release mapping
+//!
+//! Only releases are listed. Snapshots since 1.16.4-pre1 are recognised by their snapshot
+//! bit. Older snapshots used plain numbers between those of the releases around them
+//! (1.16 snapshots lie between 578 and 735); they are not listed and label as unknown.
+
+use serde::{Deserialize, Deserializer};
+use std::cmp::Ordering;
+
+/// Protocol numbers of snapshots since 1.16.4-pre1 have this bit set
+const SNAPSHOT_BIT: i32 = 0x4000_0000;
+
+pub struct ProtocolRelease {
+    pub protocol: i32,
+    pub first: &'static str,
+    pub last: &'static str,
+}
+
+impl ProtocolRelease {
+    pub fn name(&self) -> String {
+        if self.first == self.last {
+            self.first.to_string()
+        } else {
+            format!("{}-{}", self.first, self.last)
+        }
+    }
+
+    fn contains(&self, version: &[u32]) -> bool {
+        let (Some(first), Some(last)) = (release_parts(self.first), release_parts(self.last))
+        else {
+            return false;
+        };
+        compare_versions(&first, version) != Ordering::Greater
+            && compare_versions(version, &last) != Ordering::Greater
+    }
+}
+
+const fn release(protocol: i32, first: &'static str, last: &'static str) -> ProtocolRelease {
+    ProtocolRelease {
+        protocol,
+        first,
+        last,
+    }
+}
+
+/// Release versions sharing a protocol number are listed as one entry
+pub const RELEASES: &[ProtocolRelease] = &[
+    release(4, "1.7.2", "1.7.5"),
+    release(5, "1.7.6", "1.7.10"),
+    release(47, "1.8", "1.8.9"),
+    release(107, "1.9", "1.9"),
+    release(108, "1.9.1", "1.9.1"),
+    release(109, "1.9.2", "1.9.2"),
+    release(110, "1.9.3", "1.9.4"),
+    release(210, "1.10", "1.10.2"),
+    release(315, "1.11", "1.11"),
+    release(316, "1.11.1", "1.11.2"),
+    release(335, "1.12", "1.12"),
+    release(338, "1.12.1", "1.12.1"),
+    release(340, "1.12.2", "1.12.2"),
+    release(393, "1.13", "1.13"),
+    release(401, "1.13.1", "1.13.1"),
+    release(404, "1.13.2", "1.13.2"),
+    release(477, "1.14", "1.14"),
+    release(480, "1.14.1", "1.14.1"),
+    release(485, "1.14.2", "1.14.2"),
+    release(490, "1.14.3", "1.14.3"),
+    release(498, "1.14.4", "1.14.4"),
+    release(573, "1.15", "1.15"),
+    release(575, "1.15.1", "1.15.1"),
+    release(578, "1.15.2", "1.15.2"),
+    release(735, "1.16", "1.16"),
+    release(736, "1.16.1", "1.16.1"),
+    release(751, "1.16.2", "1.16.2"),
+    release(753, "1.16.3", "1.16.3"),
+    release(754, "1.16.4", "1.16.5"),
+    release(755, "1.17", "1.17"),
+    release(756, "1.17.1", "1.17.1"),
+    release(757, "1.18", "1.18.1"),
+    release(758, "1.18.2", "1.18.2"),
+    release(759, "1.19", "1.19"),
+    release(760, "1.19.1", "1.19.2"),
+    release(761, "1.19.3", "1.19.3"),
+    release(762, "1.19.4", "1.19.4"),
+    release(763, "1.20", "1.20.1"),
+    release(764, "1.20.2", "1.20.2"),
+    release(765, "1.20.3", "1.20.4"),
+    release(766, "1.20.5", "1.20.6"),
+    release(767, "1.21", "1.21.1"),
+    release(768, "1.21.2", "1.21.3"),
+    release(769, "1.21.4", "1.21.4"),
+    release(770, "1.21.5", "1.21.5"),
+    release(771, "1.21.6", "1.21.6"),
+    release(772, "1.21.7", "1.21.8"),
+    release(773, "1.21.9", "1.21.10"),
+    release(774, "1.21.11", "1.21.11"),
+];
+
+pub fn release_for_protocol(protocol: i32) -> Option<&'static ProtocolRelease> {
+    RELEASES.iter().find(|r| r.protocol == protocol)
+}
+
+pub fn is_snapshot_protocol(protocol: i32) -> bool {
+    protocol & SNAPSHOT_BIT != 0 && protocol > 0
+}
+
+/// Canonical label for a reported protocol number, e.g. `1.20.3-1.20.4` or `snapshot #212`;
+/// `None` for numbers not in `RELEASES`, pre-1.16.4 snapshots included
+pub fn canonical_version(protocol: i32) -> Option<String> {
+    if is_snapshot_protocol(protocol) {
+        return Some(format!("snapshot #{}", protocol & !SNAPSHOT_BIT));
+    }
+    release_for_protocol(protocol).map(ProtocolRelease::name)
+}
+
+/// Resolves a release name such as `1.21.1` to its protocol number. Snapshots
+/// (`24w14a`) and pre-releases (`1.21-pre1`, `1.21-rc1`) have protocol numbers of their
+/// own and are not resolved.
+pub fn protocol_for_release(version: &str) -> Option<i32> {
+    let version = release_parts(version.trim())?;
+    RELEASES
+        .iter()
+        .find(|r| r.contains(&version))
+        .map(|r| r.protocol)
+}
+
+/// Numeric parts of a release name, `None` unless every part is a plain number
+fn release_parts(version: &str) -> Option<Vec<u32>> {
+    version
+        .split('.')
+        .map(|part| match part.bytes().all(|b| b.is_ascii_digit()) {
+            true => part.parse().ok(),
+            false => None,
+        })
+        .collect()
+}
+
+/// Compares release versions numerically (`1.9.4` < `1.10`, `1.8` = `1.8.0`)
+fn compare_versions(a: &[u32], b: &[u32]) -> Ordering {
+    (0..a.len().max(b.len()))
+        .map(|i| {
+            a.get(i)
+                .copied()
+                .unwrap_or(0)
+                .cmp(&b.get(i).copied().unwrap_or(0))
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Accepts either a raw protocol number or a release name for `protocol_version`
+pub fn deserialize_protocol_version<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ProtocolOrRelease {
+        Protocol(i32),
+        Release(String),
+    }
+
+    match ProtocolOrRelease::deserialize(deserializer)? {
+        ProtocolOrRelease::Protocol(protocol) => Ok(protocol),
+        ProtocolOrRelease::Release(name) => {
+            if let Ok(protocol) = name.trim().parse::<i32>() {
+                return Ok(protocol);
+            }
+            if release_parts(name.trim()).is_none() {
+                return Err(serde::de::Error::custom(format!(
+                    "\"{}\" is not a release name; snapshots and pre-releases need their protocol number",
+                    name
+                )));
+            }
+            protocol_for_release(&name).ok_or_else(|| {
+                serde::de::Error::custom(format!("unknown Minecraft release \"{}\"", name))
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Wrapper {
+        #[serde(deserialize_with = "deserialize_protocol_version")]
+        protocol: i32,
+    }
+
+    fn parse(value: &str) -> Result<i32, toml::de::Error> {
+        toml::from_str::<Wrapper>(&format!("protocol = {}", value)).map(|w| w.protocol)
+    }
+
+    #[test]
+    fn resolves_releases_within_a_range() {
+        assert_eq!(protocol_for_release("1.20.3"), Some(765));
+        assert_eq!(protocol_for_release("1.20.4"), Some(765));
+        assert_eq!(protocol_for_release(" 1.21.1 "), Some(767));
+        assert_eq!(protocol_for_release("1.8"), Some(47));
+        assert_eq!(protocol_for_release("1.8.0"), Some(47));
+    }
+
+    #[test]
+    fn compares_parts_numerically() {
+        assert_eq!(protocol_for_release("1.9.4"), Some(110));
+        assert_eq!(protocol_for_release("1.10"), Some(210));
+        assert_eq!(protocol_for_release("1.21.10"), Some(773));
+    }
+
+    #[test]
+    fn rejects_names_that_are_not_plain_releases() {
+        for name in [
+            "1.20.3foo",
+            "24w14a",
+            "1.21-pre1",
+            "1.21-rc1",
+            "1..2",
+            "",
+            "1.99",
+        ] {
+            assert_eq!(protocol_for_release(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn deserializes_numbers_and_names() {
+        assert_eq!(parse("765").unwrap(), 765);
+        assert_eq!(parse("\"767\"").unwrap(), 767);
+        assert_eq!(parse("\"1.21.1\"").unwrap(), 767);
+        let snapshot = parse("\"24w14a\"").unwrap_err().to_string();
+        assert!(snapshot.contains("protocol number"), "{}", snapshot);
+        let unknown = parse("\"1.99\"").unwrap_err().to_string();
+        assert!(unknown.contains("unknown Minecraft release"), "{}", unknown);
+    }
+
+    #[test]
+    fn labels_protocols() {
+        assert_eq!(canonical_version(765).as_deref(), Some("1.20.3-1.20.4"));
+        assert_eq!(canonical_version(767).as_deref(), Some("1.21-1.21.1"));
+        assert_eq!(
+            canonical_version(SNAPSHOT_BIT | 212).as_deref(),
+            Some("snapshot #212")
+        );
+        assert_eq!(canonical_version(-1), None);
+        assert_eq!(canonical_version(12345), None);
+        // A 1.16 snapshot, from before snapshots had their own bit
+        assert_eq!(canonical_version(700), None);
+    }
+}
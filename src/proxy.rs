@@ -0,0 +1,522 @@
+//! SOCKS5 and HTTP CONNECT tunnels for port checks and status pings
+
+use crate::config::{ProxyConfig, ProxyKind, ProxyServerConfig};
+use crate::minecraft::{PingError, connect};
+use crate::network::{contains, parse_cidr};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Proxy name that sends a group's pings without a proxy
+const DIRECT: &str = "direct";
+
+/// Longest HTTP CONNECT response head accepted
+const MAX_HTTP_HEAD: usize = 8192;
+
+pub struct Proxy {
+    pub name: String,
+    kind: ProxyKind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    fn new(name: &str, config: &ProxyServerConfig) -> Result<Self, String> {
+        let (host, port) = config
+            .address
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| format!("proxy.servers.{}.address: expected host:port", name))?;
+        let credentials = match (&config.username, &config.password) {
+            (Some(username), password) => {
+                Some((username.clone(), password.clone().unwrap_or_default()))
+            }
+            (None, Some(_)) => {
+                return Err(format!(
+                    "proxy.servers.{}.password: set without a username",
+                    name
+                ));
+            }
+            (None, None) => None,
+        };
+        if config.kind == ProxyKind::Socks5
+            && let Some((username, password)) = &credentials
+            && (username.len() > 255 || password.len() > 255)
+        {
+            return Err(format!(
+                "proxy.servers.{}: SOCKS5 credentials are limited to 255 bytes each",
+                name
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            kind: config.kind,
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+
+    /// Tunnel to the target, connecting to the proxy from `source`
+    pub async fn connect(
+        &self,
+        server_ip: &str,
+        server_port: u16,
+        source: SocketAddrV4,
+    ) -> Result<TcpStream, PingError> {
+        let mut stream = connect(&self.host, self.port, source)
+            .await
+            .map_err(|e| self.error(e))?;
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5(&mut stream, server_ip, server_port).await?,
+            ProxyKind::Http => {
+                self.http_connect(&mut stream, server_ip, server_port)
+                    .await?
+            }
+        }
+        Ok(stream)
+    }
+
+    /// RFC 1928 CONNECT, with RFC 1929 username/password authentication
+    async fn socks5(
+        &self,
+        stream: &mut TcpStream,
+        server_ip: &str,
+        server_port: u16,
+    ) -> Result<(), PingError> {
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[0x05, 0x02, 0x00, 0x02],
+            None => &[0x05, 0x01, 0x00],
+        };
+        stream
+            .write_all(greeting)
+            .await
+            .map_err(|e| self.error(e))?;
+        let mut choice = [0u8; 2];
+        stream
+            .read_exact(&mut choice)
+            .await
+            .map_err(|e| self.error(e))?;
+        if choice[0] != 0x05 {
+            return Err(self.error("not a SOCKS5 proxy"));
+        }
+        match (choice[1], &self.credentials) {
+            (0x00, _) => {}
+            (0x02, Some((username, password))) => {
+                let mut auth = vec![0x01, username.len() as u8];
+                auth.extend(username.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend(password.as_bytes());
+                stream.write_all(&auth).await.map_err(|e| self.error(e))?;
+                let mut status = [0u8; 2];
+                stream
+                    .read_exact(&mut status)
+                    .await
+                    .map_err(|e| self.error(e))?;
+                if status[1] != 0x00 {
+                    return Err(self.error("authentication failed"));
+                }
+            }
+            _ => return Err(self.error("no acceptable authentication method")),
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        match server_ip.parse::<Ipv4Addr>() {
+            Ok(ip) => {
+                request.push(0x01);
+                request.extend(ip.octets());
+            }
+            Err(_) if server_ip.len() <= 255 => {
+                request.push(0x03);
+                request.push(server_ip.len() as u8);
+                request.extend(server_ip.as_bytes());
+            }
+            Err(_) => return Err(self.error("host name too long")),
+        }
+        request.extend(server_port.to_be_bytes());
+        stream
+            .write_all(&request)
+            .await
+            .map_err(|e| self.error(e))?;
+
+        let mut reply = [0u8; 4];
+        stream
+            .read_exact(&mut reply)
+            .await
+            .map_err(|e| self.error(e))?;
+        match reply[1] {
+            0x00 => {}
+            0x03 => return Err(PingError::NetworkError("network unreachable".to_string())),
+            0x04 => return Err(PingError::NetworkError("host unreachable".to_string())),
+            0x05 => return Err(PingError::ConnectionRefused),
+            0x06 => return Err(PingError::ConnectTimeout),
+            code => return Err(self.error(format!("SOCKS5 reply {:#04x}", code))),
+        }
+
+        // Bound address and port, unused
+        let address_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await.map_err(|e| self.error(e))? as usize,
+            atyp => return Err(self.error(format!("SOCKS5 address type {:#04x}", atyp))),
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream
+            .read_exact(&mut bound)
+            .await
+            .map_err(|e| self.error(e))?;
+        Ok(())
+    }
+
+    async fn http_connect(
+        &self,
+        stream: &mut TcpStream,
+        server_ip: &str,
+        server_port: u16,
+    ) -> Result<(), PingError> {
+        let authority = format!("{}:{}", server_ip, server_port);
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((username, password)) = &self.credentials {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                BASE64.encode(format!("{}:{}", username, password))
+            ));
+        }
+        request.push_str("\r\n");
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| self.error(e))?;
+
+        // A byte at a time so no tunnelled data is consumed along with the head
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_HTTP_HEAD {
+                return Err(self.error("CONNECT response head too long"));
+            }
+            head.push(stream.read_u8().await.map_err(|e| self.error(e))?);
+        }
+
+        let status = std::str::from_utf8(&head)
+            .ok()
+            .and_then(|head| head.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| self.error("malformed CONNECT response"))?;
+        match status {
+            200..=299 => Ok(()),
+            407 => Err(self.error("authentication failed")),
+            504 => Err(PingError::ConnectTimeout),
+            _ => Err(self.error(format!("CONNECT answered {}", status))),
+        }
+    }
+
+    fn error(&self, e: impl fmt::Display) -> PingError {
+        PingError::Proxy(format!("{}: {}", self.name, e))
+    }
+}
+
+struct Group {
+    subnets: Vec<(Ipv4Addr, u8)>,
+    proxy: Option<Arc<Proxy>>,
+}
+
+/// `[proxy]` with server names resolved
+pub struct Proxies {
+    default: Option<Arc<Proxy>>,
+    groups: Vec<Group>,
+}
+
+impl Proxies {
+    pub fn new(config: &ProxyConfig) -> Result<Self, String> {
+        let servers = config
+            .servers
+            .iter()
+            .map(|(name, server)| Ok((name.as_str(), Arc::new(Proxy::new(name, server)?))))
+            .collect::<Result<HashMap<_, _>, String>>()?;
+        let lookup = |field: &str, name: &str| match name {
+            DIRECT => Ok(None),
+            name => servers
+                .get(name)
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| format!("proxy.{}: unknown proxy \"{}\"", field, name)),
+        };
+
+        let mut groups = Vec::new();
+        for (i, group) in config.groups.iter().enumerate() {
+            let subnets = group
+                .subnets
+                .iter()
+                .map(|cidr| {
+                    parse_cidr(cidr).ok_or_else(|| {
+                        format!("proxy.groups[{}].subnets: invalid CIDR \"{}\"", i, cidr)
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            groups.push(Group {
+                subnets,
+                proxy: lookup(&format!("groups[{}].proxy", i), &group.proxy)?,
+            });
+        }
+
+        Ok(Self {
+            default: match config.default.as_deref() {
+                Some(name) => lookup("default", name)?,
+                None => None,
+            },
+            groups,
+        })
+    }
+
+    /// Proxy of the first group containing `ip`, else the default
+    pub fn for_target(&self, ip: Ipv4Addr) -> Option<Arc<Proxy>> {
+        self.groups
+            .iter()
+            .find(|group| {
+                group
+                    .subnets
+                    .iter()
+                    .any(|&(network, prefix)| contains(network, prefix, ip))
+            })
+            .map_or_else(|| self.default.clone(), |group| group.proxy.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use tokio::net::TcpListener;
+
+    const TARGET: &str = "10.0.0.1";
+
+    /// Runs `script` as the proxy side of the first connection; returns its address
+    async fn serve<F, Fut>(script: F) -> String
+    where
+        F: FnOnce(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            script(stream).await;
+        });
+        address
+    }
+
+    fn proxy(kind: ProxyKind, address: String, credentials: Option<(&str, &str)>) -> Proxy {
+        let config = ProxyServerConfig {
+            kind,
+            address,
+            username: credentials.map(|(username, _)| username.to_string()),
+            password: credentials.map(|(_, password)| password.to_string()),
+        };
+        Proxy::new("test", &config).unwrap()
+    }
+
+    async fn tunnel(proxy: &Proxy) -> Result<TcpStream, PingError> {
+        proxy
+            .connect(TARGET, 25565, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+            .await
+    }
+
+    fn proxy_error(result: Result<TcpStream, PingError>) -> String {
+        match result {
+            Err(PingError::Proxy(message)) => message,
+            other => panic!("expected a proxy error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    async fn read_http_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[tokio::test]
+    async fn socks5_without_authentication() {
+        let address = serve(|mut stream| async move {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x00]);
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [0x05, 0x01, 0x00, 0x01, 10, 0, 0, 1, 0x63, 0xdd]);
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            stream.write_all(b"tunnel").await.unwrap();
+        })
+        .await;
+
+        let mut stream = tunnel(&proxy(ProxyKind::Socks5, address, None))
+            .await
+            .unwrap();
+        let mut tunnelled = [0u8; 6];
+        stream.read_exact(&mut tunnelled).await.unwrap();
+        assert_eq!(&tunnelled, b"tunnel");
+    }
+
+    #[tokio::test]
+    async fn socks5_with_username_and_password() {
+        let address = serve(|mut stream| async move {
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x02, 0x00, 0x02]);
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+
+            let mut auth = [0u8; 13];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x06secret");
+            stream.write_all(&[0x01, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            // Bound address given as a domain name
+            stream
+                .write_all(&[
+                    0x05, 0x00, 0x00, 0x03, 5, b'p', b'r', b'o', b'x', b'y', 0, 80,
+                ])
+                .await
+                .unwrap();
+        })
+        .await;
+
+        let proxy = proxy(ProxyKind::Socks5, address, Some(("user", "secret")));
+        assert!(tunnel(&proxy).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn socks5_authentication_failure() {
+        let address = serve(|mut stream| async move {
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+            let mut auth = [0u8; 13];
+            stream.read_exact(&mut auth).await.unwrap();
+            stream.write_all(&[0x01, 0x01]).await.unwrap();
+        })
+        .await;
+
+        let proxy = proxy(ProxyKind::Socks5, address, Some(("user", "wrong!")));
+        assert_eq!(
+            proxy_error(tunnel(&proxy).await),
+            "test: authentication failed"
+        );
+    }
+
+    #[tokio::test]
+    async fn http_connect_established() {
+        let address = serve(|mut stream| async move {
+            let head = read_http_head(&mut stream).await;
+            assert!(head.starts_with("CONNECT 10.0.0.1:25565 HTTP/1.1\r\n"));
+            // base64("user:secret")
+            assert!(head.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\ntunnel")
+                .await
+                .unwrap();
+        })
+        .await;
+
+        let proxy = proxy(ProxyKind::Http, address, Some(("user", "secret")));
+        let mut stream = tunnel(&proxy).await.unwrap();
+        let mut tunnelled = [0u8; 6];
+        stream.read_exact(&mut tunnelled).await.unwrap();
+        assert_eq!(&tunnelled, b"tunnel");
+    }
+
+    #[tokio::test]
+    async fn http_connect_proxy_authentication_required() {
+        let address = serve(|mut stream| async move {
+            read_http_head(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        })
+        .await;
+
+        let proxy = proxy(ProxyKind::Http, address, None);
+        assert_eq!(
+            proxy_error(tunnel(&proxy).await),
+            "test: authentication failed"
+        );
+    }
+
+    #[tokio::test]
+    async fn http_connect_oversized_head() {
+        let address = serve(|mut stream| async move {
+            read_http_head(&mut stream).await;
+            let mut response = b"HTTP/1.1 200 OK\r\nX-Padding: ".to_vec();
+            response.resize(MAX_HTTP_HEAD * 2, b'a');
+            let _ = stream.write_all(&response).await;
+        })
+        .await;
+
+        let proxy = proxy(ProxyKind::Http, address, None);
+        assert_eq!(
+            proxy_error(tunnel(&proxy).await),
+            "test: CONNECT response head too long"
+        );
+    }
+
+    #[test]
+    fn targets_resolve_to_group_then_default() {
+        let config: ProxyConfig = toml::from_str(
+            r#"
+            default = "eu"
+
+            [servers.eu]
+            kind = "socks5"
+            address = "127.0.0.1:1080"
+
+            [servers.us]
+            kind = "http"
+            address = "127.0.0.1:3128"
+
+            [[groups]]
+            subnets = ["198.51.100.0/24"]
+            proxy = "direct"
+
+            [[groups]]
+            subnets = ["198.51.0.0/16", "203.0.113.0/24"]
+            proxy = "us"
+            "#,
+        )
+        .unwrap();
+        let proxies = Proxies::new(&config).unwrap();
+        let name = |ip: [u8; 4]| {
+            proxies
+                .for_target(Ipv4Addr::from(ip))
+                .map(|proxy| proxy.name.clone())
+        };
+
+        assert_eq!(name([198, 51, 100, 7]), None);
+        assert_eq!(name([198, 51, 7, 1]).as_deref(), Some("us"));
+        assert_eq!(name([203, 0, 113, 255]).as_deref(), Some("us"));
+        assert_eq!(name([192, 0, 2, 1]).as_deref(), Some("eu"));
+
+        let unknown = ProxyConfig {
+            default: Some("ap".to_string()),
+            ..config
+        };
+        assert_eq!(
+            Proxies::new(&unknown).err().as_deref(),
+            Some("proxy.default: unknown proxy \"ap\"")
+        );
+    }
+}
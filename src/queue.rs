@@ -0,0 +1,396 @@
+//! On-disk outbound notification queue with a dead-letter directory
+
+use crate::chat::ChatComponent;
+use crate::discord::MinecraftServer;
+use crate::notifier::OutboundRequest;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PENDING_DIR: &str = "pending";
+/// Found servers share the pending directory with rendered requests under this suffix
+const SERVER_SUFFIX: &str = ".server.json";
+const DEAD_DIR: &str = "dead";
+/// Servers collected for digests that have not been rendered yet
+const DIGEST_FILE: &str = "digest.json";
+/// Locked by the process delivering from the queue
+const LOCK_FILE: &str = "lock";
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A rendered request waiting for delivery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub id: String,
+    #[serde(flatten)]
+    pub request: OutboundRequest,
+    /// Human readable description used in logs
+    pub context: String,
+    pub queued_at: String,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Id of the found server this was rendered from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl QueuedMessage {
+    pub fn new(request: OutboundRequest, context: &str) -> Self {
+        Self {
+            id: next_id(),
+            request,
+            context: context.to_string(),
+            queued_at: chrono::Utc::now().to_rfc3339(),
+            attempts: 0,
+            last_error: None,
+            source: None,
+        }
+    }
+
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+}
+
+/// A found server persisted as soon as it is queued, before it is routed and rendered
+#[derive(Serialize, Deserialize)]
+pub struct QueuedServer {
+    pub id: String,
+    pub queued_at: String,
+    #[serde(flatten)]
+    server: PendingServer,
+}
+
+impl QueuedServer {
+    pub fn new(server: MinecraftServer) -> Self {
+        Self {
+            id: next_id(),
+            queued_at: chrono::Utc::now().to_rfc3339(),
+            server: PendingServer::from(&server),
+        }
+    }
+
+    pub fn server(&self) -> MinecraftServer {
+        self.server.to_server()
+    }
+}
+
+/// Servers waiting for the next digest to one destination
+#[derive(Serialize, Deserialize)]
+struct PendingDigest {
+    destination: String,
+    servers: Vec<PendingServer>,
+}
+
+/// Keeps the fields `MinecraftServer` leaves out of its serialized form
+#[derive(Serialize, Deserialize)]
+struct PendingServer {
+    #[serde(flatten)]
+    server: MinecraftServer,
+    motd: ChatComponent,
+    favicon: Option<String>,
+}
+
+impl PendingServer {
+    fn from(server: &MinecraftServer) -> Self {
+        Self {
+            motd: server.motd.clone(),
+            favicon: server.favicon.clone(),
+            server: server.clone(),
+        }
+    }
+
+    fn to_server(&self) -> MinecraftServer {
+        MinecraftServer {
+            motd: self.motd.clone(),
+            favicon: self.favicon.clone(),
+            ..self.server.clone()
+        }
+    }
+}
+
+/// Exclusive right to deliver from the queue; the OS releases it when the process dies
+pub struct QueueLock {
+    _file: fs::File,
+}
+
+#[derive(Debug, Clone)]
+pub struct NotificationQueue {
+    root: PathBuf,
+}
+
+impl NotificationQueue {
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(root.join(PENDING_DIR))?;
+        fs::create_dir_all(root.join(DEAD_DIR))?;
+        Ok(Self { root })
+    }
+
+    /// Fails with `WouldBlock` while another scan or replay delivers from the queue, which
+    /// would send its pending messages a second time
+    pub fn lock(&self) -> io::Result<QueueLock> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.root.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => Ok(QueueLock { _file: file }),
+            Err(fs::TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is in use by another process", self.root.display()),
+            )),
+            Err(fs::TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// Persists a message before its first delivery attempt and after each failed one
+    pub fn save(&self, message: &QueuedMessage) -> io::Result<()> {
+        write_atomic(&self.pending_path(&message.id), message)
+    }
+
+    pub fn remove(&self, message: &QueuedMessage) -> io::Result<()> {
+        match fs::remove_file(self.pending_path(&message.id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn dead_letter(&self, message: &QueuedMessage) -> io::Result<()> {
+        write_atomic(&self.dead_path(&message.id), message)?;
+        self.remove(message)
+    }
+
+    pub fn pending(&self) -> io::Result<Vec<QueuedMessage>> {
+        read_dir_sorted(&self.root.join(PENDING_DIR), false)
+    }
+
+    pub fn dead_letters(&self) -> io::Result<Vec<QueuedMessage>> {
+        read_dir_sorted(&self.root.join(DEAD_DIR), false)
+    }
+
+    pub fn save_server(&self, server: &QueuedServer) -> io::Result<()> {
+        write_atomic(&self.server_path(&server.id), server)
+    }
+
+    /// Forgets a found server once its announcements are queued or it needs none
+    pub fn remove_server(&self, server: &QueuedServer) -> io::Result<()> {
+        match fs::remove_file(self.server_path(&server.id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Found servers a previous run queued but never routed
+    pub fn pending_servers(&self) -> io::Result<Vec<QueuedServer>> {
+        read_dir_sorted(&self.root.join(PENDING_DIR), true)
+    }
+
+    /// Moves every dead letter back to the pending queue with a fresh attempt count
+    pub fn requeue_dead_letters(&self) -> io::Result<usize> {
+        let dead = self.dead_letters()?;
+        for message in &dead {
+            let mut message = message.clone();
+            message.attempts = 0;
+            message.last_error = None;
+            self.save(&message)?;
+            fs::remove_file(self.dead_path(&message.id))?;
+        }
+        Ok(dead.len())
+    }
+
+    /// Replaces the saved digest servers, per destination; nothing pending removes the file
+    pub fn save_digest(&self, pending: &[(String, Vec<MinecraftServer>)]) -> io::Result<()> {
+        let path = self.root.join(DIGEST_FILE);
+        if pending.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let digests: Vec<PendingDigest> = pending
+            .iter()
+            .map(|(destination, servers)| PendingDigest {
+                destination: destination.clone(),
+                servers: servers.iter().map(PendingServer::from).collect(),
+            })
+            .collect();
+        write_atomic(&path, &digests)
+    }
+
+    /// Digest servers a previous run collected but never rendered
+    pub fn digest(&self) -> io::Result<Vec<(String, Vec<MinecraftServer>)>> {
+        let bytes = match fs::read(self.root.join(DIGEST_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let digests: Vec<PendingDigest> = serde_json::from_slice(&bytes)?;
+        Ok(digests
+            .into_iter()
+            .map(|digest| {
+                let servers = digest
+                    .servers
+                    .iter()
+                    .map(PendingServer::to_server)
+                    .collect();
+                (digest.destination, servers)
+            })
+            .collect())
+    }
+
+    fn pending_path(&self, id: &str) -> PathBuf {
+        self.root.join(PENDING_DIR).join(format!("{}.json", id))
+    }
+
+    fn dead_path(&self, id: &str) -> PathBuf {
+        self.root.join(DEAD_DIR).join(format!("{}.json", id))
+    }
+
+    fn server_path(&self, id: &str) -> PathBuf {
+        self.root
+            .join(PENDING_DIR)
+            .join(format!("{}{}", id, SERVER_SUFFIX))
+    }
+}
+
+fn next_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    // Zero padded so that file name order is queue order
+    format!(
+        "{:024}-{:06}",
+        nanos,
+        SEQUENCE.fetch_add(1, Ordering::Relaxed) % 1_000_000
+    )
+}
+
+/// Writes to a temporary file first so a crash never leaves a half-written entry
+fn write_atomic(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(tmp, path)
+}
+
+/// Reads either the found servers or the rendered requests of a queue directory
+fn read_dir_sorted<T: DeserializeOwned>(dir: &Path, servers: bool) -> io::Result<Vec<T>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter(|path| path.to_string_lossy().ends_with(SERVER_SUFFIX) == servers)
+        .collect();
+    paths.sort();
+
+    let mut messages = Vec::with_capacity(paths.len());
+    for path in paths {
+        match fs::read(&path).map(|bytes| serde_json::from_slice::<T>(&bytes)) {
+            Ok(Ok(message)) => messages.push(message),
+            Ok(Err(e)) => warn!("Skipping corrupt queue entry {}: {}", path.display(), e),
+            Err(e) => warn!("Could not read queue entry {}: {}", path.display(), e),
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minecraft::ServerStatus;
+    use serde_json::json;
+
+    fn open_temp(name: &str) -> NotificationQueue {
+        let root = std::env::temp_dir().join(format!("mcsf-queue-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        NotificationQueue::open(root).unwrap()
+    }
+
+    #[test]
+    fn pending_digest_survives_a_restart() {
+        let queue = open_temp("digest");
+        let status: ServerStatus = serde_json::from_value(json!({
+            "version": {"name": "Paper 1.20.4", "protocol": 765},
+            "players": {"online": 3, "max": 20},
+            "description": {"text": "Hello", "color": "gold"},
+            "favicon": "data:image/png;base64,AAAA"
+        }))
+        .unwrap();
+        let server = MinecraftServer::from_status("10.0.0.1", 25565, &status, 765);
+        queue
+            .save_digest(&[("https://example.com/hook".to_string(), vec![server.clone()])])
+            .unwrap();
+
+        let reopened = NotificationQueue::open(queue.root.clone()).unwrap();
+        let restored = reopened.digest().unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].0, "https://example.com/hook");
+        let restored = &restored[0].1[0];
+        assert_eq!(restored.ip, server.ip);
+        assert_eq!(restored.fingerprint.family, server.fingerprint.family);
+        assert_eq!(restored.motd.to_ansi(), server.motd.to_ansi());
+        assert_eq!(restored.favicon, server.favicon);
+
+        queue.save_digest(&[]).unwrap();
+        assert!(queue.digest().unwrap().is_empty());
+        let _ = fs::remove_dir_all(&queue.root);
+    }
+
+    #[test]
+    fn only_one_holder_locks_the_queue() {
+        let queue = open_temp("lock");
+        let lock = queue.lock().unwrap();
+        let second = queue.lock().err().unwrap();
+        assert_eq!(second.kind(), io::ErrorKind::WouldBlock);
+
+        drop(lock);
+        assert!(queue.lock().is_ok());
+        let _ = fs::remove_dir_all(&queue.root);
+    }
+
+    #[test]
+    fn found_servers_are_kept_apart_from_rendered_requests() {
+        let queue = open_temp("found");
+        let status: ServerStatus = serde_json::from_value(json!({
+            "version": {"name": "1.20.4", "protocol": 765},
+            "players": {"online": 0, "max": 20},
+            "description": "Hello"
+        }))
+        .unwrap();
+        let found = QueuedServer::new(MinecraftServer::from_status(
+            "10.0.0.2", 25565, &status, 765,
+        ));
+        queue.save_server(&found).unwrap();
+        let message = QueuedMessage::new(
+            OutboundRequest::json("https://example.com/hook", json!({})),
+            "10.0.0.2:25565",
+        )
+        .with_source(&found.id);
+        queue.save(&message).unwrap();
+
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].source.as_deref(), Some(found.id.as_str()));
+        let servers = queue.pending_servers().unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].server().ip, "10.0.0.2");
+        assert_eq!(
+            servers[0].server().motd.to_ansi(),
+            found.server().motd.to_ansi()
+        );
+
+        queue.remove_server(&found).unwrap();
+        assert!(queue.pending_servers().unwrap().is_empty());
+        assert_eq!(queue.pending().unwrap().len(), 1);
+        let _ = fs::remove_dir_all(&queue.root);
+    }
+}
@@ -0,0 +1,288 @@
+//! Re-reads the config on SIGHUP or when its files change, while the scan keeps running
+
+use crate::config::{CONFIG_FILE, Config, LOCAL_CONFIG_FILE, SUBNETS_FILE};
+use crate::logger;
+use crate::notifier::{NotificationDispatcher, Notifiers};
+use crate::privacy::Privacy;
+use crate::routing::Router;
+use crate::shutdown::Shutdown;
+use crate::timeouts::AdaptiveTimeouts;
+use crate::validation::validate;
+use log::{error, info, warn};
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+
+/// Applies reloaded configs. Scan tasks and the stats collector read the config in effect
+/// from the watch channel; the rest is pushed to the parts holding it.
+pub struct Reloader {
+    live: watch::Sender<Arc<Config>>,
+    notifications: NotificationDispatcher,
+    timeouts: Arc<AdaptiveTimeouts>,
+}
+
+impl Reloader {
+    /// Starts watching until shutdown; the receiver always holds the config in effect.
+    /// `hangup` is taken from the caller so SIGHUP is caught from the start of the scan.
+    pub fn spawn(
+        config: Config,
+        notifications: NotificationDispatcher,
+        timeouts: Arc<AdaptiveTimeouts>,
+        hangup: Hangup,
+        shutdown: Shutdown,
+    ) -> watch::Receiver<Arc<Config>> {
+        let (live, receiver) = watch::channel(Arc::new(config));
+        let reloader = Self {
+            live,
+            notifications,
+            timeouts,
+        };
+        tokio::spawn(reloader.run(hangup, shutdown));
+        receiver
+    }
+
+    async fn run(self, mut hangup: Hangup, mut shutdown: Shutdown) {
+        let mut config_stamps = stamps(&[CONFIG_FILE, LOCAL_CONFIG_FILE]);
+        let mut subnets_stamp = stamps(&[SUBNETS_FILE]);
+
+        loop {
+            let poll = Duration::from_secs(self.live.borrow().reload.poll_seconds);
+            tokio::select! {
+                _ = shutdown.triggered() => break,
+                _ = hangup.recv() => {
+                    info!("[CONFIG] SIGHUP received, reloading");
+                    config_stamps = stamps(&[CONFIG_FILE, LOCAL_CONFIG_FILE]);
+                    self.reload();
+                }
+                _ = tokio::time::sleep(poll), if !poll.is_zero() => {
+                    let current = stamps(&[CONFIG_FILE, LOCAL_CONFIG_FILE]);
+                    if current != config_stamps {
+                        config_stamps = current;
+                        info!("[CONFIG] Config file changed, reloading");
+                        self.reload();
+                    }
+                    let current = stamps(&[SUBNETS_FILE]);
+                    if current != subnets_stamp {
+                        subnets_stamp = current;
+                        warn!(
+                            "[CONFIG] {} changed; targets are only read at startup, restart to apply",
+                            SUBNETS_FILE
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn reload(&self) {
+        match Config::load() {
+            Ok(config) => self.apply(config),
+            Err(e) => error!("[CONFIG] Reload failed, keeping the running config: {}", e),
+        }
+    }
+
+    /// Keeps the running config unless the new one is valid and only changes live fields
+    fn apply(&self, config: Config) {
+        let validation = validate(&config);
+        validation.log();
+        if validation.errors() > 0 {
+            error!(
+                "[CONFIG] Reload rejected with {} errors, keeping the running config",
+                validation.errors()
+            );
+            return;
+        }
+        let restart = restart_required(&self.live.borrow(), &config);
+        if !restart.is_empty() {
+            error!(
+                "[CONFIG] Reload rejected: {} cannot change while scanning, revert it or restart",
+                restart.join(", ")
+            );
+            return;
+        }
+
+        // Validation built both already, so these only fail if template files changed since
+        let built = Router::new(&config.routing, &config.discord)
+            .map_err(|e| format!("routing: {}", e))
+            .and_then(|router| {
+                let privacy = Privacy::new(&config.privacy)?;
+                Notifiers::new(&config.notifiers, &config.discord.embed, privacy)
+                    .map(|notifiers| (router, notifiers))
+                    .map_err(|e| e.to_string())
+            });
+        let (router, notifiers) = match built {
+            Ok(built) => built,
+            Err(e) => {
+                error!(
+                    "[CONFIG] Reload rejected, keeping the running config: {}",
+                    e
+                );
+                return;
+            }
+        };
+
+        self.notifications
+            .reconfigure(router, notifiers, config.discord.digest.clone());
+        self.timeouts.reconfigure(&config.timeouts);
+        logger::set_level(config.logging.level);
+        self.live.send_replace(Arc::new(config));
+        info!("[CONFIG] Reloaded");
+    }
+}
+
+/// Fields that were used to set up the scan and stay fixed until restart
+fn restart_required(running: &Config, reloaded: &Config) -> Vec<&'static str> {
+    [
+        (
+            "scanning.port",
+            running.scanning.port != reloaded.scanning.port,
+        ),
+        (
+            "scanning.num_tasks",
+            running.scanning.num_tasks != reloaded.scanning.num_tasks,
+        ),
+        ("networking", running.networking != reloaded.networking),
+        ("proxy", running.proxy != reloaded.proxy),
+        ("metrics", running.metrics != reloaded.metrics),
+        ("privacy", running.privacy != reloaded.privacy),
+        (
+            "discord.dedup",
+            running.discord.dedup != reloaded.discord.dedup,
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(path, _)| path)
+    .collect()
+}
+
+/// Modification times, `None` for files that do not exist
+fn stamps(paths: &[&str]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+/// SIGHUP listener. Until it is created the signal terminates the process, so the scanner
+/// creates it before anything else.
+#[cfg(unix)]
+pub struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    pub fn listen() -> Self {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::hangup()) {
+            Ok(signal) => Self(Some(signal)),
+            Err(e) => {
+                error!("Could not listen for SIGHUP: {}", e);
+                Self(None)
+            }
+        }
+    }
+
+    async fn recv(&mut self) {
+        if let Some(signal) = &mut self.0
+            && signal.recv().await.is_some()
+        {
+            return;
+        }
+        std::future::pending().await
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    pub fn listen() -> Self {
+        Self
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn reloader() -> (Reloader, watch::Receiver<Arc<Config>>) {
+        let router = Router::new(&Default::default(), &Default::default()).unwrap();
+        let (notifications, _) = NotificationDispatcher::spawn(
+            router,
+            Notifiers::default(),
+            Default::default(),
+            None,
+            None,
+        );
+        let (live, receiver) = watch::channel(Arc::new(Config::default()));
+        let reloader = Reloader {
+            live,
+            notifications,
+            timeouts: Arc::new(AdaptiveTimeouts::new(&Config::default().timeouts)),
+        };
+        (reloader, receiver)
+    }
+
+    #[test]
+    fn only_setup_fields_require_a_restart() {
+        let running = Config::default();
+        let mut reloaded = Config::default();
+        reloaded.timeouts.port_check_ms = 500;
+        reloaded.scanning.max_range_size = 10;
+        reloaded.scanning.exclude = vec![(Ipv4Addr::new(10, 0, 0, 0), 8)];
+        reloaded.retry.port_check.max_attempts = 3;
+        assert!(restart_required(&running, &reloaded).is_empty());
+
+        reloaded.scanning.port = 25566;
+        reloaded.scanning.num_tasks = 2;
+        reloaded.networking.base_source_port = 30000;
+        reloaded.metrics.enabled = true;
+        assert_eq!(
+            restart_required(&running, &reloaded),
+            [
+                "scanning.port",
+                "scanning.num_tasks",
+                "networking",
+                "metrics"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn live_fields_apply_and_the_rest_is_rejected() {
+        let (reloader, receiver) = reloader();
+        let mut config = Config::default();
+        config.timeouts.port_check_ms = 500;
+        config.scanning.exclude = vec![(Ipv4Addr::new(10, 0, 0, 0), 8)];
+        reloader.apply(config);
+        assert_eq!(receiver.borrow().timeouts.port_check_ms, 500);
+        assert!(
+            receiver
+                .borrow()
+                .scanning
+                .excludes(Ipv4Addr::new(10, 1, 2, 3))
+        );
+
+        // A restart-only change rejects the whole reload, live fields included
+        let mut config = Config::default();
+        config.scanning.port = 25566;
+        config.timeouts.port_check_ms = 700;
+        reloader.apply(config);
+        assert_eq!(receiver.borrow().scanning.port, 25565);
+        assert_eq!(receiver.borrow().timeouts.port_check_ms, 500);
+
+        let mut config = Config::default();
+        config.scanning.chunk_size = 0;
+        reloader.apply(config);
+        assert_eq!(receiver.borrow().scanning.chunk_size, 100);
+        assert_eq!(receiver.borrow().timeouts.port_check_ms, 500);
+    }
+}
@@ -0,0 +1,135 @@
+//! Retries for probes that failed for a transient reason
+
+use crate::config::RetryPolicy;
+use crate::minecraft::PingError;
+use std::future::Future;
+use tokio::time::{Duration, Instant, sleep};
+
+/// Outcome of a probe and the attempts it took
+pub struct Attempts<T> {
+    pub result: Result<T, PingError>,
+    pub attempts: u32,
+    /// Duration of the final attempt alone
+    pub last_attempt: Duration,
+}
+
+impl RetryPolicy {
+    fn should_retry(&self, attempt: u32, error: &PingError) -> bool {
+        attempt < self.max_attempts && self.retry_on.iter().any(|kind| kind == error.kind())
+    }
+
+    /// Exponential backoff before retry number `retry` (1-based), with jitter
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .backoff_ms
+            .saturating_mul(1 << (retry - 1).min(16))
+            .min(self.max_backoff_ms) as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        Duration::from_millis((delay * (1.0 - jitter)) as u64)
+    }
+
+    /// Runs `probe` until it succeeds, fails for a non-retryable reason or runs out of attempts
+    pub async fn run<T, F, Fut>(&self, mut probe: F) -> Attempts<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, PingError>>,
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let started = Instant::now();
+            let result = probe().await;
+            let last_attempt = started.elapsed();
+            match &result {
+                Err(e) if self.should_retry(attempts, e) => sleep(self.backoff(attempts)).await,
+                _ => {
+                    return Attempts {
+                        result,
+                        attempts,
+                        last_attempt,
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32, jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            backoff_ms: 100,
+            max_backoff_ms: 1000,
+            jitter,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = policy(10, 0.0);
+        let delays: Vec<u64> = (1..=6)
+            .map(|retry| policy.backoff(retry).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        for jitter in [0.5, 1.0, 7.0, -1.0, f64::NAN] {
+            let policy = policy(10, jitter);
+            for _ in 0..100 {
+                let delay = policy.backoff(3);
+                assert!(delay <= Duration::from_millis(400), "{:?}", delay);
+                if jitter <= 0.5 {
+                    assert!(delay >= Duration::from_millis(200), "{:?}", delay);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_listed_kinds_until_attempts_run_out() {
+        let policy = RetryPolicy {
+            backoff_ms: 0,
+            ..policy(3, 0.0)
+        };
+
+        let mut calls = 0;
+        let outcome: Attempts<()> = policy
+            .run(|| {
+                calls += 1;
+                async { Err(PingError::ConnectTimeout) }
+            })
+            .await;
+        assert_eq!((outcome.attempts, calls), (3, 3));
+
+        let mut calls = 0;
+        let outcome: Attempts<()> = policy
+            .run(|| {
+                calls += 1;
+                async { Err(PingError::ConnectionRefused) }
+            })
+            .await;
+        assert_eq!((outcome.attempts, calls), (1, 1));
+
+        let mut calls = 0;
+        let outcome = policy
+            .run(|| {
+                calls += 1;
+                let result = if calls < 2 {
+                    Err(PingError::ConnectionReset)
+                } else {
+                    Ok(calls)
+                };
+                async move { result }
+            })
+            .await;
+        assert_eq!(outcome.attempts, 2);
+        assert!(matches!(outcome.result, Ok(2)));
+    }
+}
@@ -0,0 +1,411 @@
+//! Ordered routing rules deciding where found-server notifications are delivered
+
+use crate::config::{DiscordConfig, RouteMatch, RouteRule, RoutingConfig};
+use crate::discord::MinecraftServer;
+use regex::Regex;
+
+#[derive(Clone)]
+struct CompiledRule {
+    rule: RouteRule,
+    motd_regex: Option<Regex>,
+}
+
+#[derive(Clone)]
+pub struct Router {
+    rules: Vec<CompiledRule>,
+    default: RouteRule,
+}
+
+impl Router {
+    /// Compiles the configured rules; without any, the legacy per-version webhooks are used
+    pub fn new(routing: &RoutingConfig, legacy: &DiscordConfig) -> Result<Self, regex::Error> {
+        let (rules, default) = if routing.rules.is_empty() && routing.default.is_empty() {
+            legacy_rules(legacy)
+        } else {
+            (routing.rules.clone(), routing.default.clone())
+        };
+
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let motd_regex = rule
+                    .matcher
+                    .motd_regex
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()?;
+                Ok(CompiledRule { rule, motd_regex })
+            })
+            .collect::<Result<Vec<_>, regex::Error>>()?;
+
+        Ok(Self {
+            rules,
+            default: RouteRule {
+                name: "default".to_string(),
+                matcher: RouteMatch::default(),
+                destinations: default,
+                continue_matching: false,
+                color: None,
+            },
+        })
+    }
+
+    /// Matching rules in config order, or the catch-all default when none match
+    pub fn route(&self, server: &MinecraftServer) -> Vec<&RouteRule> {
+        let mut matched = Vec::new();
+        for compiled in &self.rules {
+            if matches(&compiled.rule.matcher, compiled.motd_regex.as_ref(), server) {
+                matched.push(&compiled.rule);
+                if !compiled.rule.continue_matching {
+                    break;
+                }
+            }
+        }
+
+        if matched.is_empty() && !self.default.destinations.is_empty() {
+            matched.push(&self.default);
+        }
+        matched
+    }
+}
+
+fn matches(matcher: &RouteMatch, motd_regex: Option<&Regex>, server: &MinecraftServer) -> bool {
+    if let Some(pattern) = &matcher.version
+        && !glob_match(pattern, server.game_version())
+        && !glob_match(pattern, &server.version)
+    {
+        return false;
+    }
+    if matcher
+        .protocol_min
+        .is_some_and(|min| server.protocol < min)
+        || matcher
+            .protocol_max
+            .is_some_and(|max| server.protocol > max)
+    {
+        return false;
+    }
+    if matcher
+        .min_players
+        .is_some_and(|min| server.players_online < min)
+        || matcher
+            .max_players
+            .is_some_and(|max| server.players_online > max)
+    {
+        return false;
+    }
+    if !matcher.countries.is_empty() {
+        let country_matches = matcher.countries.iter().any(|wanted| {
+            [&server.country, &server.country_code]
+                .into_iter()
+                .flatten()
+                .any(|known| known.eq_ignore_ascii_case(wanted))
+        });
+        if !country_matches {
+            return false;
+        }
+    }
+    if !matcher.asns.is_empty() && !server.asn.is_some_and(|asn| matcher.asns.contains(&asn)) {
+        return false;
+    }
+    if !matcher.software.is_empty()
+        && !matcher
+            .software
+            .iter()
+            .any(|family| family.eq_ignore_ascii_case(server.fingerprint.family.name()))
+    {
+        return false;
+    }
+    if matcher
+        .proxy
+        .is_some_and(|proxy| proxy != server.fingerprint.is_proxy)
+    {
+        return false;
+    }
+    if !matcher.mods.is_empty()
+        && !server
+            .mods
+            .as_ref()
+            .is_some_and(|list| matcher.mods.iter().any(|id| list.has_mod(id)))
+    {
+        return false;
+    }
+    if let Some(regex) = motd_regex
+        && !regex.is_match(&server.description)
+    {
+        return false;
+    }
+    true
+}
+
+/// Case-insensitive glob supporting `*` and `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Rebuilds the old 1.21/1.20/1.19/other x active/empty webhook matrix as rules
+fn legacy_rules(legacy: &DiscordConfig) -> (Vec<RouteRule>, Vec<String>) {
+    let matrix = [
+        (
+            "1.21",
+            Some("1.21*"),
+            &legacy.webhook_121_active,
+            &legacy.webhook_121_empty,
+        ),
+        (
+            "1.20",
+            Some("1.20*"),
+            &legacy.webhook_120_active,
+            &legacy.webhook_120_empty,
+        ),
+        (
+            "1.19",
+            Some("1.19*"),
+            &legacy.webhook_119_active,
+            &legacy.webhook_119_empty,
+        ),
+        (
+            "other",
+            None,
+            &legacy.webhook_other_active,
+            &legacy.webhook_other_empty,
+        ),
+    ];
+
+    let mut rules = Vec::new();
+    for (name, version, active, empty) in matrix {
+        for (state, webhook, min_players, max_players) in [
+            ("active", active, Some(1), None),
+            ("empty", empty, None, Some(0)),
+        ] {
+            // Matching with no destination still stops evaluation, like the old
+            // empty-webhook behaviour did
+            rules.push(RouteRule {
+                name: format!("{} {}", name, state),
+                matcher: RouteMatch {
+                    version: version.map(str::to_string),
+                    min_players,
+                    max_players,
+                    ..RouteMatch::default()
+                },
+                destinations: if webhook.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![webhook.clone()]
+                },
+                continue_matching: false,
+                color: None,
+            });
+        }
+    }
+    (rules, Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minecraft::ServerStatus;
+    use crate::mods::{ModEntry, ModList};
+    use serde_json::json;
+
+    fn server(version: &str, protocol: i32, players: u32) -> MinecraftServer {
+        let status: ServerStatus = serde_json::from_value(json!({
+            "version": {"name": version, "protocol": protocol},
+            "players": {"online": players, "max": 20},
+            "description": "A Minecraft Server"
+        }))
+        .unwrap();
+        MinecraftServer::from_status("10.0.0.1", 25565, &status, protocol)
+    }
+
+    fn router(config: &str) -> Router {
+        let routing: RoutingConfig = toml::from_str(config).unwrap();
+        Router::new(&routing, &DiscordConfig::default()).unwrap()
+    }
+
+    fn routed(router: &Router, server: &MinecraftServer) -> Vec<String> {
+        router
+            .route(server)
+            .into_iter()
+            .map(|rule| rule.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn globs_match_case_insensitively() {
+        assert!(glob_match("1.21*", "1.21.4"));
+        assert!(glob_match("1.2?", "1.20"));
+        assert!(!glob_match("1.2?", "1.200"));
+        assert!(glob_match("*paper*", "Paper 1.20.4"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(glob_match("a*b", "ab"));
+        assert!(!glob_match("a*b", "abc"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "1.21"));
+    }
+
+    #[test]
+    fn first_match_wins_unless_it_continues() {
+        let router = router(
+            r#"
+            default = ["catch-all"]
+
+            [[rules]]
+            name = "paper"
+            match = { software = ["paper"] }
+            destinations = ["paper-hook"]
+            continue = true
+
+            [[rules]]
+            name = "active"
+            match = { min_players = 1 }
+            destinations = ["active-hook"]
+
+            [[rules]]
+            name = "modern"
+            match = { version = "1.20*" }
+            destinations = ["modern-hook"]
+            "#,
+        );
+
+        assert_eq!(
+            routed(&router, &server("Paper 1.20.4", 765, 3)),
+            ["paper", "active"]
+        );
+        assert_eq!(
+            routed(&router, &server("Paper 1.20.4", 765, 0)),
+            ["paper", "modern"]
+        );
+        assert_eq!(routed(&router, &server("1.20.4", 765, 5)), ["active"]);
+        assert_eq!(routed(&router, &server("1.8.9", 47, 0)), ["default"]);
+        assert_eq!(
+            router.route(&server("1.8.9", 47, 0))[0].destinations,
+            ["catch-all"]
+        );
+    }
+
+    #[test]
+    fn nothing_matches_without_a_default() {
+        let router = router(
+            r#"
+            [[rules]]
+            name = "active"
+            match = { min_players = 1 }
+            destinations = ["active-hook"]
+            "#,
+        );
+        assert!(router.route(&server("1.20.4", 765, 0)).is_empty());
+    }
+
+    #[test]
+    fn legacy_webhooks_become_rules() {
+        let legacy = DiscordConfig {
+            webhook_121_active: "https://example.com/121-active".to_string(),
+            webhook_120_empty: "https://example.com/120-empty".to_string(),
+            webhook_other_active: "https://example.com/other-active".to_string(),
+            ..Default::default()
+        };
+        let router = Router::new(&RoutingConfig::default(), &legacy).unwrap();
+
+        let rules = router.route(&server("1.21.1", 767, 2));
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].name, "1.21 active");
+        assert_eq!(rules[0].destinations, ["https://example.com/121-active"]);
+
+        let rules = router.route(&server("1.20.4", 765, 0));
+        assert_eq!(rules[0].name, "1.20 empty");
+        assert_eq!(rules[0].destinations, ["https://example.com/120-empty"]);
+
+        // An unset webhook still claims the server, so it is not announced anywhere
+        let rules = router.route(&server("1.19.4", 762, 0));
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].name, "1.19 empty");
+        assert!(rules[0].destinations.is_empty());
+
+        assert_eq!(routed(&router, &server("1.8.9", 47, 4)), ["other active"]);
+    }
+
+    #[test]
+    fn every_matcher_field_must_match() {
+        let matcher = |rule: &str| {
+            router(&format!(
+                "[[rules]]\nname = \"rule\"\nmatch = {{ {} }}\ndestinations = [\"hook\"]",
+                rule
+            ))
+        };
+        let hits = |rule: &str, server: &MinecraftServer| !matcher(rule).route(server).is_empty();
+
+        let mut found = server("Paper 1.20.4", 765, 3);
+        found.country = Some("Germany".to_string());
+        found.country_code = Some("DE".to_string());
+        found.asn = Some(3320);
+        found.mods = Some(ModList {
+            format: "FML3".to_string(),
+            mods: vec![ModEntry {
+                id: "create".to_string(),
+                version: None,
+            }],
+            channels: Vec::new(),
+            truncated: false,
+        });
+
+        assert!(hits("protocol_min = 765, protocol_max = 765", &found));
+        assert!(!hits("protocol_min = 766", &found));
+        assert!(!hits("protocol_max = 764", &found));
+        assert!(hits("max_players = 3", &found));
+        assert!(!hits("max_players = 2", &found));
+        assert!(hits("countries = [\"de\"]", &found));
+        assert!(hits("countries = [\"germany\"]", &found));
+        assert!(!hits("countries = [\"FR\"]", &found));
+        assert!(hits("asns = [1, 3320]", &found));
+        assert!(!hits("asns = [1]", &found));
+        assert!(hits("proxy = false", &found));
+        assert!(!hits("proxy = true", &found));
+        assert!(hits("mods = [\"Create\"]", &found));
+        assert!(!hits("mods = [\"jei\"]", &found));
+        assert!(hits("motd_regex = \"(?i)minecraft\"", &found));
+        assert!(!hits("motd_regex = \"^Survival\"", &found));
+        assert!(hits("version = \"1.20.4\", min_players = 1", &found));
+        assert!(!hits("version = \"1.20.4\", min_players = 4", &found));
+
+        found.country = None;
+        found.country_code = None;
+        found.asn = None;
+        assert!(!hits("countries = [\"DE\"]", &found));
+        assert!(!hits("asns = [3320]", &found));
+    }
+
+    #[test]
+    fn invalid_motd_regex_is_rejected() {
+        let routing: RoutingConfig = toml::from_str(
+            r#"
+            [[rules]]
+            name = "broken"
+            match = { motd_regex = "(unclosed" }
+            "#,
+        )
+        .unwrap();
+        assert!(Router::new(&routing, &DiscordConfig::default()).is_err());
+    }
+}
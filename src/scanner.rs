@@ -1,27 +1,147 @@
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
-use tokio::time::Instant;
-
-use crate::config::Config;
-use crate::discord::DiscordNotifier;
-use crate::minecraft::{extract_description, ping_server_fast, quick_port_check};
+use tokio::time::{Duration, Instant, timeout};
+
+use crate::checkpoint::{Checkpoint, RangeProgress};
+use crate::config::{CHECKPOINT_FILE, Config, OUTPUT_DIR, QUEUE_DIR, RedactionProfile, SEEN_FILE};
+use crate::dedup::SeenSet;
+use crate::discord::MinecraftServer;
+use crate::export::ResultsExporter;
+use crate::logger;
+use crate::metrics::{self, METRICS};
+use crate::minecraft::{PingError, ping_server_fast, quick_port_check};
 use crate::network::{increment_ip, load_subnets, random_ipv4_from_subnets};
-use crate::stats::{ScanMessage, StatsCollector};
+use crate::notifier::{self, NotificationDispatcher, Notifiers};
+use crate::ports::{SourceAddresses, SourcePorts};
+use crate::privacy::Privacy;
+use crate::proxy::Proxies;
+use crate::queue::NotificationQueue;
+use crate::reload::{Hangup, Reloader};
+use crate::routing::Router;
+use crate::shutdown::Shutdown;
+use crate::stats::{
+    FailureCounts, ProbeStage, ProbeTimings, RetryCounts, ScanMessage, StatsCollector,
+};
+use crate::timeouts::AdaptiveTimeouts;
+use crate::validation;
+
+/// Parts built from the config; validation already built them once, so they only fail
+/// when the environment changed in between (template files, local addresses)
+struct Components {
+    router: Router,
+    source_addresses: SourceAddresses,
+    proxies: Proxies,
+    export_redaction: Option<Arc<RedactionProfile>>,
+    notifiers: Notifiers,
+}
+
+impl Components {
+    fn build(config: &Config) -> Result<Self, String> {
+        let privacy = Privacy::new(&config.privacy)?;
+        Ok(Self {
+            router: Router::new(&config.routing, &config.discord)
+                .map_err(|e| format!("routing: {}", e))?,
+            source_addresses: SourceAddresses::new(&config.networking.source_addresses)?,
+            proxies: Proxies::new(&config.proxy)?,
+            export_redaction: privacy.for_export(),
+            notifiers: Notifiers::new(&config.notifiers, &config.discord.embed, privacy)
+                .map_err(|e| e.to_string())?,
+        })
+    }
+}
 
-pub async fn run_scanner() {
-    let config = Config::load().expect("Failed to load config");
+pub async fn run_scanner() -> ExitCode {
+    // Before the slow startup steps, so a SIGHUP sent meanwhile is not fatal
+    let hangup = Hangup::listen();
+    let Some(config) = validation::load_checked() else {
+        return ExitCode::FAILURE;
+    };
+    logger::set_level(config.logging.level);
+    let Components {
+        router,
+        source_addresses,
+        proxies,
+        export_redaction,
+        notifiers,
+    } = match Components::build(&config) {
+        Ok(components) => components,
+        Err(e) => {
+            error!("[CONFIG] {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let proxies = Arc::new(proxies);
+    let mut shutdown = Shutdown::listen();
 
     let (tx, mut rx) = mpsc::unbounded_channel::<ScanMessage>();
     let subnets = load_subnets();
 
-    let discord_notifier = DiscordNotifier::new(config.discord.clone());
+    if config.metrics.enabled {
+        tokio::spawn(metrics::serve(config.metrics.listen.clone()));
+    }
+
+    let queue = match NotificationQueue::open(format!("{}/{}", OUTPUT_DIR, QUEUE_DIR)) {
+        Ok(queue) => Some(queue),
+        Err(e) => {
+            error!(
+                "Could not open notification queue, notifications will not survive restarts: {}",
+                e
+            );
+            None
+        }
+    };
+    // Held until the scan returns so `queue replay` cannot deliver the same messages
+    let _queue_lock = match queue.as_ref().map(NotificationQueue::lock) {
+        Some(Ok(lock)) => Some(lock),
+        Some(Err(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            error!(
+                "Notification queue is busy, is another scan or replay running? {}",
+                e
+            );
+            return ExitCode::FAILURE;
+        }
+        Some(Err(e)) => {
+            warn!("Could not lock notification queue: {}", e);
+            None
+        }
+        None => None,
+    };
+    let seen = if config.discord.dedup.enabled {
+        match SeenSet::load(
+            format!("{}/{}", OUTPUT_DIR, SEEN_FILE),
+            config.discord.dedup.cooldown_seconds,
+        ) {
+            Ok(seen) => Some(seen),
+            Err(e) => {
+                error!(
+                    "Could not load seen-set, duplicates will be announced: {}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let (notifications, notifications_handle) = NotificationDispatcher::spawn(
+        router,
+        notifiers,
+        config.discord.digest.clone(),
+        queue.clone(),
+        seen,
+    );
 
     for ip in &config.test_servers.test_ips {
         info!("[TEST] Ping server {}:{}", ip, config.scanning.port);
+        let proxy = ip.parse().ok().and_then(|ip| proxies.for_target(ip));
         match ping_server_fast(
             ip,
             config.scanning.port,
-            None,
+            SocketAddrV4::new(source_addresses.next(), 0),
+            proxy.as_deref(),
             config.timeouts.connection_ms,
             config.timeouts.protocol_response_ms,
             config.minecraft.protocol_version,
@@ -29,16 +149,13 @@ pub async fn run_scanner() {
         .await
         {
             Ok(info) => {
-                let description = extract_description(&info.description);
-                info!(
-                    "[FOUND][TEST] {}:{} - {}/{} - {} - {}",
+                let server = MinecraftServer::from_status(
                     ip,
                     config.scanning.port,
-                    info.players.online,
-                    info.players.max,
-                    info.version.name,
-                    description
+                    &info,
+                    config.minecraft.protocol_version,
                 );
+                info!("[FOUND][TEST] {}", server);
             }
             Err(e) => {
                 info!(
@@ -54,17 +171,69 @@ pub async fn run_scanner() {
         config.scanning.num_tasks
     );
 
-    let stats_interval = config.stats.stats_interval_seconds;
+    let checkpoint_path = format!("{}/{}", OUTPUT_DIR, CHECKPOINT_FILE);
+    let resume = match Checkpoint::take(&checkpoint_path) {
+        Ok(Some(checkpoint)) => {
+            let written_at = checkpoint.written_at.clone();
+            let ranges = checkpoint.unfinished(&config.scanning);
+            info!(
+                "Resuming {} unfinished ranges from the checkpoint written {}",
+                ranges.len(),
+                written_at
+            );
+            ranges
+        }
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            error!("Could not read checkpoint, starting fresh ranges: {}", e);
+            Vec::new()
+        }
+    };
+    let resume = Arc::new(Mutex::new(resume));
+    let in_progress: Arc<Mutex<Vec<Option<RangeProgress>>>> =
+        Arc::new(Mutex::new(vec![None; config.scanning.num_tasks]));
+
+    let timeouts = Arc::new(AdaptiveTimeouts::new(&config.timeouts));
+
+    // From here on the config in effect comes from `live`; only live fields change there
+    let live = Reloader::spawn(
+        config,
+        notifications.clone(),
+        timeouts.clone(),
+        hangup,
+        shutdown.clone(),
+    );
+    let config = live.borrow().clone();
+
+    let mut stats_live = live.clone();
+    let stats_timeouts = timeouts.clone();
     let stats_handle = tokio::spawn(async move {
-        let mut stats = StatsCollector::new().with_discord(discord_notifier);
+        let mut stats = StatsCollector::new()
+            .with_notifications(notifications)
+            .with_filters(stats_live.borrow_and_update().filters.clone())
+            .with_timeouts(stats_timeouts);
+        match ResultsExporter::open() {
+            Ok(exporter) => {
+                stats = stats.with_exporter(match export_redaction {
+                    Some(profile) => exporter.with_redaction(profile),
+                    None => exporter,
+                })
+            }
+            Err(e) => error!("Could not open results export: {}", e),
+        }
 
         while let Some(msg) = rx.recv().await {
+            if stats_live.has_changed().unwrap_or(false) {
+                stats.set_filters(stats_live.borrow_and_update().filters.clone());
+            }
             stats.update(msg);
 
+            let stats_interval = stats_live.borrow().stats.stats_interval_seconds;
             if stats.should_report_stats(stats_interval) {
                 stats.report_stats(stats_interval);
             }
         }
+        stats.finish()
     });
 
     let mut handles = Vec::new();
@@ -73,135 +242,274 @@ pub async fn run_scanner() {
         let subnets_clone = subnets.clone();
         let tx_clone = tx.clone();
         let port = config.scanning.port;
-        let max_range_size = config.scanning.max_range_size;
-        let consecutive_threshold = config.scanning.consecutive_threshold;
-        let chunk_size = config.scanning.chunk_size;
-        let base_source_port = config.networking.base_source_port
-            + (task_id as u16 * config.networking.port_range_per_task);
-        let port_check_timeout = config.timeouts.port_check_ms;
-        let connection_timeout = config.timeouts.connection_ms;
-        let protocol_timeout = config.timeouts.protocol_response_ms;
-        let protocol_version = config.minecraft.protocol_version;
+        let range = config.networking.port_range_per_task as u32;
+        let ports = SourcePorts::new(
+            task_id,
+            source_addresses.clone(),
+            config.networking.base_source_port as u32 + task_id as u32 * range,
+            range,
+            Duration::from_secs(config.networking.time_wait_seconds),
+        );
+        let timeouts = timeouts.clone();
+        let proxies = proxies.clone();
+        let live = live.clone();
+        let shutdown = shutdown.clone();
+        let resume = resume.clone();
+        let in_progress = in_progress.clone();
 
         let handle = tokio::spawn(async move {
-            let mut source_port_counter = 0u16;
-
-            loop {
-                let thread_ip = random_ipv4_from_subnets(&subnets_clone);
+            while !shutdown.is_triggered() {
+                let resumed = resume.lock().unwrap().pop();
+                let range = match resumed {
+                    Some(range) => {
+                        debug!(
+                            "[TASK {}] Resuming range from {} at {}",
+                            task_id + 1,
+                            range.start,
+                            range.next
+                        );
+                        range
+                    }
+                    None => {
+                        let start = random_ipv4_from_subnets(&subnets_clone);
+                        debug!(
+                            "[TASK {}] New start IP {} (from subnet)",
+                            task_id + 1,
+                            start
+                        );
+                        RangeProgress {
+                            start,
+                            next: start,
+                            scanned: 0,
+                            found: 0,
+                            consecutive_empty: 0,
+                        }
+                    }
+                };
                 let start_time = Instant::now();
-                debug!(
-                    "[TASK {}] New start IP {} (from subnet)",
-                    task_id + 1,
-                    thread_ip
-                );
-
-                let mut current_ip = thread_ip;
-                let mut local_scanned = 0;
-                let mut local_found = 0;
-                let mut consecutive_empty = 0;
-
-                while local_scanned < max_range_size && consecutive_empty < consecutive_threshold {
-                    let current_chunk_size = chunk_size.min(max_range_size - local_scanned);
-                    let chunk_ips: Vec<String> = (0..current_chunk_size)
-                        .map(|i| {
-                            let ip = increment_ip(&current_ip, i as u32);
-                            ip.to_string()
-                        })
+                let thread_ip = range.start;
+                let mut current_ip = range.next;
+                let mut local_scanned = range.scanned;
+                let mut local_found = range.found;
+                let mut consecutive_empty = range.consecutive_empty;
+                in_progress.lock().unwrap()[task_id] = Some(range);
+
+                // Refreshed after every chunk to pick up reloads
+                let mut config = live.borrow().clone();
+                while local_scanned < config.scanning.max_range_size
+                    && consecutive_empty < config.scanning.consecutive_threshold
+                    && !shutdown.is_triggered()
+                {
+                    let current_chunk_size = config
+                        .scanning
+                        .chunk_size
+                        .min(config.scanning.max_range_size - local_scanned);
+                    let chunk_ips: Vec<Ipv4Addr> = (0..current_chunk_size)
+                        .map(|i| increment_ip(&current_ip, i as u32))
+                        .filter(|ip| !config.scanning.excludes(*ip))
                         .collect();
 
                     let mut port_scan_tasks = Vec::new();
-                    for ip_str in &chunk_ips {
-                        let ip_str_clone = ip_str.clone();
-
-                        let source_port = base_source_port + (source_port_counter % 255);
-                        source_port_counter = source_port_counter.wrapping_add(1);
+                    for ip in &chunk_ips {
+                        let ip = *ip;
+                        let port_check_timeout = timeouts.for_target(ip).port_check_ms;
+                        let proxy = proxies.for_target(ip);
 
+                        let config = config.clone();
+                        let ports = ports.clone();
                         let task = tokio::spawn(async move {
-                            match quick_port_check(
-                                &ip_str_clone,
-                                port,
-                                Some(source_port),
-                                port_check_timeout,
-                            )
-                            .await
-                            {
-                                Ok(true) => Some((ip_str_clone, true)),
-                                _ => Some((ip_str_clone, false)),
+                            METRICS.port_checks_in_flight.inc();
+                            let started = Instant::now();
+                            let target = ip.to_string();
+                            let outcome = config
+                                .retry
+                                .port_check
+                                .run(|| {
+                                    let lease = ports.acquire();
+                                    let target = &target;
+                                    let proxy = proxy.as_deref();
+                                    async move {
+                                        let result = quick_port_check(
+                                            target,
+                                            port,
+                                            lease.source(),
+                                            proxy,
+                                            port_check_timeout,
+                                        )
+                                        .await;
+                                        lease.finish(&result);
+                                        result
+                                    }
+                                })
+                                .await;
+                            METRICS.port_checks_in_flight.dec();
+                            if outcome.result.is_ok() {
+                                METRICS.connect_duration.observe(outcome.last_attempt);
                             }
+                            (ip, outcome, started.elapsed())
                         });
                         port_scan_tasks.push(task);
                     }
 
+                    let mut failures = FailureCounts::default();
+                    let mut retries = RetryCounts::default();
+                    let mut timings = ProbeTimings::default();
                     let mut open_ips = Vec::new();
                     for task in port_scan_tasks {
-                        if let Ok(Some((ip, is_open))) = task.await {
-                            if is_open {
-                                open_ips.push(ip.clone());
-                                let _ = tx_clone.send(ScanMessage::OpenPort(ip));
+                        let Ok((ip, outcome, elapsed)) = task.await else {
+                            continue;
+                        };
+                        retries.record(
+                            ProbeStage::PortCheck,
+                            outcome.attempts,
+                            outcome.result.is_ok(),
+                        );
+                        match outcome.result {
+                            Ok(()) => {
+                                timings.connect.push(outcome.last_attempt);
+                                // Through a proxy this includes the tunnel setup
+                                if proxies.for_target(ip).is_none() {
+                                    timeouts.record_connect(ip, outcome.last_attempt);
+                                }
+                                open_ips.push((ip, elapsed));
+                                let _ = tx_clone.send(ScanMessage::OpenPort(ip.to_string()));
                             }
+                            Err(e) => failures.record(ProbeStage::PortCheck, &e),
                         }
                     }
 
                     let mut mc_ping_tasks = Vec::new();
-                    for ip_str in &open_ips {
-                        let ip_str_clone = ip_str.clone();
-
-                        let source_port = base_source_port + (source_port_counter % 255);
-                        source_port_counter = source_port_counter.wrapping_add(1);
+                    for (ip, port_check_time) in &open_ips {
+                        let (ip, port_check_time) = (*ip, *port_check_time);
+                        let target = timeouts.for_target(ip);
+                        let proxy = proxies.for_target(ip);
 
+                        let config = config.clone();
+                        let ports = ports.clone();
                         let task = tokio::spawn(async move {
-                            match ping_server_fast(
-                                &ip_str_clone,
-                                port,
-                                Some(source_port),
-                                connection_timeout,
-                                protocol_timeout,
-                                protocol_version,
-                            )
-                            .await
-                            {
-                                Ok(info) => {
-                                    let description = extract_description(&info.description);
-                                    Some((
-                                        format!(
-                                            "[FOUND] {}:{} - {}/{} - {} - {}",
-                                            ip_str_clone,
+                            METRICS.status_pings_in_flight.inc();
+                            let started = Instant::now();
+                            let address = ip.to_string();
+                            let protocol_version = config.minecraft.protocol_version;
+                            let outcome = config
+                                .retry
+                                .status
+                                .run(|| {
+                                    let lease = ports.acquire();
+                                    let address = &address;
+                                    let proxy = proxy.as_deref();
+                                    async move {
+                                        let result = ping_server_fast(
+                                            address,
                                             port,
-                                            info.players.online,
-                                            info.players.max,
-                                            info.version.name,
-                                            description
-                                        ),
-                                        true,
-                                    ))
-                                }
-                                _ => Some((String::new(), false)),
-                            }
+                                            lease.source(),
+                                            proxy,
+                                            target.connection_ms,
+                                            target.protocol_response_ms,
+                                            protocol_version,
+                                        )
+                                        .await;
+                                        lease.finish(&result);
+                                        result
+                                    }
+                                })
+                                .await;
+                            METRICS.status_pings_in_flight.dec();
+                            let probe_time = port_check_time + started.elapsed();
+                            (ip, outcome, probe_time, target, proxy.is_some())
                         });
                         mc_ping_tasks.push(task);
                     }
 
                     let mut chunk_found = 0;
                     for task in mc_ping_tasks {
-                        if let Ok(Some((message, found))) = task.await {
-                            if found {
+                        let Ok((ip, outcome, probe_time, target, proxied)) = task.await else {
+                            continue;
+                        };
+                        retries.record(
+                            ProbeStage::Status,
+                            outcome.attempts,
+                            outcome.result.is_ok(),
+                        );
+                        match outcome.result {
+                            Ok(info) => {
+                                timings.probe.push(probe_time);
+                                if let Some(latency) = info.latency {
+                                    METRICS.status_rtt.observe(latency);
+                                    timings.status.push(latency);
+                                    timeouts.record_response(ip, latency);
+                                }
+                                // Through a proxy this is the tunnel setup, not the target's connect time
+                                if let Some(connect_time) = info.connect_time
+                                    && info.proxy.is_none()
+                                {
+                                    timeouts.record_connect(ip, connect_time);
+                                }
+                                timings.first_byte.extend(info.first_byte);
+                                let server = MinecraftServer::from_status(
+                                    &ip.to_string(),
+                                    port,
+                                    &info,
+                                    config.minecraft.protocol_version,
+                                );
                                 chunk_found += 1;
                                 local_found += 1;
                                 consecutive_empty = 0;
-                                let _ = tx_clone.send(ScanMessage::Found(message));
+                                let _ = tx_clone.send(ScanMessage::Found(Box::new(server)));
+                            }
+                            Err(e) => {
+                                // The port just answered, so a timeout means the limit was
+                                // too tight: it counts as a sample at that limit, letting
+                                // learned timeouts widen again. Port check timeouts are not
+                                // counted, most of them are filtered ports.
+                                match e {
+                                    PingError::ConnectTimeout if !proxied => timeouts
+                                        .record_connect(
+                                            ip,
+                                            Duration::from_millis(target.connection_ms),
+                                        ),
+                                    PingError::ProtocolTimeout => timeouts.record_response(
+                                        ip,
+                                        Duration::from_millis(target.protocol_response_ms),
+                                    ),
+                                    _ => {}
+                                }
+                                failures.record(ProbeStage::Status, &e);
                             }
                         }
                     }
 
                     local_scanned += current_chunk_size;
-                    let _ = tx_clone.send(ScanMessage::Scanned(current_chunk_size as u64));
+                    // Excluded addresses advance the range but are not counted as scanned
+                    let _ = tx_clone.send(ScanMessage::Scanned(chunk_ips.len() as u64));
+                    if !failures.is_empty() {
+                        let _ = tx_clone.send(ScanMessage::Failed(failures));
+                    }
+                    if !retries.is_empty() {
+                        let _ = tx_clone.send(ScanMessage::Retried(retries));
+                    }
+                    let _ = tx_clone.send(ScanMessage::Timings(timings));
 
                     if chunk_found == 0 && open_ips.is_empty() {
                         consecutive_empty += current_chunk_size;
                     }
 
                     current_ip = increment_ip(&current_ip, current_chunk_size as u32);
+                    in_progress.lock().unwrap()[task_id] = Some(RangeProgress {
+                        start: thread_ip,
+                        next: current_ip,
+                        scanned: local_scanned,
+                        found: local_found,
+                        consecutive_empty,
+                    });
+                    config = live.borrow().clone();
+                }
+
+                // Unfinished ranges stay in `in_progress` for the checkpoint
+                if shutdown.is_triggered() {
+                    break;
                 }
+                in_progress.lock().unwrap()[task_id] = None;
 
                 let elapsed = start_time.elapsed();
                 let scans_per_minute = if elapsed.as_secs() > 0 {
@@ -241,10 +549,68 @@ pub async fn run_scanner() {
         handles.push(handle);
     }
 
-    for handle in handles {
-        let _ = handle.await;
+    shutdown.triggered().await;
+    let config = live.borrow().clone();
+    let drain_deadline = Duration::from_secs(config.shutdown.drain_seconds);
+    let drained = timeout(drain_deadline, async {
+        for handle in &mut handles {
+            let _ = handle.await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "In-flight probes did not finish within {}s, abandoning them",
+            config.shutdown.drain_seconds
+        );
+        handles.retain(|handle| !handle.is_finished());
+        for handle in &handles {
+            handle.abort();
+        }
+        for handle in handles {
+            let _ = handle.await;
+        }
     }
 
     drop(tx);
-    let _ = stats_handle.await;
+    let progress = match stats_handle.await {
+        Ok(progress) => progress,
+        Err(e) => {
+            error!("Stats collector failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut ranges: Vec<RangeProgress> = in_progress
+        .lock()
+        .unwrap()
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+    ranges.append(&mut resume.lock().unwrap());
+    match Checkpoint::new(&progress, ranges).save(&checkpoint_path) {
+        Ok(()) => info!("[SUMMARY] Checkpoint written to {}", checkpoint_path),
+        Err(e) => error!("Could not write checkpoint {}: {}", checkpoint_path, e),
+    }
+
+    let flush_deadline = Duration::from_secs(config.shutdown.flush_seconds);
+    if !notifier::drain(notifications_handle, flush_deadline).await {
+        match &queue {
+            Some(queue) => {
+                let pending = queue.pending().map(|p| p.len()).unwrap_or_default()
+                    + queue.pending_servers().map(|p| p.len()).unwrap_or_default();
+                warn!(
+                    "{} notifications still pending after {}s; they stay queued for `queue replay` and the next scan",
+                    pending, config.shutdown.flush_seconds
+                );
+            }
+            None => warn!(
+                "{} notifications still pending after {}s are lost, there is no notification queue",
+                METRICS.notification_queue_depth.get().max(0),
+                config.shutdown.flush_seconds
+            ),
+        }
+    }
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,59 @@
+//! SIGINT/SIGTERM handling: the first signal asks for a graceful stop, the second exits at once
+
+use log::{error, warn};
+use tokio::sync::watch;
+
+/// Cheap to clone; every clone sees the same signal
+#[derive(Clone)]
+pub struct Shutdown {
+    triggered: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Starts listening for signals
+    pub fn listen() -> Self {
+        let (sender, triggered) = watch::channel(false);
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            warn!("Shutting down: finishing in-flight probes, send the signal again to force exit");
+            let _ = sender.send(true);
+
+            wait_for_signal().await;
+            error!("Second signal received, exiting immediately");
+            std::process::exit(130);
+        });
+        Self { triggered }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    /// Resolves once the first signal arrived
+    pub async fn triggered(&mut self) {
+        let _ = self.triggered.wait_for(|triggered| *triggered).await;
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Could not listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,165 @@
+//! Slack incoming webhooks with Block Kit formatted announcements
+
+use crate::config::{NotifierConfig, RouteRule};
+use crate::discord::MinecraftServer;
+use crate::notifier::{
+    Notifier, OutboundRequest, ServerCard, digest_line, pack_lines, progress_fields,
+    truncate_at_char_boundary,
+};
+use crate::stats::ScanProgress;
+use crate::template::RenderError;
+use serde_json::{Value, json};
+use std::error::Error;
+
+/// Slack allows one message per second per incoming webhook
+const DEFAULT_MIN_INTERVAL_MS: u64 = 1000;
+
+/// Block Kit limits
+const MAX_SECTION_FIELDS: usize = 10;
+const MAX_FIELD_LEN: usize = 2000;
+const MAX_TEXT_LEN: usize = 3000;
+
+pub struct SlackWebhook {
+    url: String,
+    min_interval_ms: u64,
+}
+
+impl SlackWebhook {
+    pub fn new(config: &NotifierConfig) -> Result<Self, Box<dyn Error>> {
+        if config.url.is_empty() {
+            return Err("url is required".into());
+        }
+        Ok(Self {
+            url: config.url.clone(),
+            min_interval_ms: config.min_interval_ms.unwrap_or(DEFAULT_MIN_INTERVAL_MS),
+        })
+    }
+
+    fn request(&self, payload: Value) -> OutboundRequest {
+        OutboundRequest {
+            min_interval_ms: self.min_interval_ms,
+            ..OutboundRequest::json(&self.url, payload)
+        }
+    }
+}
+
+impl Notifier for SlackWebhook {
+    fn server_found(
+        &self,
+        server: &MinecraftServer,
+        _route: Option<&RouteRule>,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let card = ServerCard::new(server);
+        let mut blocks = vec![
+            json!({
+                "type": "header",
+                "text": { "type": "plain_text", "text": format!("🎮 {}", card.title) }
+            }),
+            json!({
+                "type": "section",
+                "fields": fields_block(&card.fields),
+            }),
+        ];
+        if !server.description.trim().is_empty() {
+            // Code blocks keep MOTD art aligned and stop mrkdwn interpreting it
+            let motd = escape_mrkdwn(&server.description.replace("```", "'''"));
+            blocks.push(json!({
+                "type": "section",
+                "text": {
+                    "type": "mrkdwn",
+                    "text": format!("```{}```", truncate_at_char_boundary(&motd, MAX_TEXT_LEN - 6))
+                }
+            }));
+        }
+        blocks.push(footer());
+
+        Ok(vec![self.request(json!({
+            // Shown in notifications, where blocks are not rendered
+            "text": format!("{}: {}:{}", card.title, server.ip, server.port),
+            "blocks": blocks,
+        }))])
+    }
+
+    fn digest(
+        &self,
+        servers: &[MinecraftServer],
+        window_seconds: u64,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let title = format!("Servers found ({})", servers.len());
+        let lines: Vec<String> = servers
+            .iter()
+            .map(|server| format!("• {}", escape_mrkdwn(&digest_line(server))))
+            .collect();
+
+        Ok(pack_lines(&lines, MAX_TEXT_LEN)
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut blocks = Vec::new();
+                if i == 0 {
+                    blocks.push(json!({
+                        "type": "header",
+                        "text": { "type": "plain_text", "text": title }
+                    }));
+                    blocks.push(json!({
+                        "type": "context",
+                        "elements": [{
+                            "type": "mrkdwn",
+                            "text": format!("{} responsive servers in the last {}s", servers.len(), window_seconds)
+                        }]
+                    }));
+                }
+                blocks.push(json!({
+                    "type": "section",
+                    "text": { "type": "mrkdwn", "text": chunk }
+                }));
+                self.request(json!({ "text": title, "blocks": blocks }))
+            })
+            .collect())
+    }
+
+    fn progress(&self, progress: &ScanProgress) -> Result<Vec<OutboundRequest>, RenderError> {
+        Ok(vec![self.request(json!({
+            "text": "Scan progress",
+            "blocks": [
+                {
+                    "type": "header",
+                    "text": { "type": "plain_text", "text": "📊 Scan Progress" }
+                },
+                {
+                    "type": "section",
+                    "fields": fields_block(&progress_fields(progress)),
+                },
+                footer()
+            ]
+        }))])
+    }
+}
+
+fn fields_block(fields: &[(&str, String)]) -> Vec<Value> {
+    fields
+        .iter()
+        .take(MAX_SECTION_FIELDS)
+        .map(|(name, value)| {
+            let text = format!("*{}*\n{}", name, escape_mrkdwn(value));
+            json!({
+                "type": "mrkdwn",
+                "text": truncate_at_char_boundary(&text, MAX_FIELD_LEN)
+            })
+        })
+        .collect()
+}
+
+fn footer() -> Value {
+    json!({
+        "type": "context",
+        "elements": [{ "type": "mrkdwn", "text": "Minecraft Port Scanner" }]
+    })
+}
+
+/// Slack only requires the three control characters to be escaped
+fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -1,12 +1,118 @@
-use crate::discord::{DiscordNotifier, extract_server_info_with_country};
+use crate::config::FiltersConfig;
+use crate::discord::MinecraftServer;
+use crate::export::ResultsExporter;
+use crate::histogram::LatencyHistogram;
+use crate::metrics::METRICS;
+use crate::minecraft::PingError;
+use crate::notifier::NotificationDispatcher;
+use crate::timeouts::AdaptiveTimeouts;
 use log::info;
-use tokio::time::Instant;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum ScanMessage {
     Scanned(u64),
     OpenPort(String),
-    Found(String),
+    Found(Box<MinecraftServer>),
+    /// Failures of one chunk, sent alongside its `Scanned`
+    Failed(FailureCounts),
+    Timings(ProbeTimings),
+    Retried(RetryCounts),
+}
+
+/// Probes that took more than one attempt, by stage, and how many of those succeeded
+#[derive(Debug, Default)]
+pub struct RetryCounts(BTreeMap<ProbeStage, (u64, u64)>);
+
+impl RetryCounts {
+    pub fn record(&mut self, stage: ProbeStage, attempts: u32, succeeded: bool) {
+        if attempts > 1 {
+            let (retried, recovered) = self.0.entry(stage).or_default();
+            *retried += 1;
+            if succeeded {
+                *recovered += 1;
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Raw timing samples from one chunk
+#[derive(Debug, Default)]
+pub struct ProbeTimings {
+    /// TCP connect time of open port checks
+    pub connect: Vec<Duration>,
+    /// Status request to first response byte
+    pub first_byte: Vec<Duration>,
+    /// Status request to parsed response
+    pub status: Vec<Duration>,
+    /// Port check plus status ping, per server that answered
+    pub probe: Vec<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProbeStage {
+    PortCheck,
+    Status,
+}
+
+impl ProbeStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProbeStage::PortCheck => "port_check",
+            ProbeStage::Status => "status",
+        }
+    }
+}
+
+/// Probe failures by stage and `PingError::kind`
+#[derive(Debug, Default)]
+pub struct FailureCounts(BTreeMap<(ProbeStage, &'static str), u64>);
+
+impl FailureCounts {
+    pub fn record(&mut self, stage: ProbeStage, error: &PingError) {
+        *self.0.entry((stage, error.kind())).or_default() += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `connect_timeout 1200, refused 30`, most frequent first
+    fn summary(&self, stage: ProbeStage) -> Option<String> {
+        let mut kinds: Vec<(&str, u64)> = self
+            .0
+            .iter()
+            .filter(|((s, _), _)| *s == stage)
+            .map(|((_, kind), count)| (*kind, *count))
+            .collect();
+        if kinds.is_empty() {
+            return None;
+        }
+        kinds.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        Some(
+            kinds
+                .iter()
+                .map(|(kind, count)| format!("{} {}", kind, count))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+}
+
+/// Snapshot of the scan counters for progress summaries
+#[derive(Debug, Clone)]
+pub struct ScanProgress {
+    pub scanned_total: u64,
+    pub ports_open: u64,
+    pub servers_found: u64,
+    pub scans_per_minute: f64,
+    pub runtime: Duration,
 }
 
 pub struct StatsCollector {
@@ -14,11 +120,20 @@ pub struct StatsCollector {
     scanned_total: u64,
     servers_found: u64,
     ports_open: u64,
+    failures: FailureCounts,
+    retries: RetryCounts,
+    connect_times: LatencyHistogram,
+    first_byte_times: LatencyHistogram,
+    status_times: LatencyHistogram,
+    probe_times: LatencyHistogram,
     scanned_last: u64,
     servers_last: u64,
     ports_last: u64,
     last_report_time: Instant,
-    discord: Option<DiscordNotifier>,
+    notifications: Option<NotificationDispatcher>,
+    exporter: Option<ResultsExporter>,
+    filters: FiltersConfig,
+    timeouts: Option<Arc<AdaptiveTimeouts>>,
 }
 
 impl StatsCollector {
@@ -29,39 +144,109 @@ impl StatsCollector {
             scanned_total: 0,
             servers_found: 0,
             ports_open: 0,
+            failures: FailureCounts::default(),
+            retries: RetryCounts::default(),
+            connect_times: LatencyHistogram::new(),
+            first_byte_times: LatencyHistogram::new(),
+            status_times: LatencyHistogram::new(),
+            probe_times: LatencyHistogram::new(),
             scanned_last: 0,
             servers_last: 0,
             ports_last: 0,
             last_report_time: now,
-            discord: None,
+            notifications: None,
+            exporter: None,
+            filters: FiltersConfig::default(),
+            timeouts: None,
         }
     }
 
-    /// Adds Discord notifications to the stats collector
-    pub fn with_discord(mut self, discord_notifier: DiscordNotifier) -> Self {
-        self.discord = Some(discord_notifier);
+    /// Adds found-server and progress notifications to the stats collector
+    pub fn with_notifications(mut self, dispatcher: NotificationDispatcher) -> Self {
+        self.notifications = Some(dispatcher);
+        self
+    }
+
+    /// Writes found servers to the results export
+    pub fn with_exporter(mut self, exporter: ResultsExporter) -> Self {
+        self.exporter = Some(exporter);
         self
     }
 
-    /// Updates counters based on scan results and notifies Discord
+    /// Restricts exports and notifications to servers matching the filters
+    pub fn with_filters(mut self, filters: FiltersConfig) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Swaps in reloaded filters for servers found from now on
+    pub fn set_filters(&mut self, filters: FiltersConfig) {
+        self.filters = filters;
+    }
+
+    /// Reports the timeouts in effect when they adapt to observed latency
+    pub fn with_timeouts(mut self, timeouts: Arc<AdaptiveTimeouts>) -> Self {
+        self.timeouts = Some(timeouts);
+        self
+    }
+
+    /// Updates counters based on scan results and sends notifications
     pub fn update(&mut self, message: ScanMessage) {
         match message {
-            ScanMessage::Scanned(count) => self.scanned_total += count,
-            ScanMessage::OpenPort(_ip) => self.ports_open += 1,
-            ScanMessage::Found(message) => {
+            ScanMessage::Scanned(count) => {
+                self.scanned_total += count;
+                METRICS.targets_scanned.add(count);
+            }
+            ScanMessage::OpenPort(_ip) => {
+                self.ports_open += 1;
+                METRICS.open_ports.inc();
+            }
+            ScanMessage::Failed(failures) => {
+                for ((stage, kind), count) in failures.0 {
+                    METRICS.probe_errors.add(&[stage.name(), kind], count);
+                    *self.failures.0.entry((stage, kind)).or_default() += count;
+                }
+            }
+            ScanMessage::Retried(retries) => {
+                for (stage, (retried, recovered)) in retries.0 {
+                    METRICS
+                        .probe_retries
+                        .add(&[stage.name(), "recovered"], recovered);
+                    METRICS
+                        .probe_retries
+                        .add(&[stage.name(), "failed"], retried - recovered);
+                    let (total_retried, total_recovered) = self.retries.0.entry(stage).or_default();
+                    *total_retried += retried;
+                    *total_recovered += recovered;
+                }
+            }
+            ScanMessage::Timings(timings) => {
+                for (samples, histogram) in [
+                    (timings.connect, &mut self.connect_times),
+                    (timings.first_byte, &mut self.first_byte_times),
+                    (timings.status, &mut self.status_times),
+                    (timings.probe, &mut self.probe_times),
+                ] {
+                    for sample in samples {
+                        histogram.record(sample);
+                    }
+                }
+            }
+            ScanMessage::Found(server) => {
                 self.servers_found += 1;
-                info!("{}", message);
-
-                if let Some(discord_notifier) = &self.discord {
-                    let discord_notifier = discord_notifier.clone();
-                    let message_clone = message.clone();
-                    tokio::spawn(async move {
-                        if let Some(server_info) =
-                            extract_server_info_with_country(&message_clone).await
-                        {
-                            discord_notifier.notify_server_found(server_info).await;
-                        }
-                    });
+                METRICS.record_found(&server);
+                info!("[FOUND] {}", server);
+
+                if !self.filters.matches(&server) {
+                    return;
+                }
+
+                if let Some(exporter) = &mut self.exporter {
+                    exporter.write(&server);
+                }
+
+                if let Some(notifications) = &self.notifications {
+                    notifications.notify_server_found(*server);
                 }
             }
         }
@@ -115,6 +300,7 @@ impl StatsCollector {
             recent_rate,
             runtime.as_secs_f64() / 60.0
         );
+        self.report_details();
 
         if server_delta > 0 || port_delta > 0 {
             info!(
@@ -123,9 +309,114 @@ impl StatsCollector {
             );
         }
 
+        if let Some(notifications) = &self.notifications {
+            notifications.notify_progress(self.progress());
+        }
+
         self.scanned_last = self.scanned_total;
         self.servers_last = self.servers_found;
         self.ports_last = self.ports_open;
         self.last_report_time = Instant::now();
     }
+
+    pub fn progress(&self) -> ScanProgress {
+        let runtime = self.start_time.elapsed();
+        ScanProgress {
+            scanned_total: self.scanned_total,
+            ports_open: self.ports_open,
+            servers_found: self.servers_found,
+            scans_per_minute: if runtime.as_secs() > 0 {
+                (self.scanned_total as f64 * 60.0) / runtime.as_secs() as f64
+            } else {
+                0.0
+            },
+            runtime,
+        }
+    }
+
+    /// Flushes the export and logs the final report once the scan stopped
+    pub fn finish(&mut self) -> ScanProgress {
+        if let Some(exporter) = &mut self.exporter {
+            exporter.flush();
+        }
+
+        let progress = self.progress();
+        info!(
+            "[SUMMARY] {} IPs scanned, {} open ports, {} MC servers in {:.1}s ({:.1} scans/min)",
+            progress.scanned_total,
+            progress.ports_open,
+            progress.servers_found,
+            progress.runtime.as_secs_f64(),
+            progress.scans_per_minute
+        );
+        self.report_details();
+        progress
+    }
+
+    fn timings(&self) -> [(&'static str, &LatencyHistogram); 4] {
+        [
+            ("connect", &self.connect_times),
+            ("first_byte", &self.first_byte_times),
+            ("status", &self.status_times),
+            ("probe", &self.probe_times),
+        ]
+    }
+
+    /// Failure, retry, timing and timeout breakdowns, with the timings also exported
+    fn report_details(&self) {
+        if let Some(exporter) = &self.exporter {
+            exporter.write_timings(&self.timings());
+        }
+
+        let failures: Vec<String> = [ProbeStage::PortCheck, ProbeStage::Status]
+            .into_iter()
+            .filter_map(|stage| {
+                self.failures
+                    .summary(stage)
+                    .map(|summary| format!("{}: {}", stage.name(), summary))
+            })
+            .collect();
+        if !failures.is_empty() {
+            info!("[STATS] Failures | {}", failures.join(" | "));
+        }
+
+        if !self.retries.is_empty() {
+            let retries: Vec<String> = self
+                .retries
+                .0
+                .iter()
+                .map(|(stage, (retried, recovered))| {
+                    format!(
+                        "{}: {} retried, {} recovered ({:.1}%)",
+                        stage.name(),
+                        retried,
+                        recovered,
+                        *recovered as f64 * 100.0 / *retried as f64
+                    )
+                })
+                .collect();
+            info!("[STATS] Retries | {}", retries.join(" | "));
+        }
+
+        let timings: Vec<String> = self
+            .timings()
+            .into_iter()
+            .filter(|(_, histogram)| !histogram.is_empty())
+            .map(|(name, histogram)| format!("{} {}", name.replace('_', " "), histogram.summary()))
+            .collect();
+        if !timings.is_empty() {
+            info!("[STATS] Timings p50/p95/p99 ms | {}", timings.join(" | "));
+        }
+
+        if let Some(timeouts) = self.timeouts.as_ref().filter(|timeouts| timeouts.enabled()) {
+            let current = timeouts.current();
+            info!(
+                "[STATS] Timeouts | port check {} ms, connect {} ms, status {} ms ({} networks tuned separately)",
+                current.port_check_ms,
+                current.connection_ms,
+                current.protocol_response_ms,
+                timeouts.tuned_networks()
+            );
+        }
+    }
 }
@@ -0,0 +1,122 @@
+//! Telegram chats, posted to with the Bot API `sendMessage` method
+
+use crate::chat::{HtmlDialect, escape_html};
+use crate::config::{NotifierConfig, RouteRule};
+use crate::discord::MinecraftServer;
+use crate::notifier::{
+    Notifier, OutboundRequest, ServerCard, digest_line, pack_lines, progress_fields,
+};
+use crate::stats::ScanProgress;
+use crate::template::RenderError;
+use serde_json::json;
+use std::error::Error;
+
+const DEFAULT_API_URL: &str = "https://api.telegram.org";
+
+/// Telegram allows about one message per second per chat (20 per minute in groups)
+const DEFAULT_MIN_INTERVAL_MS: u64 = 1000;
+
+/// Message text limit after entity parsing; escaped markup counts against the raw request
+const MAX_MESSAGE_LEN: usize = 4096;
+
+pub struct TelegramChat {
+    send_url: String,
+    chat_id: String,
+    min_interval_ms: u64,
+}
+
+impl TelegramChat {
+    pub fn new(config: &NotifierConfig) -> Result<Self, Box<dyn Error>> {
+        let bot_token = config.bot_token.as_deref().ok_or("bot_token is required")?;
+        let chat_id = config.chat_id.clone().ok_or("chat_id is required")?;
+        let api_url = if config.url.is_empty() {
+            DEFAULT_API_URL
+        } else {
+            config.url.trim_end_matches('/')
+        };
+
+        Ok(Self {
+            send_url: format!("{}/bot{}/sendMessage", api_url, bot_token),
+            chat_id,
+            min_interval_ms: config.min_interval_ms.unwrap_or(DEFAULT_MIN_INTERVAL_MS),
+        })
+    }
+
+    fn request(&self, text: String) -> OutboundRequest {
+        OutboundRequest {
+            min_interval_ms: self.min_interval_ms,
+            ..OutboundRequest::json(
+                &self.send_url,
+                json!({
+                    "chat_id": self.chat_id,
+                    "text": text,
+                    "parse_mode": "HTML",
+                    "link_preview_options": { "is_disabled": true },
+                }),
+            )
+        }
+    }
+}
+
+impl Notifier for TelegramChat {
+    fn server_found(
+        &self,
+        server: &MinecraftServer,
+        _route: Option<&RouteRule>,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let card = ServerCard::new(server);
+        let mut text = format!("<b>🎮 {}</b>", card.title);
+        for (name, value) in &card.fields {
+            let value = escape_html(value);
+            if *name == "Address" {
+                text.push_str(&format!("\n<b>{}:</b> <code>{}</code>", name, value));
+            } else {
+                text.push_str(&format!("\n<b>{}:</b> {}", name, value));
+            }
+        }
+
+        if !server.description.trim().is_empty() {
+            let motd = server.motd.to_tagged_html(HtmlDialect::Telegram);
+            // Cutting tagged HTML could leave a tag open, so long MOTDs fall back to plain text
+            let motd = if text.len() + motd.len() + 32 > MAX_MESSAGE_LEN {
+                let room = MAX_MESSAGE_LEN.saturating_sub(text.len() + 32);
+                let plain: String = server.description.chars().take(room / 6).collect();
+                escape_html(&plain)
+            } else {
+                motd
+            };
+            text.push_str(&format!("\n\n<blockquote>{}</blockquote>", motd));
+        }
+
+        Ok(vec![self.request(text)])
+    }
+
+    fn digest(
+        &self,
+        servers: &[MinecraftServer],
+        window_seconds: u64,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let title = format!(
+            "<b>Servers found ({})</b> in the last {}s",
+            servers.len(),
+            window_seconds
+        );
+        let lines: Vec<String> = servers
+            .iter()
+            .map(|server| format!("• {}", escape_html(&digest_line(server))))
+            .collect();
+
+        Ok(pack_lines(&lines, MAX_MESSAGE_LEN - title.len() - 1)
+            .into_iter()
+            .map(|chunk| self.request(format!("{}\n{}", title, chunk)))
+            .collect())
+    }
+
+    fn progress(&self, progress: &ScanProgress) -> Result<Vec<OutboundRequest>, RenderError> {
+        let mut text = "<b>📊 Scan Progress</b>".to_string();
+        for (name, value) in progress_fields(progress) {
+            text.push_str(&format!("\n<b>{}:</b> {}", name, escape_html(&value)));
+        }
+        Ok(vec![self.request(text)])
+    }
+}
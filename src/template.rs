@@ -0,0 +1,120 @@
+//! Handlebars templates rendered over found-server records
+
+use crate::config::TemplateEscape;
+use crate::discord::MinecraftServer;
+use crate::stats::ScanProgress;
+use handlebars::{Handlebars, handlebars_helper};
+use serde_json::{Value, json};
+
+pub use handlebars::{RenderError, TemplateError};
+
+// `{{{json mods}}}` embeds any value as JSON
+handlebars_helper!(json_helper: |value: Json| serde_json::to_string(value).unwrap_or_default());
+
+pub struct Templates {
+    registry: Handlebars<'static>,
+}
+
+impl Templates {
+    pub fn new(escape: TemplateEscape) -> Self {
+        let mut registry = Handlebars::new();
+        registry.register_helper("json", Box::new(json_helper));
+        match escape {
+            TemplateEscape::Json => registry.register_escape_fn(escape_json),
+            TemplateEscape::Html => registry.register_escape_fn(handlebars::html_escape),
+            TemplateEscape::None => registry.register_escape_fn(handlebars::no_escape),
+        }
+        Self { registry }
+    }
+
+    pub fn register(&mut self, name: &str, source: &str) -> Result<(), TemplateError> {
+        self.registry.register_template_string(name, source)
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.registry.has_template(name)
+    }
+
+    pub fn render(&self, name: &str, context: &Value) -> Result<String, RenderError> {
+        self.registry.render(name, context)
+    }
+}
+
+/// Escapes a value for use inside a JSON string literal
+fn escape_json(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// The exported server record plus convenience fields for templates
+pub fn server_context(server: &MinecraftServer) -> Value {
+    let mut context = serde_json::to_value(server).unwrap_or_default();
+    context["address"] = json!(format!("{}:{}", server.ip, server.port));
+    context["game_version"] = json!(server.game_version());
+    context["active"] = json!(server.players_online > 0);
+    context["motd_markdown"] = json!(server.motd.to_markdown());
+    context["motd_html"] = json!(server.motd.to_html());
+    context["found_at"] = json!(chrono::Utc::now().to_rfc3339());
+    context
+}
+
+pub fn digest_context(servers: &[MinecraftServer], window_seconds: u64) -> Value {
+    json!({
+        "count": servers.len(),
+        "window_seconds": window_seconds,
+        "servers": servers.iter().map(server_context).collect::<Vec<_>>(),
+    })
+}
+
+pub fn progress_context(progress: &ScanProgress) -> Value {
+    json!({
+        "scanned_total": progress.scanned_total,
+        "ports_open": progress.ports_open,
+        "servers_found": progress.servers_found,
+        "scans_per_minute": progress.scans_per_minute,
+        "runtime_seconds": progress.runtime.as_secs(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(escape: TemplateEscape, source: &str, context: &Value) -> String {
+        let mut templates = Templates::new(escape);
+        templates.register("test", source).unwrap();
+        templates.render("test", context).unwrap()
+    }
+
+    #[test]
+    fn json_escaping_keeps_hostile_text_inside_the_string() {
+        let hostile = "\"}, \"injected\": true, \"x\": \"\\\n\u{1b}[2J</script>";
+        let body = render(
+            TemplateEscape::Json,
+            r#"{"content": "{{motd}}"}"#,
+            &json!({ "motd": hostile }),
+        );
+        let parsed: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed, json!({ "content": hostile }));
+    }
+
+    #[test]
+    fn html_escaping_neutralizes_markup() {
+        let body = render(
+            TemplateEscape::Html,
+            "<p>{{motd}}</p>",
+            &json!({ "motd": "<img src=x onerror=alert(1)>&" }),
+        );
+        assert_eq!(
+            body,
+            "<p>&lt;img src&#x3D;x onerror&#x3D;alert(1)&gt;&amp;</p>"
+        );
+    }
+
+    #[test]
+    fn json_helper_embeds_values_and_templates_are_not_reevaluated() {
+        let context = json!({ "mods": ["a", "b\""], "name": "{{mods}}" });
+        let body = render(TemplateEscape::None, "{{{json mods}}} {{name}}", &context);
+        assert_eq!(body, r#"["a","b\""] {{mods}}"#);
+    }
+}
@@ -0,0 +1,352 @@
+//! Probe timeouts, optionally tuned from the observed latency distribution
+
+use crate::config::{AdaptiveTimeoutsConfig, TimeoutsConfig};
+use lru::LruCache;
+use std::net::Ipv4Addr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Recent samples kept for the whole scan and for each network
+const GLOBAL_WINDOW: usize = 4096;
+const NETWORK_WINDOW: usize = 128;
+
+/// Beyond this, the least recently tuned network is forgotten
+const MAX_NETWORKS: NonZeroUsize = NonZeroUsize::new(4096).unwrap();
+
+/// New samples between recomputing a window's timeout
+const RECOMPUTE_EVERY: u32 = 16;
+
+/// Timeouts in effect for one target
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub port_check_ms: u64,
+    pub connection_ms: u64,
+    pub protocol_response_ms: u64,
+}
+
+/// Ring buffer of the most recent samples, in microseconds
+struct SampleWindow {
+    samples: Vec<u64>,
+    capacity: usize,
+    next: usize,
+    since_update: u32,
+    /// Effective timeout once enough samples were seen
+    timeout_ms: Option<u64>,
+}
+
+impl SampleWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            samples: Vec::new(),
+            capacity,
+            next: 0,
+            since_update: 0,
+            timeout_ms: None,
+        }
+    }
+
+    fn record(&mut self, sample: Duration, config: &AdaptiveTimeoutsConfig) {
+        let micros = sample.as_micros() as u64;
+        if self.samples.len() < self.capacity {
+            self.samples.push(micros);
+        } else {
+            self.samples[self.next] = micros;
+        }
+        self.next = (self.next + 1) % self.capacity;
+        self.since_update += 1;
+
+        if self.samples.len() >= config.min_samples.max(1)
+            && (self.timeout_ms.is_none() || self.since_update >= RECOMPUTE_EVERY)
+        {
+            self.since_update = 0;
+            let mut sorted = self.samples.clone();
+            sorted.sort_unstable();
+            let rank = (config.percentile.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize;
+            let quantile_ms = sorted[rank.clamp(1, sorted.len()) - 1] as f64 / 1000.0;
+            self.timeout_ms = Some(
+                ((quantile_ms * config.headroom).ceil() as u64)
+                    .clamp(config.floor_ms, config.ceiling_ms.max(config.floor_ms)),
+            );
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Sample {
+    Connect,
+    Response,
+}
+
+struct Windows {
+    connect: SampleWindow,
+    response: SampleWindow,
+}
+
+impl Windows {
+    fn new(capacity: usize) -> Self {
+        Self {
+            connect: SampleWindow::new(capacity),
+            response: SampleWindow::new(capacity),
+        }
+    }
+
+    fn get(&mut self, sample: Sample) -> &mut SampleWindow {
+        match sample {
+            Sample::Connect => &mut self.connect,
+            Sample::Response => &mut self.response,
+        }
+    }
+}
+
+struct State {
+    config: AdaptiveTimeoutsConfig,
+    base: Timeouts,
+    global: Windows,
+    /// Per network, ordered by when each last received a sample
+    networks: LruCache<u32, Windows>,
+}
+
+pub struct AdaptiveTimeouts {
+    state: Mutex<State>,
+}
+
+impl AdaptiveTimeouts {
+    pub fn new(config: &TimeoutsConfig) -> Self {
+        Self {
+            state: Mutex::new(State {
+                config: config.adaptive.clone(),
+                base: base_timeouts(config),
+                global: Windows::new(GLOBAL_WINDOW),
+                networks: LruCache::new(MAX_NETWORKS),
+            }),
+        }
+    }
+
+    /// Applies a reloaded `[timeouts]`. Learned timeouts are kept unless the network
+    /// prefix changed, which makes the per-network ones meaningless.
+    pub fn reconfigure(&self, config: &TimeoutsConfig) {
+        let mut state = self.state.lock().unwrap();
+        if state.config.network_prefix != config.adaptive.network_prefix {
+            state.networks.clear();
+        }
+        state.config = config.adaptive.clone();
+        state.base = base_timeouts(config);
+    }
+
+    /// Network's own timeouts once tuned, else the global ones, else the configured ones
+    pub fn for_target(&self, ip: Ipv4Addr) -> Timeouts {
+        let state = self.state.lock().unwrap();
+        if !state.config.enabled {
+            return state.base;
+        }
+        let network = state.networks.peek(&state.network(ip));
+        let connect_ms = network
+            .and_then(|windows| windows.connect.timeout_ms)
+            .or(state.global.connect.timeout_ms);
+        let response_ms = network
+            .and_then(|windows| windows.response.timeout_ms)
+            .or(state.global.response.timeout_ms);
+        state.effective(connect_ms, response_ms)
+    }
+
+    /// Global timeouts currently in effect, for stats
+    pub fn current(&self) -> Timeouts {
+        let state = self.state.lock().unwrap();
+        if !state.config.enabled {
+            return state.base;
+        }
+        state.effective(
+            state.global.connect.timeout_ms,
+            state.global.response.timeout_ms,
+        )
+    }
+
+    /// Networks with a tuned timeout of their own
+    pub fn tuned_networks(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .networks
+            .iter()
+            .filter(|(_, windows)| {
+                windows.connect.timeout_ms.is_some() || windows.response.timeout_ms.is_some()
+            })
+            .count()
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.state.lock().unwrap().config.enabled
+    }
+
+    /// Time to establish a TCP connection
+    pub fn record_connect(&self, ip: Ipv4Addr, sample: Duration) {
+        self.record(ip, Sample::Connect, sample);
+    }
+
+    /// Time from the status request to the response
+    pub fn record_response(&self, ip: Ipv4Addr, sample: Duration) {
+        self.record(ip, Sample::Response, sample);
+    }
+
+    fn record(&self, ip: Ipv4Addr, kind: Sample, sample: Duration) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if !state.config.enabled {
+            return;
+        }
+        let network = state.network(ip);
+
+        state.global.get(kind).record(sample, &state.config);
+        state
+            .networks
+            .get_or_insert_mut(network, || Windows::new(NETWORK_WINDOW))
+            .get(kind)
+            .record(sample, &state.config);
+    }
+}
+
+fn base_timeouts(config: &TimeoutsConfig) -> Timeouts {
+    Timeouts {
+        port_check_ms: config.port_check_ms,
+        connection_ms: config.connection_ms,
+        protocol_response_ms: config.protocol_response_ms,
+    }
+}
+
+impl State {
+    fn network(&self, ip: Ipv4Addr) -> u32 {
+        let prefix = self.config.network_prefix.min(32) as u32;
+        u32::from(ip).checked_shr(32 - prefix).unwrap_or(0)
+    }
+
+    fn effective(&self, connect_ms: Option<u64>, response_ms: Option<u64>) -> Timeouts {
+        Timeouts {
+            port_check_ms: connect_ms.unwrap_or(self.base.port_check_ms),
+            connection_ms: connect_ms.unwrap_or(self.base.connection_ms),
+            protocol_response_ms: response_ms.unwrap_or(self.base.protocol_response_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> TimeoutsConfig {
+        TimeoutsConfig {
+            adaptive: AdaptiveTimeoutsConfig {
+                enabled: true,
+                percentile: 0.9,
+                headroom: 2.0,
+                min_samples: 10,
+                network_prefix: 24,
+                floor_ms: 100,
+                ceiling_ms: 5000,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// First address of the n-th /24
+    fn network(n: u32) -> Ipv4Addr {
+        Ipv4Addr::from((n << 8) | 1)
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn percentile_times_headroom_once_enough_samples() {
+        let timeouts = AdaptiveTimeouts::new(&config());
+        for i in 1..10 {
+            timeouts.record_connect(network(1), ms(i * 10));
+        }
+        assert_eq!(timeouts.for_target(network(1)).connection_ms, 3000);
+
+        timeouts.record_connect(network(1), ms(100));
+        // 90th percentile of 10..=100 is 90, doubled
+        let tuned = timeouts.for_target(network(1));
+        assert_eq!(tuned.connection_ms, 180);
+        assert_eq!(tuned.port_check_ms, 180);
+        assert_eq!(tuned.protocol_response_ms, 5000);
+        // Other networks fall back to the global window, fed by the same samples
+        assert_eq!(timeouts.for_target(network(2)).connection_ms, 180);
+        assert_eq!(timeouts.tuned_networks(), 1);
+    }
+
+    #[test]
+    fn clamps_to_floor_and_ceiling() {
+        let timeouts = AdaptiveTimeouts::new(&config());
+        for _ in 0..10 {
+            timeouts.record_connect(network(1), ms(1));
+            timeouts.record_response(network(1), ms(20_000));
+        }
+        let tuned = timeouts.for_target(network(1));
+        assert_eq!(tuned.connection_ms, 100);
+        assert_eq!(tuned.protocol_response_ms, 5000);
+    }
+
+    #[test]
+    fn timeouts_recorded_at_the_limit_widen_it() {
+        let timeouts = AdaptiveTimeouts::new(&config());
+        for i in 1..=10 {
+            timeouts.record_connect(network(1), ms(i * 10));
+        }
+        let limit = timeouts.for_target(network(1)).connection_ms;
+        assert_eq!(limit, 180);
+
+        for _ in 0..RECOMPUTE_EVERY {
+            timeouts.record_connect(network(1), ms(limit));
+        }
+        assert_eq!(timeouts.for_target(network(1)).connection_ms, 360);
+    }
+
+    #[test]
+    fn least_recently_sampled_network_is_evicted() {
+        let mut config = config();
+        config.adaptive.min_samples = 1;
+        let timeouts = AdaptiveTimeouts::new(&config);
+        timeouts.record_connect(network(0), ms(1000));
+        timeouts.record_connect(network(1), ms(500));
+        for n in 2..MAX_NETWORKS.get() as u32 {
+            timeouts.record_connect(network(n), ms(10));
+        }
+        // Sampling network 0 again makes network 1 the stalest
+        timeouts.record_connect(network(0), ms(1000));
+        assert_eq!(timeouts.for_target(network(1)).connection_ms, 1000);
+
+        timeouts.record_connect(network(MAX_NETWORKS.get() as u32), ms(10));
+        assert_eq!(timeouts.tuned_networks(), MAX_NETWORKS.get());
+        assert_eq!(timeouts.for_target(network(0)).connection_ms, 2000);
+        assert_eq!(
+            timeouts.for_target(network(1)).connection_ms,
+            timeouts.current().connection_ms
+        );
+    }
+
+    #[test]
+    fn reconfigure_keeps_learned_timeouts_unless_the_prefix_changes() {
+        let mut config = config();
+        let timeouts = AdaptiveTimeouts::new(&config);
+        for _ in 0..10 {
+            timeouts.record_connect(network(1), ms(400));
+        }
+        assert_eq!(timeouts.for_target(network(1)).connection_ms, 800);
+
+        config.protocol_response_ms = 7000;
+        timeouts.reconfigure(&config);
+        assert_eq!(timeouts.tuned_networks(), 1);
+        let tuned = timeouts.for_target(network(1));
+        assert_eq!(tuned.connection_ms, 800);
+        assert_eq!(tuned.protocol_response_ms, 7000);
+
+        config.adaptive.network_prefix = 16;
+        timeouts.reconfigure(&config);
+        assert_eq!(timeouts.tuned_networks(), 0);
+
+        config.adaptive.enabled = false;
+        timeouts.reconfigure(&config);
+        assert_eq!(timeouts.for_target(network(1)).connection_ms, 3000);
+    }
+}
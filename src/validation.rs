@@ -0,0 +1,579 @@
+//! Checks on `config.toml` values that parse but would misbehave at runtime
+
+use crate::config::{Config, NotifierConfig, RetryPolicy};
+use crate::minecraft::PING_ERROR_KINDS;
+use crate::notifier::Notifiers;
+use crate::ports::SourceAddresses;
+use crate::privacy::Privacy;
+use crate::protocol::canonical_version;
+use crate::proxy::Proxies;
+use log::{error, info, warn};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Where the kernel picks ports for unbound connections
+const EPHEMERAL_PORT_RANGE: &str = "/proc/sys/net/ipv4/ip_local_port_range";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// The scanner refuses to start
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    /// Dotted path of the offending field, e.g. `routing.rules[2].destinations[0]`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+pub struct Validation {
+    pub issues: Vec<Issue>,
+}
+
+impl Validation {
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == severity)
+            .count()
+    }
+
+    pub fn log(&self) {
+        for issue in &self.issues {
+            match issue.severity {
+                Severity::Error => error!("[CONFIG] {}", issue),
+                Severity::Warning => warn!("[CONFIG] {}", issue),
+            }
+        }
+    }
+
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, path.into(), message.into());
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, path.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, path: String, message: String) {
+        self.issues.push(Issue {
+            severity,
+            path,
+            message,
+        });
+    }
+
+    /// Errors from component constructors, which are already prefixed with their field path
+    fn constructor_error(&mut self, e: impl fmt::Display) {
+        let e = e.to_string();
+        match e.split_once(": ") {
+            Some((path, message)) => self.error(path, message),
+            None => self.error("config", e),
+        }
+    }
+
+    fn positive(&mut self, path: &str, value: u64) {
+        if value == 0 {
+            self.error(path, "must be greater than 0");
+        }
+    }
+
+    /// A `[notifiers]` name or a Discord webhook URL
+    fn destination(
+        &mut self,
+        path: String,
+        destination: &str,
+        notifiers: &BTreeMap<String, NotifierConfig>,
+    ) {
+        if destination.is_empty() {
+            self.error(path, "empty destination");
+        } else if !notifiers.contains_key(destination)
+            && !destination.starts_with("https://")
+            && !destination.starts_with("http://")
+        {
+            self.error(
+                path,
+                format!(
+                    "\"{}\" is neither a [notifiers] name nor a webhook URL",
+                    destination
+                ),
+            );
+        }
+    }
+
+    fn retry(&mut self, path: &str, policy: &RetryPolicy) {
+        if policy.max_attempts == 0 {
+            self.error(
+                format!("{}.max_attempts", path),
+                "must be at least 1 (1 disables retries)",
+            );
+        }
+        if !(0.0..=1.0).contains(&policy.jitter) {
+            self.warning(
+                format!("{}.jitter", path),
+                "outside 0.0-1.0, clamped to that range",
+            );
+        }
+        if policy.backoff_ms > policy.max_backoff_ms {
+            self.warning(
+                format!("{}.backoff_ms", path),
+                format!(
+                    "larger than max_backoff_ms, every retry waits {} ms",
+                    policy.max_backoff_ms
+                ),
+            );
+        }
+        for (i, kind) in policy.retry_on.iter().enumerate() {
+            if !PING_ERROR_KINDS.contains(&kind.as_str()) {
+                self.error(
+                    format!("{}.retry_on[{}]", path, i),
+                    format!(
+                        "unknown failure kind \"{}\", expected one of {}",
+                        kind,
+                        PING_ERROR_KINDS.join(", ")
+                    ),
+                );
+            }
+        }
+    }
+}
+
+pub fn validate(config: &Config) -> Validation {
+    let mut v = Validation::default();
+
+    let scanning = &config.scanning;
+    if scanning.port == 0 {
+        v.error("scanning.port", "must be 1-65535");
+    }
+    v.positive("scanning.num_tasks", scanning.num_tasks as u64);
+    v.positive("scanning.max_range_size", scanning.max_range_size as u64);
+    v.positive(
+        "scanning.consecutive_threshold",
+        scanning.consecutive_threshold as u64,
+    );
+    v.positive("scanning.chunk_size", scanning.chunk_size as u64);
+
+    let timeouts = &config.timeouts;
+    v.positive("timeouts.port_check_ms", timeouts.port_check_ms);
+    v.positive("timeouts.connection_ms", timeouts.connection_ms);
+    v.positive(
+        "timeouts.protocol_response_ms",
+        timeouts.protocol_response_ms,
+    );
+    let adaptive = &timeouts.adaptive;
+    if adaptive.enabled {
+        if !(adaptive.percentile > 0.0 && adaptive.percentile <= 1.0) {
+            v.error("timeouts.adaptive.percentile", "must be within (0.0, 1.0]");
+        }
+        if adaptive.headroom < 1.0 {
+            v.warning(
+                "timeouts.adaptive.headroom",
+                "below 1.0 times out targets slower than the tracked percentile",
+            );
+        }
+        if adaptive.network_prefix > 32 {
+            v.error("timeouts.adaptive.network_prefix", "must be 0-32");
+        }
+        if adaptive.floor_ms > adaptive.ceiling_ms {
+            v.error(
+                "timeouts.adaptive.floor_ms",
+                format!("larger than ceiling_ms ({})", adaptive.ceiling_ms),
+            );
+        }
+    }
+
+    validate_networking(config, &mut v);
+
+    if config.minecraft.protocol_version < 0 {
+        v.error("minecraft.protocol_version", "must not be negative");
+    } else if canonical_version(config.minecraft.protocol_version).is_none() {
+        v.warning(
+            "minecraft.protocol_version",
+            format!(
+                "{} does not match any known release",
+                config.minecraft.protocol_version
+            ),
+        );
+    }
+
+    v.positive(
+        "stats.stats_interval_seconds",
+        config.stats.stats_interval_seconds,
+    );
+
+    validate_destinations(config, &mut v);
+
+    let discord = &config.discord;
+    if discord.digest.enabled {
+        v.positive(
+            "discord.digest.window_seconds",
+            discord.digest.window_seconds,
+        );
+        if discord.digest.max_servers == 0 {
+            v.warning(
+                "discord.digest.max_servers",
+                "0 is treated as 1, sending every server on its own",
+            );
+        }
+    }
+    if discord.digest.progress_interval_seconds > 0 && discord.digest.progress_webhooks.is_empty() {
+        v.warning(
+            "discord.digest.progress_webhooks",
+            "empty, so progress_interval_seconds has no effect",
+        );
+    }
+    if discord.dedup.enabled && discord.dedup.cooldown_seconds == 0 {
+        v.warning(
+            "discord.dedup.cooldown_seconds",
+            "0 announces every sighting again",
+        );
+    }
+
+    for (i, rule) in config.routing.rules.iter().enumerate() {
+        let matcher = &rule.matcher;
+        if let Some(pattern) = &matcher.motd_regex
+            && let Err(e) = Regex::new(pattern)
+        {
+            v.error(
+                format!("routing.rules[{}].match.motd_regex", i),
+                e.to_string(),
+            );
+        }
+        if let (Some(min), Some(max)) = (matcher.protocol_min, matcher.protocol_max)
+            && min > max
+        {
+            v.warning(
+                format!("routing.rules[{}].match.protocol_min", i),
+                "larger than protocol_max, the rule never matches",
+            );
+        }
+        if let (Some(min), Some(max)) = (matcher.min_players, matcher.max_players)
+            && min > max
+        {
+            v.warning(
+                format!("routing.rules[{}].match.min_players", i),
+                "larger than max_players, the rule never matches",
+            );
+        }
+    }
+
+    let privacy = Privacy::new(&config.privacy).unwrap_or_else(|e| {
+        v.constructor_error(e);
+        Privacy::default()
+    });
+    if let Err(e) = Notifiers::new(&config.notifiers, &config.discord.embed, privacy) {
+        v.constructor_error(e);
+    }
+    if let Err(e) = Proxies::new(&config.proxy) {
+        v.constructor_error(e);
+    }
+
+    if config.metrics.enabled
+        && config
+            .metrics
+            .listen
+            .rsplit_once(':')
+            .is_none_or(|(_, port)| port.parse::<u16>().is_err())
+    {
+        v.error("metrics.listen", "expected host:port");
+    }
+
+    v.retry("retry.port_check", &config.retry.port_check);
+    v.retry("retry.status", &config.retry.status);
+
+    v
+}
+
+fn validate_networking(config: &Config, v: &mut Validation) {
+    let networking = &config.networking;
+    if let Err(e) = SourceAddresses::new(&networking.source_addresses) {
+        v.constructor_error(e);
+    }
+
+    let range = networking.port_range_per_task as u64;
+    if range == 0 {
+        return;
+    }
+    let first = networking.base_source_port as u64;
+    let last = first + config.scanning.num_tasks as u64 * range - 1;
+    if last > u16::MAX as u64 {
+        v.error(
+            "networking.port_range_per_task",
+            format!(
+                "{} tasks x {} ports from base_source_port {} end at {}, past 65535",
+                config.scanning.num_tasks, range, first, last
+            ),
+        );
+    }
+    if first < 1024 {
+        v.warning(
+            "networking.base_source_port",
+            "ports below 1024 can only be bound with elevated privileges",
+        );
+    }
+    if range < config.scanning.chunk_size as u64 {
+        v.warning(
+            "networking.port_range_per_task",
+            format!(
+                "fewer ports than scanning.chunk_size ({}), probes beyond that use kernel-assigned ports",
+                config.scanning.chunk_size
+            ),
+        );
+    }
+    if networking.time_wait_seconds == 0 {
+        v.warning(
+            "networking.time_wait_seconds",
+            "0 reuses ports that may still be in TIME_WAIT, failing connects with addr_in_use",
+        );
+    }
+    if let Some((ephemeral_first, ephemeral_last)) = ephemeral_port_range()
+        && first <= ephemeral_last
+        && last >= ephemeral_first
+    {
+        v.warning(
+            "networking.base_source_port",
+            format!(
+                "source ports {}-{} overlap the kernel's ephemeral range {}-{}, so other connections can take them",
+                first,
+                last.min(u16::MAX as u64),
+                ephemeral_first,
+                ephemeral_last
+            ),
+        );
+    }
+}
+
+fn validate_destinations(config: &Config, v: &mut Validation) {
+    let notifiers = &config.notifiers;
+    let routing = &config.routing;
+    let discord = &config.discord;
+
+    let mut any = false;
+    if routing.rules.is_empty() && routing.default.is_empty() {
+        for (field, webhook) in [
+            ("webhook_121_active", &discord.webhook_121_active),
+            ("webhook_120_active", &discord.webhook_120_active),
+            ("webhook_119_active", &discord.webhook_119_active),
+            ("webhook_other_active", &discord.webhook_other_active),
+            ("webhook_121_empty", &discord.webhook_121_empty),
+            ("webhook_120_empty", &discord.webhook_120_empty),
+            ("webhook_119_empty", &discord.webhook_119_empty),
+            ("webhook_other_empty", &discord.webhook_other_empty),
+        ] {
+            // Empty legacy webhooks deliberately drop their servers
+            if !webhook.is_empty() {
+                any = true;
+                v.destination(format!("discord.{}", field), webhook, notifiers);
+            }
+        }
+    } else {
+        for (i, destination) in routing.default.iter().enumerate() {
+            any = true;
+            v.destination(format!("routing.default[{}]", i), destination, notifiers);
+        }
+        for (i, rule) in routing.rules.iter().enumerate() {
+            for (j, destination) in rule.destinations.iter().enumerate() {
+                any = true;
+                v.destination(
+                    format!("routing.rules[{}].destinations[{}]", i, j),
+                    destination,
+                    notifiers,
+                );
+            }
+        }
+    }
+    if !any {
+        v.warning(
+            "routing",
+            "no notification destinations, found servers are only logged and exported",
+        );
+    }
+
+    for (i, destination) in discord.digest.progress_webhooks.iter().enumerate() {
+        v.destination(
+            format!("discord.digest.progress_webhooks[{}]", i),
+            destination,
+            notifiers,
+        );
+    }
+}
+
+/// `(first, last)` from the kernel, where available
+fn ephemeral_port_range() -> Option<(u64, u64)> {
+    let range = std::fs::read_to_string(EPHEMERAL_PORT_RANGE).ok()?;
+    let mut bounds = range.split_whitespace().map(|bound| bound.parse().ok());
+    Some((bounds.next()??, bounds.next()??))
+}
+
+/// Loads `config.toml` and logs every problem found; `None` when the scanner must not start
+pub fn load_checked() -> Option<Config> {
+    let (config, unknown) = match Config::load_with_unknown_keys() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("[CONFIG] Could not load config.toml: {}", e);
+            return None;
+        }
+    };
+    let mut validation = validate(&config);
+    for path in unknown {
+        validation.warning(path, "unknown key, ignored");
+    }
+    validation.log();
+    let warnings = count(validation.warnings(), "warning");
+    if validation.errors() > 0 {
+        error!(
+            "[CONFIG] config.toml has {} and {}",
+            count(validation.errors(), "error"),
+            warnings
+        );
+        return None;
+    }
+    info!("[CONFIG] config.toml is valid ({})", warnings);
+    Some(config)
+}
+
+/// `1 error`, `2 errors`
+fn count(n: usize, noun: &str) -> String {
+    format!("{} {}{}", n, noun, if n == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RedactionProfile;
+
+    fn severity_of(config: &Config, path: &str) -> Option<Severity> {
+        validate(config)
+            .issues
+            .into_iter()
+            .find(|issue| issue.path.starts_with(path))
+            .map(|issue| issue.severity)
+    }
+
+    #[test]
+    fn negative_protocol_version_is_an_error() {
+        let mut config = Config::default();
+        config.minecraft.protocol_version = -1;
+        assert_eq!(
+            severity_of(&config, "minecraft.protocol_version"),
+            Some(Severity::Error)
+        );
+
+        config.minecraft.protocol_version = 765;
+        assert_eq!(severity_of(&config, "minecraft.protocol_version"), None);
+    }
+
+    #[test]
+    fn zero_sizes_are_errors() {
+        let mut config = Config::default();
+        config.scanning.chunk_size = 0;
+        config.scanning.max_range_size = 0;
+        assert_eq!(
+            severity_of(&config, "scanning.chunk_size"),
+            Some(Severity::Error)
+        );
+        assert_eq!(
+            severity_of(&config, "scanning.max_range_size"),
+            Some(Severity::Error)
+        );
+    }
+
+    #[test]
+    fn zero_timeouts_are_errors() {
+        let mut config = Config::default();
+        config.timeouts.port_check_ms = 0;
+        config.timeouts.connection_ms = 0;
+        config.timeouts.protocol_response_ms = 0;
+        for path in [
+            "timeouts.port_check_ms",
+            "timeouts.connection_ms",
+            "timeouts.protocol_response_ms",
+        ] {
+            assert_eq!(
+                severity_of(&config, path),
+                Some(Severity::Error),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn source_ports_past_65535_are_an_error() {
+        let mut config = Config::default();
+        config.networking.base_source_port = 60000;
+        config.networking.port_range_per_task = 1000;
+        config.scanning.num_tasks = 5;
+        assert_eq!(severity_of(&config, "networking.port_range_per_task"), None);
+
+        config.scanning.num_tasks = 6;
+        assert_eq!(
+            severity_of(&config, "networking.port_range_per_task"),
+            Some(Severity::Error)
+        );
+
+        // Without reserved ranges the kernel picks ports and there is nothing to overflow
+        config.networking.port_range_per_task = 0;
+        assert_eq!(severity_of(&config, "networking.port_range_per_task"), None);
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let table: toml::Table =
+            toml::from_str("[scaning]\nport = 25565\n[scanning]\nnum_task = 8\nnum_tasks = 8\n")
+                .unwrap();
+        let (config, mut unknown) = Config::from_table(table).unwrap();
+        unknown.sort();
+        assert_eq!(unknown, ["scaning", "scanning.num_task"]);
+        assert_eq!(config.scanning.num_tasks, 8);
+    }
+
+    #[test]
+    fn example_config_has_no_errors() {
+        let table: toml::Table = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        let (config, unknown) = Config::from_table(table).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+        let errors: Vec<String> = validate(&config)
+            .issues
+            .into_iter()
+            .filter(|issue| issue.severity == Severity::Error)
+            .map(|issue| issue.path)
+            .collect();
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn hashing_without_salt_is_an_error() {
+        let mut config = Config::default();
+        config.privacy.profiles.insert(
+            "public".to_string(),
+            RedactionProfile {
+                hash_player_ids: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            severity_of(&config, "privacy.profiles.public.hash_salt"),
+            Some(Severity::Error)
+        );
+    }
+}
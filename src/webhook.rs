@@ -0,0 +1,117 @@
+//! Generic HTTP webhook whose body is rendered from a user-supplied template
+
+use crate::config::{NotifierConfig, RouteRule, TemplateEscape};
+use crate::discord::MinecraftServer;
+use crate::notifier::{Notifier, OutboundRequest, announce_each};
+use crate::stats::ScanProgress;
+use crate::template::{RenderError, Templates, digest_context, progress_context, server_context};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+const SERVER_TEMPLATE: &str = "server";
+const DIGEST_TEMPLATE: &str = "digest";
+const PROGRESS_TEMPLATE: &str = "progress";
+
+pub struct WebhookNotifier {
+    url: String,
+    method: String,
+    headers: BTreeMap<String, String>,
+    min_interval_ms: u64,
+    templates: Templates,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: &NotifierConfig) -> Result<Self, Box<dyn Error>> {
+        if config.url.is_empty() {
+            return Err("url is required".into());
+        }
+        let template = match (&config.template, &config.template_file) {
+            (Some(template), None) => template.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map_err(|e| format!("could not read template_file {}: {}", path, e))?,
+            (Some(_), Some(_)) => {
+                return Err("set either template or template_file, not both".into());
+            }
+            (None, None) => return Err("template or template_file is required".into()),
+        };
+
+        let mut templates = Templates::new(config.escape);
+        templates.register(SERVER_TEMPLATE, &template)?;
+        if let Some(digest) = &config.digest_template {
+            templates.register(DIGEST_TEMPLATE, digest)?;
+        }
+        if let Some(progress) = &config.progress_template {
+            templates.register(PROGRESS_TEMPLATE, progress)?;
+        }
+
+        let mut headers = config.headers.clone();
+        if !headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("content-type"))
+        {
+            let content_type = match config.escape {
+                TemplateEscape::Json => "application/json",
+                TemplateEscape::Html => "text/html; charset=utf-8",
+                TemplateEscape::None => "text/plain; charset=utf-8",
+            };
+            headers.insert("Content-Type".to_string(), content_type.to_string());
+        }
+
+        Ok(Self {
+            url: config.url.clone(),
+            method: config.method.to_uppercase(),
+            headers,
+            min_interval_ms: config.min_interval_ms.unwrap_or(0),
+            templates,
+        })
+    }
+
+    fn request(&self, body: String) -> OutboundRequest {
+        OutboundRequest {
+            url: self.url.clone(),
+            method: self.method.clone(),
+            headers: self.headers.clone(),
+            payload: serde_json::Value::Null,
+            body: Some(body),
+            min_interval_ms: self.min_interval_ms,
+            attachments: Vec::new(),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn server_found(
+        &self,
+        server: &MinecraftServer,
+        _route: Option<&RouteRule>,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        let body = self
+            .templates
+            .render(SERVER_TEMPLATE, &server_context(server))?;
+        Ok(vec![self.request(body)])
+    }
+
+    fn digest(
+        &self,
+        servers: &[MinecraftServer],
+        window_seconds: u64,
+    ) -> Result<Vec<OutboundRequest>, RenderError> {
+        if !self.templates.has(DIGEST_TEMPLATE) {
+            return announce_each(self, servers);
+        }
+        let body = self
+            .templates
+            .render(DIGEST_TEMPLATE, &digest_context(servers, window_seconds))?;
+        Ok(vec![self.request(body)])
+    }
+
+    fn progress(&self, progress: &ScanProgress) -> Result<Vec<OutboundRequest>, RenderError> {
+        if !self.templates.has(PROGRESS_TEMPLATE) {
+            return Ok(Vec::new());
+        }
+        let body = self
+            .templates
+            .render(PROGRESS_TEMPLATE, &progress_context(progress))?;
+        Ok(vec![self.request(body)])
+    }
+}